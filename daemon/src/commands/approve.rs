@@ -0,0 +1,8 @@
+use {super::control, anyhow::Result, std::path::Path};
+
+/// Approves a pending deploy of the repository in the working directory,
+/// through the control socket of the daemon deploying it
+pub fn approve(id: &str) -> Result<()> {
+    println!("{}", control::send(Path::new("."), "approve", id)?);
+    Ok(())
+}
@@ -0,0 +1,83 @@
+use {
+    super::config_loader::{self, is_secret, ConfigOptions, REDACTED},
+    anyhow::Result,
+    serde_json::Value,
+    std::env,
+};
+
+/// Prints the effective config after all layers are merged
+pub fn config_show(options: &ConfigOptions) -> Result<()> {
+    let loaded = config_loader::load_layered(options, env::vars())?;
+
+    println!("File:     {}", loaded.describe_path());
+    for o in &loaded.overrides {
+        let value = if is_secret(o.key) {
+            Value::from(REDACTED)
+        } else {
+            o.value.clone()
+        };
+        println!("Override: {} = {} (from {})", o.key, value, o.source);
+    }
+
+    let mut config = serde_json::to_value(&loaded.config)?;
+    redact("", &mut config);
+    println!("{}", serde_json::to_string_pretty(&config)?);
+
+    Ok(())
+}
+
+/// Hides secrets under the dotted key `prefix`
+fn redact(prefix: &str, value: &mut Value) {
+    let join = |key: &dyn std::fmt::Display| match prefix {
+        "" => key.to_string(),
+        _ => format!("{}.{}", prefix, key),
+    };
+
+    match value {
+        Value::Object(table) => {
+            for (key, value) in table.iter_mut() {
+                let key = join(key);
+                if is_secret(&key) && !value.is_null() {
+                    *value = Value::from(REDACTED);
+                } else {
+                    redact(&key, value);
+                }
+            }
+        }
+        Value::Array(items) => {
+            for (i, item) in items.iter_mut().enumerate() {
+                redact(&join(&i), item);
+            }
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, serde_json::json};
+
+    #[test]
+    fn redacts_secret_keys() {
+        let mut config = json!({
+            "endpoint": "https://app.user.repl.co/refresh",
+            "webhook": { "secret": "hunter2", "unset_token": null },
+            "credentials": [{ "GITHUB_TOKEN": "ghp_abc" }],
+            "env": { "DATABASE_URL": "postgres://user:pass@db/app" },
+            "processes": [{ "name": "web", "env": { "SENTRY_DSN": "https://key@sentry" } }],
+        });
+
+        redact("", &mut config);
+
+        assert_eq!(
+            config,
+            json!({
+                "endpoint": "https://app.user.repl.co/refresh",
+                "webhook": { "secret": REDACTED, "unset_token": null },
+                "credentials": [{ "GITHUB_TOKEN": REDACTED }],
+                "env": { "DATABASE_URL": REDACTED },
+                "processes": [{ "name": "web", "env": { "SENTRY_DSN": REDACTED } }],
+            })
+        );
+    }
+}
@@ -0,0 +1,18 @@
+use {super::deploy_schedule, anyhow::Result, std::path::Path};
+
+/// Freezes deploys of the repository in the working directory, which a
+/// running daemon picks up with the next deploy request
+pub fn freeze(reason: &str) -> Result<()> {
+    deploy_schedule::freeze(Path::new("."), reason)?;
+    println!("Deploys are frozen until `repl.deploy unfreeze`");
+    Ok(())
+}
+
+pub fn unfreeze() -> Result<()> {
+    if deploy_schedule::unfreeze(Path::new("."))? {
+        println!("Deploys are unfrozen, queued deploys run within a minute");
+    } else {
+        println!("Deploys weren't frozen");
+    }
+    Ok(())
+}
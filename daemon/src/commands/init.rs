@@ -0,0 +1,254 @@
+use {
+    super::constants::{
+        CONFIG_FILE_EXISTS_ERROR, MISSING_INIT_OPTION_ERROR, PREFLIGHT_FETCH_FAILED_ERROR,
+        REFRESH_PATH, REPLIT_DEPLOY_CONFIG_FILE_NAMES, REPLIT_DEPLOY_JSON_PATH, REPLIT_FILE_PATH,
+        UNCOMMITTED_CHANGES_WARN,
+    },
+    super::git_updater,
+    super::types::GitConfig,
+    anyhow::{bail, Context, Result},
+    std::{
+        env, fs,
+        io::{self, BufRead, IsTerminal, Write},
+        path::Path,
+    },
+};
+
+pub struct InitOptions {
+    pub endpoint: Option<String>,
+    pub remote: Option<String>,
+    pub branch: Option<String>,
+    pub command: Option<String>,
+    pub standalone: bool,
+    pub force: bool,
+}
+
+pub fn init(options: InitOptions) -> Result<()> {
+    let config_path = Path::new(REPLIT_DEPLOY_JSON_PATH);
+    if let Some(existing) = REPLIT_DEPLOY_CONFIG_FILE_NAMES
+        .iter()
+        .find(|name| Path::new(name).exists())
+    {
+        if !options.force {
+            bail!("{} ({})", CONFIG_FILE_EXISTS_ERROR, existing);
+        }
+    }
+
+    let remote = match options.remote {
+        Some(remote) => remote,
+        None => git_updater::default_remote(None)?,
+    };
+
+    let branch = match options.branch {
+        Some(branch) => branch,
+        None => git_updater::default_branch(None, &remote)
+            .unwrap_or_else(|| GitConfig::default().branch),
+    };
+
+    let git_config = GitConfig {
+        remote,
+        branch,
+        ..Default::default()
+    };
+    println!("Deploying from {}", git_config.remote_ref());
+
+    let endpoint = match options
+        .endpoint
+        .or_else(|| endpoint_from_env(|name| env::var(name).ok()))
+    {
+        Some(endpoint) => endpoint,
+        None => prompt("Endpoint (address of your repl + /refresh)", "--endpoint")?,
+    };
+    println!("Endpoint is {}", endpoint);
+
+    let (command, standalone) = match options.command {
+        Some(command) => (command, options.standalone),
+        None => (
+            prompt("Command to run your program", "<command>")?,
+            options.standalone
+                || !prompt(
+                    "Does your program run its own HTTP server? [y/N]",
+                    "--standalone",
+                )?
+                .to_lowercase()
+                .starts_with('y'),
+        ),
+    };
+
+    fs::write(config_path, config_json(&endpoint, &git_config))
+        .with_context(|| format!("Failed to write {}", REPLIT_DEPLOY_JSON_PATH))?;
+    println!("Wrote {}", REPLIT_DEPLOY_JSON_PATH);
+
+    let replit_path = Path::new(REPLIT_FILE_PATH);
+    let replit = fs::read_to_string(replit_path).unwrap_or_default();
+    fs::write(
+        replit_path,
+        set_run_line(&replit, &run_line(&command, standalone)),
+    )
+    .with_context(|| format!("Failed to write {}", REPLIT_FILE_PATH))?;
+    println!("Updated the run line in {}", REPLIT_FILE_PATH);
+
+    preflight(&git_config)
+}
+
+/// Makes sure the first deploy won't fail or silently throw away work
+fn preflight(git_config: &GitConfig) -> Result<()> {
+    let remote_ref = git_config.remote_ref();
+    println!("Checking that {} can be fetched...", remote_ref);
+
+    git_updater::fetch_branch(None, git_config)
+        .and_then(|_| git_updater::rev_parse(None, &remote_ref))
+        .with_context(|| PREFLIGHT_FETCH_FAILED_ERROR)?;
+
+    let changes = git_updater::uncommitted_changes(None)?;
+    if !changes.is_empty() {
+        println!("Warning: {}", UNCOMMITTED_CHANGES_WARN);
+        for path in changes {
+            println!("    {}", path);
+        }
+    }
+
+    println!("All set! Click Run to start repl.deploy");
+    Ok(())
+}
+
+/// Works out the address of the repl from the variables Replit sets
+fn endpoint_from_env(var: impl Fn(&str) -> Option<String>) -> Option<String> {
+    let domain = var("REPLIT_DOMAINS")
+        .and_then(|domains| domains.split(',').next().map(|d| d.trim().to_owned()))
+        .filter(|domain| !domain.is_empty())
+        .or_else(|| var("REPLIT_DEV_DOMAIN"))
+        .or_else(|| match (var("REPL_SLUG"), var("REPL_OWNER")) {
+            (Some(slug), Some(owner)) => Some(format!("{}.{}.repl.co", slug, owner).to_lowercase()),
+            _ => None,
+        })?;
+
+    Some(format!("https://{}/{}", domain, REFRESH_PATH))
+}
+
+fn prompt(question: &str, flag: &str) -> Result<String> {
+    if !io::stdin().is_terminal() {
+        bail!("{} {}", MISSING_INIT_OPTION_ERROR, flag);
+    }
+
+    print!("{}: ", question);
+    io::stdout().flush()?;
+
+    let mut answer = String::new();
+    io::stdin().lock().read_line(&mut answer)?;
+    Ok(answer.trim().to_owned())
+}
+
+fn config_json(endpoint: &str, git_config: &GitConfig) -> String {
+    format!(
+        "{{\n    \"endpoint\": {},\n    \"git\": {{\n        \"remote\": {},\n        \"branch\": {}\n    }}\n}}\n",
+        serde_json::to_string(endpoint).unwrap(),
+        serde_json::to_string(&git_config.remote).unwrap(),
+        serde_json::to_string(&git_config.branch).unwrap(),
+    )
+}
+
+fn run_line(command: &str, standalone: bool) -> String {
+    let run = if standalone {
+        format!("./repl.deploy --standalone {}", command)
+    } else {
+        format!("./repl.deploy {}", command)
+    };
+
+    // JSON strings are valid TOML basic strings
+    format!("run = {}", serde_json::to_string(&run).unwrap())
+}
+
+/// Replaces the top-level `run` key of a .replit file, adding it to the top of
+/// the file (before any tables) if it isn't there yet
+fn set_run_line(replit: &str, run_line: &str) -> String {
+    let mut replaced = false;
+    let mut in_table = false;
+
+    let mut lines: Vec<String> = replit
+        .lines()
+        .map(|line| {
+            let trimmed = line.trim_start();
+            in_table = in_table || trimmed.starts_with('[');
+
+            let is_run_key = trimmed
+                .strip_prefix("run")
+                .is_some_and(|rest| rest.trim_start().starts_with('='));
+
+            if is_run_key && !in_table && !replaced {
+                replaced = true;
+                run_line.to_owned()
+            } else {
+                line.to_owned()
+            }
+        })
+        .collect();
+
+    if !replaced {
+        lines.insert(0, run_line.to_owned());
+    }
+
+    lines.join("\n") + "\n"
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replaces_existing_run_line() {
+        let replit =
+            "language = \"nodejs\"\nrun=\"node index.js\"\n\n[packager]\nrun = \"npm i\"\n";
+        assert_eq!(
+            set_run_line(replit, &run_line("node index.js", true)),
+            "language = \"nodejs\"\nrun = \"./repl.deploy --standalone node index.js\"\n\n[packager]\nrun = \"npm i\"\n"
+        );
+    }
+
+    #[test]
+    fn adds_run_line_before_tables() {
+        let replit = "[packager]\nlanguage = \"nodejs\"\n";
+        assert_eq!(
+            set_run_line(replit, &run_line("node \"server.js\"", false)),
+            "run = \"./repl.deploy node \\\"server.js\\\"\"\n[packager]\nlanguage = \"nodejs\"\n"
+        );
+        assert_eq!(
+            set_run_line("", &run_line("cargo run", false)),
+            "run = \"./repl.deploy cargo run\"\n"
+        );
+    }
+
+    #[test]
+    fn writes_config_json() {
+        let json = config_json("https://app.user.repl.co/refresh", &GitConfig::default());
+        let config: crate::types::Config = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(config.endpoint, "https://app.user.repl.co/refresh");
+        assert_eq!(config.git.remote_ref(), "origin/main");
+    }
+
+    #[test]
+    fn derives_endpoint_from_env() {
+        let env = |vars: &'static [(&'static str, &'static str)]| {
+            move |name: &str| {
+                vars.iter()
+                    .find(|(key, _)| *key == name)
+                    .map(|(_, value)| value.to_string())
+            }
+        };
+
+        assert_eq!(
+            endpoint_from_env(env(&[
+                ("REPLIT_DOMAINS", "app.example.com,other.example.com"),
+                ("REPL_SLUG", "app"),
+                ("REPL_OWNER", "user"),
+            ])),
+            Some("https://app.example.com/refresh".to_owned())
+        );
+        assert_eq!(
+            endpoint_from_env(env(&[("REPL_SLUG", "My-App"), ("REPL_OWNER", "User")])),
+            Some("https://my-app.user.repl.co/refresh".to_owned())
+        );
+        assert_eq!(endpoint_from_env(env(&[("REPL_SLUG", "app")])), None);
+    }
+}
@@ -0,0 +1,88 @@
+use {
+    super::constants::{KEY_FILE_EXISTS_ERROR, KEY_GENERATION_FAILED_ERROR},
+    anyhow::{bail, Context, Result},
+    rand::rngs::OsRng,
+    rsa::{PrivateKeyPemEncoding, PublicKeyPemEncoding, RSAPrivateKey, RSAPublicKey},
+    std::{fs, path::Path},
+};
+
+pub fn keygen(prefix: &str, bits: usize, force: bool) -> Result<()> {
+    let private_path = format!("{}.pem", prefix);
+    let public_path = format!("{}.pub.pem", prefix);
+
+    for path in [&private_path, &public_path] {
+        if Path::new(path).exists() && !force {
+            bail!("{} ({})", KEY_FILE_EXISTS_ERROR, path);
+        }
+    }
+
+    println!(
+        "Generating a {} bit RSA key pair, this can take a while...",
+        bits
+    );
+    let private_key =
+        RSAPrivateKey::new(&mut OsRng, bits).with_context(|| KEY_GENERATION_FAILED_ERROR)?;
+    let public_key = RSAPublicKey::from(&private_key);
+
+    write_private(&private_path, &private_key.to_pem_pkcs1()?)?;
+    fs::write(&public_path, public_key.to_pem_pkcs1()?)
+        .with_context(|| format!("Failed to write {}", public_path))?;
+
+    println!(
+        "Wrote {} (keep this secret) and {}",
+        private_path, public_path
+    );
+    println!(
+        "Commit {} to your repository and add \"public_key\": \"{}\" to replit-deploy.json,",
+        public_path, public_path
+    );
+    println!(
+        "then run `repl.deploy relay --key {}` wherever your webhooks are sent",
+        private_path
+    );
+
+    Ok(())
+}
+
+#[cfg(unix)]
+fn write_private(path: &str, pem: &str) -> Result<()> {
+    use std::{io::Write, os::unix::fs::OpenOptionsExt};
+
+    fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)
+        .and_then(|mut file| file.write_all(pem.as_bytes()))
+        .with_context(|| format!("Failed to write {}", path))
+}
+
+#[cfg(not(unix))]
+fn write_private(path: &str, pem: &str) -> Result<()> {
+    fs::write(path, pem).with_context(|| format!("Failed to write {}", path))
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::{signature_verifier::load_public_key, signer::load_private_key},
+    };
+
+    #[test]
+    fn generated_keys_can_be_loaded() {
+        let dir = Path::new("test_repo_keygen");
+        let _ = fs::remove_dir_all(dir);
+        fs::create_dir_all(dir).unwrap();
+        let prefix = dir.join("repl-deploy");
+        let prefix = prefix.to_str().unwrap();
+
+        keygen(prefix, 1024, false).expect("Failed to generate keys");
+        assert!(keygen(prefix, 1024, false).is_err());
+
+        let private_key = load_private_key(Path::new(&format!("{}.pem", prefix))).unwrap();
+        let public_key = load_public_key(Some(Path::new(&format!("{}.pub.pem", prefix)))).unwrap();
+        assert!(public_key == RSAPublicKey::from(&private_key));
+    }
+}
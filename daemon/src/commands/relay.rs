@@ -0,0 +1,373 @@
+use {
+    super::config_loader::{self, ConfigFormat},
+    super::constants::{
+        INVALID_CONFIG_ERROR, INVALID_WEBHOOK_SECRET_FILE_ERROR, MISSING_ENDPOINT_ERROR,
+        MISSING_WEBHOOK_SECRET_ERROR, RELAY_WITHOUT_SECRET_WARN, REPLIT_DEPLOY_CONFIG_FILE_NAMES,
+        REPL_REQUEST_FAILED_ERROR, SIGNATURE_HEADER_NAME, STAT_RELAY_IGNORED, STAT_RELAY_LISTENING,
+        STAT_RELAY_SENT, WEBHOOK_PATH, WEBHOOK_SECRET_VAR,
+    },
+    super::git_updater,
+    super::signer,
+    super::types::Payload,
+    super::webhook::{self, WebhookAuth},
+    anyhow::{bail, Context, Result},
+    log::{error, info, warn},
+    rsa::RSAPrivateKey,
+    std::{
+        convert::Infallible,
+        env, fs,
+        path::{Path, PathBuf},
+        sync::Arc,
+        time::{Duration, SystemTime, UNIX_EPOCH},
+    },
+    tokio::task,
+    warp::{http::StatusCode, hyper::body::Bytes, reply, Filter},
+};
+
+pub struct RelayOptions {
+    pub key: PathBuf,
+    pub mirror: PathBuf,
+    pub port: u16,
+    /// File holding the webhook secret, read instead of
+    /// `REPL_DEPLOY_WEBHOOK_SECRET`
+    pub secret_file: Option<PathBuf>,
+    /// Relays webhooks without checking them, so anyone who can reach the relay
+    /// can trigger deploys
+    pub insecure_no_secret: bool,
+}
+
+struct Relay {
+    key: RSAPrivateKey,
+    mirror: PathBuf,
+    secret: Option<String>,
+}
+
+enum Forward {
+    Send {
+        endpoint: String,
+        payload: Vec<u8>,
+        signature: String,
+    },
+    Ignore(String),
+}
+
+/// Receives push webhooks, signs a payload for the endpoint configured at the
+/// pushed commit and sends it on, like the hosted GitHub app does
+pub async fn relay(options: RelayOptions) -> Result<()> {
+    let secret = webhook_secret(
+        options.secret_file.as_deref(),
+        |name| env::var(name).ok(),
+        options.insecure_no_secret,
+    )?;
+    if secret.is_none() {
+        warn!("{}", RELAY_WITHOUT_SECRET_WARN);
+    }
+
+    let relay = Arc::new(Relay {
+        key: signer::load_private_key(&options.key)?,
+        mirror: options.mirror,
+        secret,
+    });
+
+    git_updater::rev_parse(Some(&relay.mirror), "HEAD")
+        .with_context(|| format!("{} isn't a git mirror", relay.mirror.display()))?;
+
+    let webhooks = warp::post()
+        .and(warp::path(WEBHOOK_PATH))
+        .and(warp::body::bytes())
+        .and(warp::header::optional("X-Hub-Signature-256"))
+        .and(warp::header::optional("X-Gitea-Signature"))
+        .and(warp::header::optional("X-Gitlab-Token"))
+        .and_then(
+            move |body: Bytes,
+                  hub_signature: Option<String>,
+                  gitea_signature: Option<String>,
+                  gitlab_token: Option<String>| {
+                let relay = relay.clone();
+                let auth = WebhookAuth {
+                    hub_signature,
+                    gitea_signature,
+                    gitlab_token,
+                };
+
+                async move { Ok::<_, Infallible>(handle_webhook(relay, body, auth).await) }
+            },
+        );
+
+    info!("{} {}", STAT_RELAY_LISTENING, options.port);
+    warp::serve(webhooks)
+        .run(([0, 0, 0, 0], options.port))
+        .await;
+
+    Ok(())
+}
+
+/// The secret webhooks have to be signed with, from `secret_file` or else the
+/// environment. Kept off the command line, where `ps` and shell history would
+/// show it, and only optional when `insecure` says so.
+fn webhook_secret(
+    secret_file: Option<&Path>,
+    var: impl Fn(&str) -> Option<String>,
+    insecure: bool,
+) -> Result<Option<String>> {
+    let secret = match secret_file {
+        Some(path) => Some(
+            fs::read_to_string(path)
+                .ok()
+                .map(|secret| secret.trim().to_owned())
+                .filter(|secret| !secret.is_empty())
+                .with_context(|| {
+                    format!("{} {}", INVALID_WEBHOOK_SECRET_FILE_ERROR, path.display())
+                })?,
+        ),
+        None => var(WEBHOOK_SECRET_VAR).filter(|secret| !secret.is_empty()),
+    };
+
+    match secret {
+        None if !insecure => bail!(MISSING_WEBHOOK_SECRET_ERROR),
+        secret => Ok(secret),
+    }
+}
+
+async fn handle_webhook(
+    relay: Arc<Relay>,
+    body: Bytes,
+    auth: WebhookAuth,
+) -> reply::WithStatus<String> {
+    if let Some(secret) = &relay.secret {
+        if let Err(e) = webhook::verify_secret(&body, secret, &auth) {
+            warn!("{}", e);
+            return reply::with_status(e.to_string(), StatusCode::UNAUTHORIZED);
+        }
+    }
+
+    let forwarded = task::spawn_blocking(move || match relay.prepare(&body)? {
+        Forward::Send {
+            endpoint,
+            payload,
+            signature,
+        } => send(&endpoint, &payload, &signature).map(|_| {
+            info!("{} {}", STAT_RELAY_SENT, endpoint);
+            (StatusCode::OK, format!("{} {}", STAT_RELAY_SENT, endpoint))
+        }),
+        Forward::Ignore(reason) => {
+            info!("{}: {}", STAT_RELAY_IGNORED, reason);
+            Ok((
+                StatusCode::ACCEPTED,
+                format!("{}: {}", STAT_RELAY_IGNORED, reason),
+            ))
+        }
+    })
+    .await
+    .unwrap();
+
+    match forwarded {
+        Ok((status, body)) => reply::with_status(body, status),
+        Err(e) => {
+            error!("{:#}", e);
+            reply::with_status(format!("{:#}", e), StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+impl Relay {
+    fn prepare(&self, body: &[u8]) -> Result<Forward> {
+        let event = match webhook::parse_push_event(body)? {
+            Some(event) => event,
+            None => return Ok(Forward::Ignore("not a push to a branch".to_owned())),
+        };
+
+        git_updater::update_mirror(&self.mirror)?;
+
+        let mut config = None;
+        for name in REPLIT_DEPLOY_CONFIG_FILE_NAMES {
+            if let Some(contents) =
+                git_updater::read_file_at_commit(Some(&self.mirror), &event.commit, name)?
+            {
+                let format = ConfigFormat::from_path(Path::new(name));
+                config = Some(
+                    config_loader::parse_config(&contents, format)
+                        .with_context(|| format!("{} ({})", INVALID_CONFIG_ERROR, name))?,
+                );
+                break;
+            }
+        }
+
+        let endpoint = config
+            .as_ref()
+            .and_then(|config| config.accepted_endpoints().next().cloned());
+        let (config, endpoint) = match (config, endpoint) {
+            (Some(config), Some(endpoint)) => (config, endpoint),
+            (Some(_), None) => return Ok(Forward::Ignore(MISSING_ENDPOINT_ERROR.to_owned())),
+            (None, _) => {
+                return Ok(Forward::Ignore(format!(
+                    "no replit-deploy config at {}",
+                    event.commit
+                )))
+            }
+        };
+
+        let deployed_ref = format!("refs/heads/{}", config.git.branch);
+        if event.git_ref != deployed_ref {
+            return Ok(Forward::Ignore(format!(
+                "{} isn't the deployed branch ({})",
+                event.git_ref, deployed_ref
+            )));
+        }
+
+        // Signed last so that the timestamp is as fresh as possible
+        let payload = serde_json::to_vec(&Payload {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_millis(),
+            endpoint: endpoint.clone(),
+            commit: Some(event.commit),
+        })?;
+        let signature = signer::sign(&payload, &self.key)?;
+
+        Ok(Forward::Send {
+            endpoint,
+            payload,
+            signature,
+        })
+    }
+}
+
+fn send(endpoint: &str, payload: &[u8], signature: &str) -> Result<()> {
+    ureq::post(endpoint)
+        .timeout(Duration::from_secs(30))
+        .set(SIGNATURE_HEADER_NAME, signature)
+        .set("Content-Type", "application/json")
+        .send_bytes(payload)
+        .map(|_| ())
+        .with_context(|| format!("{} ({})", REPL_REQUEST_FAILED_ERROR, endpoint))
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::{signature_verifier::validate_payload_and_signature, types::Config},
+        rand::rngs::OsRng,
+        rsa::RSAPublicKey,
+        std::{fs, process::Command},
+    };
+
+    const ENDPOINT: &str = "https://app.user.repl.co/refresh";
+
+    #[test]
+    fn prepares_signed_payload_from_mirror() {
+        let (relay, commit) = prepare_mirror("test_repo_relay");
+
+        let body = format!(r#"{{"ref":"refs/heads/main","after":"{}"}}"#, commit);
+        let (endpoint, payload, signature) = match relay.prepare(body.as_bytes()).unwrap() {
+            Forward::Send {
+                endpoint,
+                payload,
+                signature,
+            } => (endpoint, payload, signature),
+            Forward::Ignore(reason) => panic!("Push was ignored: {}", reason),
+        };
+
+        assert_eq!(endpoint, ENDPOINT);
+
+        let result = validate_payload_and_signature(
+            &payload,
+            &signature,
+            &Config {
+                endpoint: ENDPOINT.to_owned(),
+                ..Default::default()
+            },
+            &RSAPublicKey::from(&relay.key),
+        );
+        assert!(result.is_ok(), "{:#}", result.unwrap_err());
+
+        let payload: Payload = serde_json::from_slice(&payload).unwrap();
+        assert_eq!(payload.commit, Some(commit));
+    }
+
+    #[test]
+    fn requires_a_webhook_secret() {
+        let no_env = |_: &str| None;
+        assert!(webhook_secret(None, no_env, false).is_err());
+        assert_eq!(webhook_secret(None, no_env, true).unwrap(), None);
+
+        let env = |name: &str| (name == WEBHOOK_SECRET_VAR).then(|| "from-env".to_owned());
+        assert_eq!(
+            webhook_secret(None, env, false).unwrap().as_deref(),
+            Some("from-env")
+        );
+
+        let dir = Path::new("test_repo_relay_secret");
+        let _ = fs::remove_dir_all(dir);
+        fs::create_dir_all(dir).unwrap();
+        fs::write(dir.join("secret"), "from-file\n").unwrap();
+        fs::write(dir.join("empty"), "\n").unwrap();
+        assert_eq!(
+            webhook_secret(Some(&dir.join("secret")), env, false)
+                .unwrap()
+                .as_deref(),
+            Some("from-file")
+        );
+        assert!(webhook_secret(Some(&dir.join("empty")), env, true).is_err());
+        assert!(webhook_secret(Some(&dir.join("missing")), env, true).is_err());
+    }
+
+    #[test]
+    fn ignores_other_branches() {
+        let (relay, commit) = prepare_mirror("test_repo_relay_branch");
+
+        let body = format!(r#"{{"ref":"refs/heads/feature","after":"{}"}}"#, commit);
+        assert!(matches!(
+            relay.prepare(body.as_bytes()).unwrap(),
+            Forward::Ignore(_)
+        ));
+        assert!(matches!(
+            relay.prepare(br#"{"zen":"ping"}"#).unwrap(),
+            Forward::Ignore(_)
+        ));
+    }
+
+    fn prepare_mirror(prefix: &str) -> (Relay, String) {
+        Command::new("./src/functionality/git_updater_test_prep.sh")
+            .arg(prefix)
+            .output()
+            .expect("Failed to prepare repos");
+
+        let remote = format!("{}1", prefix);
+        let mirror = PathBuf::from(format!("{}_bare", prefix));
+        let _ = fs::remove_dir_all(&mirror);
+
+        fs::write(
+            Path::new(&remote).join("replit-deploy.json"),
+            format!(r#"{{"endpoint":"{}"}}"#, ENDPOINT),
+        )
+        .unwrap();
+        for args in [
+            vec!["add", "replit-deploy.json"],
+            vec!["commit", "-m", "add_config"],
+        ] {
+            Command::new("git")
+                .args(args)
+                .current_dir(&remote)
+                .output()
+                .unwrap();
+        }
+        Command::new("git")
+            .args(["clone", "--mirror", &remote])
+            .arg(&mirror)
+            .output()
+            .expect("Failed to create mirror");
+        assert!(mirror.join("HEAD").exists());
+
+        let commit = git_updater::rev_parse(Some(&mirror), "main").unwrap();
+        let relay = Relay {
+            key: RSAPrivateKey::new(&mut OsRng, 2048).unwrap(),
+            mirror,
+            secret: None,
+        };
+
+        (relay, commit)
+    }
+}
@@ -0,0 +1,86 @@
+use {
+    super::constants::{SIGNATURE_HEADER_NAME, UNKNOWN_SIGN_FORMAT_ERROR},
+    super::signer,
+    super::types::Payload,
+    anyhow::{bail, Result},
+    std::{
+        path::Path,
+        time::{SystemTime, UNIX_EPOCH},
+    },
+};
+
+pub const SIGN_FORMATS: &[&str] = &["headers", "curl", "stdio"];
+
+pub fn sign(key_path: &Path, endpoint: &str, commit: Option<&str>, format: &str) -> Result<()> {
+    let key = signer::load_private_key(key_path)?;
+
+    let payload = serde_json::to_string(&Payload {
+        timestamp: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis(),
+        endpoint: endpoint.to_owned(),
+        commit: commit.map(String::from),
+    })?;
+
+    let signature = signer::sign(payload.as_bytes(), &key)?;
+
+    println!("{}", format_signed(&payload, &signature, endpoint, format)?);
+    Ok(())
+}
+
+fn format_signed(payload: &str, signature: &str, endpoint: &str, format: &str) -> Result<String> {
+    Ok(match format {
+        "headers" => format!(
+            "{}: {}\nContent-Type: application/json\n\n{}",
+            SIGNATURE_HEADER_NAME, signature, payload
+        ),
+        "curl" => format!(
+            "curl -X POST -H {} -H {} --data {} {}",
+            shell_quote(&format!("{}: {}", SIGNATURE_HEADER_NAME, signature)),
+            shell_quote("Content-Type: application/json"),
+            shell_quote(payload),
+            shell_quote(endpoint)
+        ),
+        // The line a program running its own HTTP server logs to stdout
+        "stdio" => format!("repl.deploy{}{}", payload, signature),
+        _ => bail!("{}: {}", UNKNOWN_SIGN_FORMAT_ERROR, format),
+    })
+}
+
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::{constants::STDIN_REGEX, stdio_event_handler::get_matches},
+        regex::Regex,
+    };
+
+    const PAYLOAD: &str = r#"{"timestamp":1,"endpoint":"https://app.user.repl.co/refresh"}"#;
+
+    #[test]
+    fn stdio_line_is_recognised() {
+        let line = format_signed(PAYLOAD, "c2ln", "", "stdio").unwrap();
+        let (payload, signature) = get_matches(&line, &Regex::new(STDIN_REGEX).unwrap()).unwrap();
+
+        assert_eq!(payload, PAYLOAD.as_bytes());
+        assert_eq!(signature, "c2ln");
+    }
+
+    #[test]
+    fn curl_command_is_quoted() {
+        assert_eq!(
+            format_signed("{\"it's\":1}", "c2ln", "https://a/refresh", "curl").unwrap(),
+            "curl -X POST -H 'Signature: c2ln' -H 'Content-Type: application/json' --data '{\"it'\\''s\":1}' 'https://a/refresh'"
+        );
+    }
+
+    #[test]
+    fn unknown_format_fails() {
+        assert!(format_signed(PAYLOAD, "c2ln", "", "xml").is_err());
+    }
+}
@@ -0,0 +1,146 @@
+use {
+    super::config_loader::{self, ConfigOptions},
+    super::constants::HTTP_PORT,
+    super::control,
+    super::deploy_history::{self, DeployRecord},
+    super::deploy_schedule,
+    super::git_updater,
+    super::types::{Approval, GitConfig},
+    anyhow::Result,
+    chrono::Utc,
+    std::{
+        env,
+        net::{Ipv4Addr, SocketAddr, TcpStream},
+        path::Path,
+        time::Duration,
+    },
+};
+
+// How many deploys from the history are shown
+const RECENT_DEPLOYS: usize = 5;
+
+pub fn status(config_options: &ConfigOptions) -> Result<()> {
+    let (git_config, schedule, approval) =
+        match config_loader::load_layered(config_options, env::vars()) {
+            Ok(loaded) => {
+                println!(
+                    "Config:   {} (endpoint {})",
+                    loaded.describe_path(),
+                    loaded
+                        .config
+                        .accepted_endpoints()
+                        .next()
+                        .map_or("", String::as_str)
+                );
+                (
+                    loaded.config.git,
+                    loaded.config.schedule,
+                    loaded.config.approval,
+                )
+            }
+            Err(e) => {
+                println!("Config:   {:#}", e);
+                (GitConfig::default(), None, Approval::None)
+            }
+        };
+
+    // Only compares against the last fetch, so status never touches the network
+    let target = git_config.describe_target();
+    match (
+        git_updater::rev_parse(None, "HEAD"),
+        git_updater::target_rev(None, &git_config)
+            .and_then(|rev| git_updater::rev_parse(None, &format!("{}^{{commit}}", rev))),
+    ) {
+        (Ok(head), Ok(remote)) if head == remote => {
+            println!("Commit:   {} (up to date with {})", head, target)
+        }
+        (Ok(head), Ok(remote)) => println!("Commit:   {} ({} is at {})", head, target, remote),
+        (Err(e), _) | (_, Err(e)) => println!("Commit:   {:#}", e),
+    }
+
+    match deploy_schedule::hold(Path::new("."), schedule.as_ref(), Utc::now()) {
+        Ok(None) => println!("Schedule: deploys allowed"),
+        Ok(Some(hold)) => println!("Schedule: {}", hold),
+        Err(e) => println!("Schedule: {:#}", e),
+    }
+
+    // Pending deploys only live in the daemon's memory
+    if approval == Approval::Required {
+        match control::send(Path::new("."), "status", "")
+            .and_then(|reply| Ok(serde_json::from_str::<control::Status>(&reply)?))
+        {
+            Ok(control::Status {
+                pending: Some(pending),
+                ..
+            }) => println!(
+                "Approval: {} awaits `repl.deploy approve {}`, expires in {}",
+                pending.id,
+                pending.id,
+                duration(pending.expires_at.saturating_sub(deploy_history::now()))
+            ),
+            Ok(_) => println!("Approval: no deploy awaits approval"),
+            Err(e) => println!("Approval: {:#}", e),
+        }
+    }
+
+    match deploy_history::read(Path::new(".")) {
+        Ok(records) if records.is_empty() => println!("Deploys:  none yet"),
+        Ok(records) => {
+            println!("Deploys:");
+            for record in records.iter().rev().take(RECENT_DEPLOYS) {
+                println!("  {}", describe(record));
+            }
+        }
+        Err(e) => println!("Deploys:  {:#}", e),
+    }
+
+    let address = SocketAddr::from((Ipv4Addr::LOCALHOST, HTTP_PORT));
+    if TcpStream::connect_timeout(&address, Duration::from_secs(1)).is_ok() {
+        println!("Daemon:   HTTP server listening on port {}", HTTP_PORT);
+    } else {
+        println!(
+            "Daemon:   no HTTP server on port {} (fine unless running with --standalone)",
+            HTTP_PORT
+        );
+    }
+
+    Ok(())
+}
+
+/// e.g. `12m ago  deployed 1a2b3c4 (fetched 2048 bytes in 0.4s)`
+fn describe(record: &DeployRecord) -> String {
+    let mut line = format!(
+        "{:<8} {}",
+        ago(deploy_history::now().saturating_sub(record.time)),
+        record.outcome.as_str()
+    );
+
+    if let Some(commit) = &record.commit {
+        line.push_str(&format!(" {}", &commit[..commit.len().min(7)]));
+    }
+    if let (Some(ms), Some(bytes)) = (record.fetch_ms, record.fetch_bytes) {
+        line.push_str(&format!(
+            " (fetched {} bytes in {:.1}s)",
+            bytes,
+            ms as f64 / 1000.0
+        ));
+    }
+    if let Some(error) = &record.error {
+        line.push_str(&format!(": {}", error));
+    }
+
+    line
+}
+
+fn ago(secs: u64) -> String {
+    format!("{} ago", duration(secs))
+}
+
+fn duration(secs: u64) -> String {
+    match secs {
+        0..=59 => format!("{}s", secs),
+        60..=3599 => format!("{}m", secs / 60),
+        3600..=86399 => format!("{}h", secs / 3600),
+        _ => format!("{}d", secs / 86400),
+    }
+}
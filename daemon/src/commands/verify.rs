@@ -0,0 +1,159 @@
+use {
+    super::config_loader::{self, ConfigOptions},
+    super::constants::{
+        BAD_ENDPOINT_ERROR, BAD_PAYLOAD_ERROR, MISSING_SIGNED_PAYLOAD_ERROR, PAYLOAD_TOO_OLD_ERROR,
+        STDIN_REGEX,
+    },
+    super::signature_verifier,
+    super::stdio_event_handler,
+    super::types::{Config, Payload},
+    anyhow::{bail, Result},
+    regex::Regex,
+    rsa::RSAPublicKey,
+    std::{
+        io::{self, BufRead},
+        path::Path,
+        time::{SystemTime, UNIX_EPOCH},
+    },
+};
+
+/// Where the signed payload to check comes from
+pub enum SignedInput {
+    PayloadAndSignature(String, String),
+    /// A `repl.deploy{...}signature` line, as logged by programs in stdio mode
+    Line(String),
+    Stdin,
+}
+
+pub fn verify(
+    input: SignedInput,
+    config_options: &ConfigOptions,
+    public_key_path: Option<&Path>,
+) -> Result<()> {
+    let config = config_loader::load_config(config_options)?;
+    let public_key = signature_verifier::load_public_key(
+        public_key_path.or_else(|| config.public_key.as_deref().map(Path::new)),
+    )?;
+
+    let line = match input {
+        SignedInput::PayloadAndSignature(payload, signature) => {
+            return check(payload.as_bytes(), &signature, &config, &public_key)
+        }
+        SignedInput::Line(line) => line,
+        SignedInput::Stdin => {
+            let mut line = String::new();
+            io::stdin().lock().read_line(&mut line)?;
+            line
+        }
+    };
+
+    match stdio_event_handler::get_matches(line.trim(), &Regex::new(STDIN_REGEX).unwrap()) {
+        Some((payload, signature)) => check(payload, signature, &config, &public_key),
+        None => bail!(MISSING_SIGNED_PAYLOAD_ERROR),
+    }
+}
+
+fn check(
+    payload: &[u8],
+    signature: &str,
+    config: &Config,
+    public_key: &RSAPublicKey,
+) -> Result<()> {
+    match signature_verifier::validate_payload_and_signature(payload, signature, config, public_key)
+    {
+        Ok(_) => {
+            println!("Valid: the daemon would accept this request and deploy");
+            if let Some(endpoint) =
+                serde_json::from_slice::<Payload>(payload)
+                    .ok()
+                    .and_then(|payload| {
+                        signature_verifier::matching_endpoint(config, &payload.endpoint)
+                    })
+            {
+                println!("Matched endpoint: {}", endpoint);
+            }
+            Ok(())
+        }
+        Err(res) => bail!(
+            "{} ({}): {}",
+            res.body,
+            res.status,
+            explain(res.body, payload, config)
+        ),
+    }
+}
+
+/// Spells out why a check failed, using the same inputs the daemon sees
+fn explain(failure: &str, payload: &[u8], config: &Config) -> String {
+    let parsed = serde_json::from_slice::<Payload>(payload);
+
+    match (failure, parsed) {
+        (BAD_PAYLOAD_ERROR, Err(e)) => format!(
+            "payload isn't JSON with a numeric \"timestamp\" and a string \"endpoint\" ({})",
+            e
+        ),
+        (PAYLOAD_TOO_OLD_ERROR, Ok(payload)) => {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_millis();
+            format!(
+                "payload was signed {:.1}s ago, but must be at most 15s old",
+                now.saturating_sub(payload.timestamp) as f64 / 1000.0
+            )
+        }
+        (BAD_ENDPOINT_ERROR, Ok(payload)) => format!(
+            "payload is for {:?}, but the config only accepts {:?}",
+            payload.endpoint,
+            config.accepted_endpoints().collect::<Vec<_>>()
+        ),
+        _ => "payload checks passed, but the signature isn't valid base64 or wasn't made with the private key matching the public key".to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ENDPOINT: &str = "https://app.user.repl.co/refresh";
+
+    fn config() -> Config {
+        Config {
+            endpoint: ENDPOINT.to_owned(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn explains_bad_payload() {
+        let explanation = explain(BAD_PAYLOAD_ERROR, b"{\"timestamp\":\"now\"}", &config());
+        assert!(
+            explanation.starts_with("payload isn't JSON"),
+            "{}",
+            explanation
+        );
+    }
+
+    #[test]
+    fn explains_old_payload() {
+        let payload = format!("{{\"timestamp\":0,\"endpoint\":\"{}\"}}", ENDPOINT);
+        let explanation = explain(PAYLOAD_TOO_OLD_ERROR, payload.as_bytes(), &config());
+        assert!(
+            explanation.contains("must be at most 15s old"),
+            "{}",
+            explanation
+        );
+    }
+
+    #[test]
+    fn explains_endpoint_mismatch() {
+        let payload = "{\"timestamp\":0,\"endpoint\":\"https://other.repl.co/refresh\"}";
+        let explanation = explain(BAD_ENDPOINT_ERROR, payload.as_bytes(), &config());
+        assert!(
+            explanation.contains("https://other.repl.co/refresh"),
+            "{}",
+            explanation
+        );
+        assert!(explanation.contains(ENDPOINT), "{}", explanation);
+    }
+}
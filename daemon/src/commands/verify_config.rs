@@ -0,0 +1,36 @@
+use {
+    super::config_loader::{self, ConfigOptions},
+    super::constants::{INVALID_ENDPOINT_ERROR, REFRESH_PATH},
+    anyhow::{bail, Result},
+    std::env,
+};
+
+pub fn verify_config(config_options: &ConfigOptions) -> Result<()> {
+    let loaded = config_loader::load_layered(config_options, env::vars())?;
+    let config = &loaded.config;
+
+    for endpoint in config.accepted_endpoints() {
+        if !(endpoint.starts_with("https://") || endpoint.starts_with("http://")) {
+            bail!("{}: {}", INVALID_ENDPOINT_ERROR, endpoint);
+        }
+
+        if !endpoint
+            .trim_end_matches('/')
+            .ends_with(&format!("/{}", REFRESH_PATH))
+        {
+            println!(
+                "Warning: {} doesn't end with /{}, make sure your program handles that path",
+                endpoint, REFRESH_PATH
+            );
+        }
+    }
+
+    println!("Config is valid ({})", loaded.describe_path());
+    for (i, endpoint) in config.accepted_endpoints().enumerate() {
+        let label = if i == 0 { "Endpoint:" } else { "Also:    " };
+        println!("{} {}", label, endpoint);
+    }
+    println!("Deploys:  {}", config.git.describe_target());
+
+    Ok(())
+}
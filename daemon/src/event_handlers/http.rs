@@ -1,81 +1,258 @@
 use {
     super::constants::{
-        REFRESH_PATH, SIGNATURE_HEADER_NAME, STAT_REQUEST_RECEIVED,
-        STAT_SIGNATURE_VALIDATION_FAILED, STAT_SIGNATURE_VALIDATION_SUCCESS,
+        BAD_ENDPOINT_ERROR, CONTROL_DISABLED_ERROR, CONTROL_PATH, CONTROL_UNAUTHORIZED_ERROR,
+        HTTP_PORT, REFRESH_PATH, SIGNATURE_HEADER_NAME, STATUS_PATH, STAT_REQUEST_RECEIVED,
+        STAT_SIGNATURE_VALIDATION_FAILED, STAT_SIGNATURE_VALIDATION_SUCCESS, UNKNOWN_APP_ERROR,
+        UNKNOWN_CONTROL_ACTION_ERROR,
     },
+    super::control,
+    super::deploy_approval::Approvals,
+    super::shared_config::SharedConfig,
     super::signature_verifier,
     super::types,
     anyhow::Result,
     log::{error, info, warn},
     rsa::RSAPublicKey,
-    std::{borrow::Cow, sync::Arc},
-    warp::{http::StatusCode, reply, Filter},
+    std::{borrow::Cow, env, future::Future, path::PathBuf, sync::Arc},
+    warp::{http::StatusCode, path::Tail, reply, Filter},
 };
 
-pub async fn listen<S: Send + Sync + Clone + 'static>(
-    config_ref: Arc<types::Config>,
-    public_key_ref: Arc<RSAPublicKey>,
-    state: S,
-    handler: impl Fn(S) -> Result<()> + Clone + Send + Sync + 'static,
-) {
-    let refresher = refresher(config_ref, public_key_ref, state, handler)
+/// Where signed requests are handled: the repository the daemon runs in, or
+/// one of its apps
+pub struct Route<S> {
+    /// Set for apps, which are also reachable at /refresh/<name>
+    pub name: Option<String>,
+    pub root: PathBuf,
+    pub config: SharedConfig,
+    pub public_key: RSAPublicKey,
+    /// Deploys awaiting approval through /control/approve
+    pub approvals: Approvals,
+    pub state: S,
+}
+
+/// Looks up environment variables, so tests don't have to set them
+type Var = fn(&str) -> Option<String>;
+
+pub async fn listen<S, F>(
+    routes: Vec<Route<S>>,
+    handler: impl Fn(S) -> F + Clone + Send + Sync + 'static,
+) where
+    S: Send + Sync + Clone + 'static,
+    F: Future<Output = Result<()>> + Send + 'static,
+{
+    let routes = Arc::new(routes);
+    let refresher = refresher(routes.clone(), handler)
+        .or(control(routes.clone(), env_var))
+        .or(status(routes, env_var))
         .or(repl_deploy_is_working_msg())
         .recover(handle_rejection);
 
-    warp::serve(refresher).run(([0, 0, 0, 0], 8090)).await;
+    warp::serve(refresher).run(([0, 0, 0, 0], HTTP_PORT)).await;
 }
 
-fn refresher<S: Send + Sync + Clone + 'static>(
-    config_ref: Arc<types::Config>,
-    public_key_ref: Arc<RSAPublicKey>,
-    state: S,
-    handler: impl Fn(S) -> Result<()> + Clone + Send + Sync + 'static,
+fn refresher<S, F>(
+    routes: Arc<Vec<Route<S>>>,
+    handler: impl Fn(S) -> F + Clone + Send + Sync + 'static,
 ) -> impl Filter<Extract = (reply::WithStatus<Cow<'static, str>>,), Error = warp::Rejection> + Clone
+where
+    S: Send + Sync + Clone + 'static,
+    F: Future<Output = Result<()>> + Send + 'static,
 {
     warp::post()
         .and(warp::path(REFRESH_PATH))
-        .and(validate_payload_and_signature(config_ref, public_key_ref))
-        .map(move |res: types::ValidationResult| {
-            info!("{}", STAT_SIGNATURE_VALIDATION_SUCCESS);
-
-            let state = state.clone();
-            let body = res.body;
-            match handler(state) {
-                Ok(()) => reply::with_status(Cow::from(body), StatusCode::OK),
-                Err(e) => {
-                    error!("{}", e);
-                    reply::with_status(Cow::from(e.to_string()), StatusCode::INTERNAL_SERVER_ERROR)
+        .and(validate_payload_and_signature(routes))
+        .then(
+            move |(res, name, state): (types::ValidationResult, Option<String>, S)| {
+                match name {
+                    Some(name) => info!("{} ({})", STAT_SIGNATURE_VALIDATION_SUCCESS, name),
+                    None => info!("{}", STAT_SIGNATURE_VALIDATION_SUCCESS),
                 }
-            }
+
+                let handled = handler(state);
+                let body = res.body;
+
+                async move {
+                    match handled.await {
+                        Ok(()) => reply::with_status(Cow::from(body), StatusCode::OK),
+                        // Held by the schedule or awaiting approval
+                        Err(e) if e.is::<types::StatusReply>() => {
+                            let e = e.downcast::<types::StatusReply>().unwrap();
+                            reply::with_status(Cow::from(e.message), e.status)
+                        }
+                        Err(e) => {
+                            error!("{}", e);
+                            reply::with_status(
+                                Cow::from(e.to_string()),
+                                StatusCode::INTERNAL_SERVER_ERROR,
+                            )
+                        }
+                    }
+                }
+            },
+        )
+}
+
+/// POST /control/<action>[/<app>], authenticated with the app's control
+/// token. Actions are `freeze`, with an optional reason as the body,
+/// `unfreeze` and `approve`, with the id of the pending deploy as the body.
+fn control<S>(
+    routes: Arc<Vec<Route<S>>>,
+    var: Var,
+) -> impl Filter<Extract = (reply::WithStatus<Cow<'static, str>>,), Error = warp::Rejection> + Clone
+where
+    S: Send + Sync + 'static,
+{
+    warp::post()
+        .and(warp::path(CONTROL_PATH))
+        .and(warp::path::param::<String>())
+        .and(warp::path::tail())
+        .and(warp::header::optional::<String>("authorization"))
+        .and(warp::body::bytes())
+        .map(
+            move |action: String,
+                  tail: Tail,
+                  authorization: Option<String>,
+                  body: warp::hyper::body::Bytes| {
+                let command = control::Command::parse(&action, &String::from_utf8_lossy(&body));
+                control_reply(control_action(
+                    &routes,
+                    command,
+                    tail.as_str(),
+                    authorization,
+                    var,
+                ))
+            },
+        )
+}
+
+/// GET /status[/<app>], authenticated like /control, answers with whether
+/// deploys are held and which one awaits approval as JSON
+fn status<S>(
+    routes: Arc<Vec<Route<S>>>,
+    var: Var,
+) -> impl Filter<Extract = (reply::WithStatus<Cow<'static, str>>,), Error = warp::Rejection> + Clone
+where
+    S: Send + Sync + 'static,
+{
+    warp::get()
+        .and(warp::path(STATUS_PATH))
+        .and(warp::path::tail())
+        .and(warp::header::optional::<String>("authorization"))
+        .map(move |tail: Tail, authorization: Option<String>| {
+            let command = Some(control::Command::Status);
+            control_reply(control_action(
+                &routes,
+                command,
+                tail.as_str(),
+                authorization,
+                var,
+            ))
         })
 }
 
+type ControlReply = Result<Cow<'static, str>, (StatusCode, Cow<'static, str>)>;
+
+fn control_reply(reply: ControlReply) -> reply::WithStatus<Cow<'static, str>> {
+    match reply {
+        Ok(body) => reply::with_status(body, StatusCode::OK),
+        Err((status, body)) => reply::with_status(body, status),
+    }
+}
+
+fn control_action<S>(
+    routes: &[Route<S>],
+    command: Option<control::Command>,
+    name: &str,
+    authorization: Option<String>,
+    var: Var,
+) -> ControlReply {
+    let name = name.trim_end_matches('/');
+    let not_found = |body: &'static str| Err((StatusCode::NOT_FOUND, Cow::from(body)));
+
+    // Without a name, only a daemon deploying a single repository can be meant
+    let route = match routes
+        .iter()
+        .find(|route| route.name.as_deref() == Some(name) || name.is_empty() && routes.len() == 1)
+    {
+        Some(route) => route,
+        None => return not_found(UNKNOWN_APP_ERROR),
+    };
+
+    let config = route.config.get();
+    let token = config
+        .control
+        .as_ref()
+        .and_then(|control| var(&control.token_env))
+        .filter(|token| !token.is_empty());
+    let token = match token {
+        Some(token) => token,
+        None => return not_found(CONTROL_DISABLED_ERROR),
+    };
+
+    let expected = format!("Bearer {}", token);
+    if !authorization.is_some_and(|given| constant_time_eq(given.as_bytes(), expected.as_bytes())) {
+        return Err((
+            StatusCode::UNAUTHORIZED,
+            Cow::from(CONTROL_UNAUTHORIZED_ERROR),
+        ));
+    }
+
+    let command = match command {
+        Some(command) => command,
+        None => return not_found(UNKNOWN_CONTROL_ACTION_ERROR),
+    };
+
+    match control::run(&route.root, &config, &route.approvals, command) {
+        Ok(body) => Ok(Cow::from(body)),
+        Err(e) if e.is::<types::StatusReply>() => {
+            let e = e.downcast::<types::StatusReply>().unwrap();
+            Err((e.status, Cow::from(e.message)))
+        }
+        Err(e) => {
+            error!("{:#}", e);
+            Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Cow::from(format!("{:#}", e)),
+            ))
+        }
+    }
+}
+
+fn env_var(name: &str) -> Option<String> {
+    env::var(name).ok()
+}
+
+/// Compares without leaking how much of the token matched through timing
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
 fn repl_deploy_is_working_msg(
 ) -> impl Filter<Extract = (&'static str,), Error = warp::Rejection> + Clone {
     warp::get().map(|| "repl.deploy is running")
 }
 
-fn validate_payload_and_signature(
-    config_ref: Arc<types::Config>,
-    public_key_ref: Arc<RSAPublicKey>,
-) -> impl Filter<Extract = (types::ValidationResult,), Error = warp::Rejection> + Clone {
-    warp::body::bytes()
+/// Validates requests to /refresh/<app> against that app, and requests to
+/// /refresh against whichever route the payload's endpoint belongs to
+fn validate_payload_and_signature<S>(
+    routes: Arc<Vec<Route<S>>>,
+) -> impl Filter<Extract = ((types::ValidationResult, Option<String>, S),), Error = warp::Rejection>
+       + Clone
+where
+    S: Send + Sync + Clone + 'static,
+{
+    warp::path::tail()
+        .and(warp::body::bytes())
         .and(warp::header(SIGNATURE_HEADER_NAME))
         .and_then(
-            move |payload: warp::hyper::body::Bytes, signature: String| {
-                let config = config_ref.clone();
-                let public_key = public_key_ref.clone();
+            move |tail: Tail, payload: warp::hyper::body::Bytes, signature: String| {
+                let routes = routes.clone();
 
                 async move {
                     info!("{}", STAT_REQUEST_RECEIVED);
 
-                    match signature_verifier::validate_payload_and_signature(
-                        &payload.to_vec(),
-                        &signature,
-                        &config,
-                        &public_key,
-                    ) {
-                        Ok(res) => Ok(res),
+                    match route(&routes, tail.as_str(), &payload, &signature) {
+                        Ok((res, route)) => Ok((res, route.name.clone(), route.state.clone())),
                         Err(e) => {
                             warn!("{}", STAT_SIGNATURE_VALIDATION_FAILED);
                             Err(warp::reject::custom(e))
@@ -86,6 +263,41 @@ fn validate_payload_and_signature(
         )
 }
 
+fn route<'a, S>(
+    routes: &'a [Route<S>],
+    name: &str,
+    payload: &[u8],
+    signature: &str,
+) -> Result<(types::ValidationResult, &'a Route<S>), types::ValidationResult> {
+    let name = name.trim_end_matches('/');
+    let mut rejection = types::ValidationResult {
+        body: UNKNOWN_APP_ERROR,
+        status: StatusCode::NOT_FOUND,
+    };
+
+    for route in routes
+        .iter()
+        .filter(|route| name.is_empty() || route.name.as_deref() == Some(name))
+    {
+        // A deploy may swap the config meanwhile, the request keeps its snapshot
+        let config = route.config.get();
+
+        match signature_verifier::validate_payload_and_signature(
+            payload,
+            signature,
+            &config,
+            &route.public_key,
+        ) {
+            Ok(res) => return Ok((res, route)),
+            // Keep the reason the route the request was meant for rejected it
+            Err(e) if e.body == BAD_ENDPOINT_ERROR && rejection.body != UNKNOWN_APP_ERROR => {}
+            Err(e) => rejection = e,
+        }
+    }
+
+    Err(rejection)
+}
+
 async fn handle_rejection(err: warp::Rejection) -> Result<impl warp::Reply, warp::Rejection> {
     if let Some(res) = err.find::<types::ValidationResult>() {
         Ok(reply::with_status(res.body, res.status))
@@ -98,10 +310,12 @@ async fn handle_rejection(err: warp::Rejection) -> Result<impl warp::Reply, warp
 mod tests {
     use {
         super::*,
-        rsa::{hash::Hash, PaddingScheme, RSAPrivateKey},
-        serde_json,
-        sha2::{Digest, Sha256},
-        std::time::{SystemTime, UNIX_EPOCH},
+        crate::{deploy_history, deploy_schedule},
+        rsa::RSAPrivateKey,
+        std::{
+            sync::Mutex,
+            time::{SystemTime, UNIX_EPOCH},
+        },
         types::Payload,
         warp::Reply,
     };
@@ -131,21 +345,233 @@ mod tests {
         assert!(status.is_err(), "Response is OK");
     }
 
+    #[tokio::test]
+    async fn routes_requests_to_apps() {
+        const BLOG: &str = "https://example.com/refresh/blog";
+        const SHOP: &str = "https://example.com/refresh/shop";
+        let (pub_key, priv_key) = new_keypair();
+
+        let deployed = Arc::new(Mutex::new(vec![]));
+        let routes = vec![
+            app_route("blog", BLOG, &pub_key),
+            app_route("shop", SHOP, &pub_key),
+        ];
+        let filter = refresher(Arc::new(routes), {
+            let deployed = deployed.clone();
+            move |name: &'static str| {
+                deployed.lock().unwrap().push(name);
+                async { Ok(()) }
+            }
+        });
+
+        let status = make_request_to("/refresh", SHOP, &priv_key, &filter).await;
+        assert_eq!(status, Ok(StatusCode::OK));
+        let status = make_request_to("/refresh/blog", BLOG, &priv_key, &filter).await;
+        assert_eq!(status, Ok(StatusCode::OK));
+        assert_eq!(*deployed.lock().unwrap(), vec!["shop", "blog"]);
+
+        // Signed for another app
+        let status = make_request_to("/refresh/blog", SHOP, &priv_key, &filter).await;
+        assert_eq!(status, Err(StatusCode::FORBIDDEN));
+        let status = make_request_to("/refresh/docs", BLOG, &priv_key, &filter).await;
+        assert_eq!(status, Err(StatusCode::NOT_FOUND));
+    }
+
+    #[tokio::test]
+    async fn answers_held_deploys() {
+        const TEST_ENDPOINT: &str = "https://endpoint.example.com/";
+        let (pub_key, priv_key) = new_keypair();
+
+        let filter = refresher(
+            Arc::new(vec![app_route("blog", TEST_ENDPOINT, &pub_key)]),
+            |_| async {
+                Err(types::StatusReply {
+                    status: StatusCode::ACCEPTED,
+                    message: "queued (Deploys are frozen)".to_owned(),
+                }
+                .into())
+            },
+        );
+
+        let status = make_request_to("/refresh", TEST_ENDPOINT, &priv_key, &filter).await;
+        assert_eq!(status, Ok(StatusCode::ACCEPTED));
+    }
+
+    #[tokio::test]
+    async fn controls_deploys() {
+        let root = PathBuf::from("test_repo_control");
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(&root).unwrap();
+        std::process::Command::new("git")
+            .arg("init")
+            .current_dir(&root)
+            .output()
+            .expect("Failed to create repo");
+
+        let (pub_key, _) = new_keypair();
+        let (approvals, mut approved) = Approvals::new(root.clone());
+        let controlled = SharedConfig::new(
+            Default::default(),
+            types::Config {
+                control: Some(types::ControlConfig {
+                    token_env: "CONTROL_TOKEN".to_owned(),
+                }),
+                ..Default::default()
+            },
+        );
+        let routes = Arc::new(vec![
+            Route {
+                root: root.clone(),
+                config: controlled,
+                approvals: approvals.clone(),
+                ..app_route("blog", "", &pub_key)
+            },
+            app_route("shop", "", &pub_key),
+        ]);
+        let var: Var = |name| (name == "CONTROL_TOKEN").then(|| "s3cret".to_owned());
+        let filter = control(routes.clone(), var).or(status(routes, var)).unify();
+        let request_with = |method: &str, path: &str, token: Option<&str>, body: &str| {
+            let mut request = warp::test::request().method(method).path(path).body(body);
+            if let Some(token) = token {
+                request = request.header("authorization", format!("Bearer {}", token));
+            }
+            let filter = filter.clone();
+            async move { request.reply(&filter).await.status() }
+        };
+        let request =
+            |path: &str, token: Option<&str>| request_with("POST", path, token, "incident #12");
+
+        assert_eq!(
+            request("/control/freeze/blog", None).await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            request("/control/freeze/blog", Some("guess")).await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            request("/control/freeze/shop", Some("s3cret")).await,
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            request("/control/freeze", Some("s3cret")).await,
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            request("/control/thaw/blog", Some("s3cret")).await,
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(deploy_schedule::frozen(&root).unwrap(), None);
+
+        assert_eq!(
+            request("/control/freeze/blog", Some("s3cret")).await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            deploy_schedule::frozen(&root).unwrap().as_deref(),
+            Some("incident #12")
+        );
+        assert_eq!(
+            request("/control/unfreeze/blog", Some("s3cret")).await,
+            StatusCode::OK
+        );
+        assert_eq!(deploy_schedule::frozen(&root).unwrap(), None);
+
+        let (pending, _) = approvals.request(60, deploy_history::now());
+        assert_eq!(
+            request_with("GET", "/status/blog", None, "").await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            request_with("GET", "/status/blog", Some("s3cret"), "").await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            request("/control/approve/blog", Some("s3cret")).await,
+            StatusCode::NOT_FOUND
+        );
+        assert!(approved.try_recv().is_err());
+        assert_eq!(
+            request_with("POST", "/control/approve/blog", Some("s3cret"), &pending.id).await,
+            StatusCode::OK
+        );
+        assert_eq!(approved.try_recv().ok(), Some(pending));
+    }
+
+    fn app_route(
+        name: &'static str,
+        endpoint: &str,
+        pub_key: &RSAPublicKey,
+    ) -> Route<&'static str> {
+        Route {
+            name: Some(name.to_owned()),
+            root: PathBuf::from("."),
+            config: shared_config(endpoint),
+            public_key: pub_key.clone(),
+            approvals: Approvals::new(PathBuf::from(".")).0,
+            state: name,
+        }
+    }
+
+    fn shared_config(endpoint: &str) -> SharedConfig {
+        SharedConfig::new(
+            Default::default(),
+            types::Config {
+                endpoint: endpoint.to_owned(),
+                ..Default::default()
+            },
+        )
+    }
+
     fn get_filter(
         endpoint: &str,
         pub_key: RSAPublicKey,
     ) -> impl Filter<Extract = (reply::WithStatus<Cow<'static, str>>,), Error = warp::Rejection> + Clone
     {
         refresher(
-            Arc::new(types::Config {
-                endpoint: endpoint.to_owned(),
-            }),
-            Arc::new(pub_key),
-            (),
-            move |_| Ok(()),
+            Arc::new(vec![Route {
+                name: None,
+                root: PathBuf::from("."),
+                config: shared_config(endpoint),
+                public_key: pub_key,
+                approvals: Approvals::new(PathBuf::from(".")).0,
+                state: (),
+            }]),
+            move |_| async { Ok(()) },
         )
     }
 
+    async fn make_request_to(
+        path: &str,
+        endpoint: &str,
+        priv_key: &RSAPrivateKey,
+        filter: &(impl Filter<Extract = (reply::WithStatus<Cow<'static, str>>,), Error = warp::Rejection>
+              + Clone
+              + 'static),
+    ) -> Result<StatusCode, StatusCode> {
+        let payload = serde_json::to_vec(&Payload {
+            timestamp: now_ms(),
+            endpoint: endpoint.to_owned(),
+            commit: None,
+        })
+        .unwrap();
+
+        let response = warp::test::request()
+            .method("POST")
+            .path(path)
+            .body(&payload)
+            .header(SIGNATURE_HEADER_NAME, sign_and_hash(&payload, priv_key))
+            .filter(filter)
+            .await;
+
+        match response {
+            Ok(r) => Ok(r.into_response().status()),
+            Err(rejection) => Err(rejection
+                .find::<types::ValidationResult>()
+                .map_or(StatusCode::NOT_FOUND, |res| res.status)),
+        }
+    }
+
     async fn make_request(
         endpoint: &str,
         priv_key: RSAPrivateKey,
@@ -156,6 +582,7 @@ mod tests {
         let payload = serde_json::to_vec(&Payload {
             timestamp: now_ms(),
             endpoint: endpoint.to_owned(),
+            commit: None,
         })
         .unwrap();
 
@@ -194,21 +621,6 @@ mod tests {
     }
 
     fn sign_and_hash(body: &[u8], priv_key: &RSAPrivateKey) -> String {
-        base64::encode(
-            priv_key
-                .sign(
-                    PaddingScheme::PKCS1v15Sign {
-                        hash: Some(Hash::SHA2_256),
-                    },
-                    &hash(body),
-                )
-                .expect("signing request failed"),
-        )
-    }
-
-    fn hash(body: &[u8]) -> Vec<u8> {
-        let mut hasher = Sha256::new();
-        hasher.update(body);
-        Vec::from(hasher.finalize().as_slice())
+        crate::signer::sign(body, priv_key).expect("signing request failed")
     }
 }
@@ -0,0 +1,269 @@
+use {
+    super::constants::{
+        INVALID_POLL_INTERVAL_ERROR, POLL_FETCH_FAILED_WARN, STAT_POLL_FAILED_TARGET,
+        STAT_POLL_NEW_COMMIT,
+    },
+    super::git_updater,
+    super::shared_config::SharedConfig,
+    super::types::StatusReply,
+    anyhow::{bail, Result},
+    log::{debug, error, info, warn},
+    rand::Rng,
+    std::{future::Future, path::PathBuf, time::Duration},
+    tokio::{task, time},
+};
+
+// Fraction of the delay by which each poll is randomly shifted
+const JITTER: f64 = 0.1;
+// Upper bound for the backoff, as a multiple of the poll interval
+const MAX_BACKOFF_FACTOR: u32 = 16;
+
+pub async fn listen<S, F>(
+    interval: Duration,
+    root: PathBuf,
+    config: SharedConfig,
+    state: S,
+    handler: impl Fn(S) -> F,
+) where
+    S: Clone,
+    F: Future<Output = Result<()>>,
+{
+    let mut consecutive_failures = 0;
+    // Deploying this again would fail or be refused the same way, so it waits
+    // for the target to move
+    let mut failed_target = None;
+
+    loop {
+        let delay = with_jitter(
+            backoff(interval, consecutive_failures),
+            rand::thread_rng().gen_range(-JITTER..=JITTER),
+        );
+
+        debug!("Next poll in {:?}", delay);
+        time::sleep(delay).await;
+
+        let git_config = config.get().git.clone();
+        let root = root.clone();
+        match task::spawn_blocking(move || {
+            git_updater::fetch_and_check_for_update(Some(&root), &git_config)
+        })
+        .await
+        .unwrap()
+        {
+            Ok(Some(target)) if failed_target.as_ref() == Some(&target) => {
+                debug!("{} ({})", STAT_POLL_FAILED_TARGET, target)
+            }
+            Ok(Some(target)) => {
+                info!("{}", STAT_POLL_NEW_COMMIT);
+
+                match handler(state.clone()).await {
+                    Ok(()) => {
+                        consecutive_failures = 0;
+                        failed_target = None;
+                    }
+                    // Held or awaiting approval, which was logged already
+                    Err(e) if e.is::<StatusReply>() => {
+                        debug!("{}", e);
+                        failed_target = Some(target);
+                    }
+                    Err(e) => {
+                        consecutive_failures += 1;
+                        error!("{}", e);
+                        failed_target = Some(target);
+                    }
+                }
+            }
+            Ok(None) => consecutive_failures = 0,
+            Err(e) => {
+                consecutive_failures += 1;
+                error!("{}", e);
+                warn!("{}", POLL_FETCH_FAILED_WARN);
+            }
+        }
+    }
+}
+
+/// Parses intervals such as `90`, `30s`, `5m` or `1h` (bare numbers are seconds)
+pub fn parse_interval(interval: &str) -> Result<Duration> {
+    let interval = interval.trim();
+    let (amount, unit_secs) = match interval.char_indices().last() {
+        Some((i, 's')) => (&interval[..i], 1),
+        Some((i, 'm')) => (&interval[..i], 60),
+        Some((i, 'h')) => (&interval[..i], 60 * 60),
+        _ => (interval, 1),
+    };
+
+    match amount
+        .parse::<u64>()
+        .ok()
+        .and_then(|amount| amount.checked_mul(unit_secs))
+    {
+        Some(secs) if secs > 0 => Ok(Duration::from_secs(secs)),
+        _ => bail!("{}: {}", INVALID_POLL_INTERVAL_ERROR, interval),
+    }
+}
+
+fn backoff(interval: Duration, consecutive_failures: u32) -> Duration {
+    let factor = 2u32
+        .saturating_pow(consecutive_failures)
+        .min(MAX_BACKOFF_FACTOR);
+
+    interval.saturating_mul(factor)
+}
+
+fn with_jitter(delay: Duration, jitter: f64) -> Duration {
+    Duration::try_from_secs_f64(delay.as_secs_f64() * (1.0 + jitter)).unwrap_or(Duration::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::types::{GitConfig, PathsConfig},
+        std::{
+            fs,
+            path::Path,
+            process::Command,
+            sync::{
+                atomic::{AtomicUsize, Ordering},
+                Arc,
+            },
+        },
+    };
+
+    #[tokio::test]
+    async fn skipped_targets_are_polled_once() {
+        Command::new("./src/functionality/git_updater_test_prep.sh")
+            .arg("test_repo_poll_skip")
+            .output()
+            .expect("Failed to prepare repos");
+        let (remote, root) = (
+            "test_repo_poll_skip1",
+            PathBuf::from("test_repo_poll_skip2"),
+        );
+        let git_config = GitConfig {
+            paths: Some(PathsConfig {
+                include: vec!["src/**".to_owned()],
+                exclude: vec![],
+                update_tree: false,
+            }),
+            ..Default::default()
+        };
+        git_updater::update_git_from_remote(Some(&root), &git_config, None).unwrap();
+
+        fs::write(Path::new(remote).join("temp"), "docs only").unwrap();
+        Command::new("git")
+            .args(["commit", "-am", "irrelevant"])
+            .current_dir(remote)
+            .output()
+            .expect("Failed to commit to remote");
+
+        let config = SharedConfig::new(
+            Default::default(),
+            crate::types::Config {
+                git: git_config,
+                ..Default::default()
+            },
+        );
+        let deploys = Arc::new(AtomicUsize::new(0));
+        let poller = tokio::spawn(listen(
+            Duration::from_millis(200),
+            root.clone(),
+            config.clone(),
+            deploys.clone(),
+            move |deploys: Arc<AtomicUsize>| {
+                let (root, config) = (root.clone(), config.clone());
+                async move {
+                    deploys.fetch_add(1, Ordering::SeqCst);
+                    task::spawn_blocking(move || {
+                        let config = config.get();
+                        git_updater::update_git_from_remote(
+                            Some(&root),
+                            &config.git,
+                            config.env_file.as_deref(),
+                        )
+                    })
+                    .await
+                    .unwrap()
+                    .map(|_| ())
+                }
+            },
+        ));
+
+        // Enough for several intervals, only the first of which finds the push
+        time::sleep(Duration::from_millis(1500)).await;
+        poller.abort();
+        assert_eq!(deploys.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn failed_targets_are_polled_once() {
+        Command::new("./src/functionality/git_updater_test_prep.sh")
+            .arg("test_repo_poll_fail")
+            .output()
+            .expect("Failed to prepare repos");
+        let (remote, root) = (
+            "test_repo_poll_fail1",
+            PathBuf::from("test_repo_poll_fail2"),
+        );
+        git_updater::update_git_from_remote(Some(&root), &Default::default(), None).unwrap();
+
+        fs::write(Path::new(remote).join("temp"), "broken").unwrap();
+        Command::new("git")
+            .args(["commit", "-am", "broken"])
+            .current_dir(remote)
+            .output()
+            .expect("Failed to commit to remote");
+
+        let deploys = Arc::new(AtomicUsize::new(0));
+        let poller = tokio::spawn(listen(
+            Duration::from_millis(200),
+            root,
+            SharedConfig::new(Default::default(), Default::default()),
+            deploys.clone(),
+            |deploys: Arc<AtomicUsize>| async move {
+                deploys.fetch_add(1, Ordering::SeqCst);
+                Err(anyhow::anyhow!("install failed"))
+            },
+        ));
+
+        // The failed deploy isn't retried on later polls, as HEAD didn't move
+        time::sleep(Duration::from_millis(1500)).await;
+        poller.abort();
+        assert_eq!(deploys.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn parses_intervals() {
+        assert_eq!(parse_interval("90").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_interval("30s").unwrap(), Duration::from_secs(30));
+        assert_eq!(parse_interval("5m").unwrap(), Duration::from_secs(300));
+        assert_eq!(parse_interval("1h").unwrap(), Duration::from_secs(3600));
+        assert!(parse_interval("0").is_err());
+        assert!(parse_interval("soon").is_err());
+        assert!(parse_interval("").is_err());
+        assert!(parse_interval("999999999999999999h").is_err());
+        assert_eq!(
+            parse_interval("18446744073709551615").unwrap(),
+            Duration::from_secs(u64::MAX)
+        );
+    }
+
+    #[test]
+    fn backoff_doubles_and_caps() {
+        let interval = Duration::from_secs(10);
+        assert_eq!(backoff(interval, 0), interval);
+        assert_eq!(backoff(interval, 1), interval * 2);
+        assert_eq!(backoff(interval, 3), interval * 8);
+        assert_eq!(backoff(interval, 40), interval * MAX_BACKOFF_FACTOR);
+        assert_eq!(backoff(Duration::MAX, 3), Duration::MAX);
+    }
+
+    #[test]
+    fn jitter_stays_in_bounds() {
+        let delay = Duration::from_secs(100);
+        assert_eq!(with_jitter(delay, JITTER), Duration::from_secs(110));
+        assert_eq!(with_jitter(delay, -JITTER), Duration::from_secs(90));
+        assert_eq!(with_jitter(Duration::MAX, JITTER), Duration::MAX);
+    }
+}
@@ -4,38 +4,44 @@ use {
         STAT_REQUEST_RECEIVED, STAT_SIGNATURE_VALIDATION_FAILED, STAT_SIGNATURE_VALIDATION_SUCCESS,
         STDIN_REGEX, STDIN_RESPONDED_SUCCESSFULLY, STDIN_SUCCESS,
     },
+    super::shared_config::SharedConfig,
     super::signature_verifier,
+    super::supervisor::ChildPipes,
     super::types::{Config, ValidationResult},
     anyhow::Result,
     log::{debug, error, info, warn},
     regex::Regex,
     rsa::RSAPublicKey,
-    serde_json,
     std::{
-        cell::RefCell,
         io::{self, BufRead, BufReader, Write},
-        process::{self, Child},
-        rc::Rc,
+        process, thread,
+        time::Duration,
     },
 };
 
+// Wait before scanning again after a failed deploy, in case the program exited
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
 pub fn listen(
     public_key: &RSAPublicKey,
-    config: &Config,
-    child: Rc<RefCell<Child>>,
-    handler: &mut impl FnMut() -> Result<Rc<RefCell<Child>>>,
+    config: &SharedConfig,
+    pipes: ChildPipes,
+    handler: &mut impl FnMut() -> Result<ChildPipes>,
 ) {
-    let mut child_ref = child;
+    let mut pipes = pipes;
     let stdin_regex = Regex::new(STDIN_REGEX).unwrap();
     loop {
         scan_process_stdout_until_success(
-            child_ref.clone(),
+            &mut pipes,
             &stdin_regex,
             |payload, signature, writer| {
                 info!("{}", STAT_REQUEST_RECEIVED);
 
                 let response = match validation_result_to_string(validate_and_return_response(
-                    payload, signature, config, public_key,
+                    payload,
+                    signature,
+                    &config.get(),
+                    public_key,
                 )) {
                     Some(r) => r,
                     None => return,
@@ -50,23 +56,24 @@ pub fn listen(
         debug!("Successful request, trying to restart process");
 
         match handler() {
-            Ok(new_child) => {
-                child_ref = new_child;
+            Ok(new_pipes) => {
+                pipes = new_pipes;
+            }
+            Err(e) => {
+                error!("{}", e);
+                thread::sleep(RETRY_DELAY);
             }
-            Err(e) => error!("{}", e),
         }
     }
 }
 
 fn scan_process_stdout_until_success(
-    child: Rc<RefCell<Child>>,
+    pipes: &mut ChildPipes,
     stdin_regex: &Regex,
-    handle_request: impl Fn(&[u8], &str, &mut process::ChildStdin) -> (),
+    handle_request: impl Fn(&[u8], &str, &mut process::ChildStdin),
 ) {
-    let mut child = child.borrow_mut();
-
-    let mut writer = child.stdin.take().unwrap();
-    let reader = BufReader::new(child.stdout.as_mut().unwrap());
+    let writer = &mut pipes.stdin;
+    let reader = BufReader::new(&mut pipes.stdout);
 
     for line in reader.lines().filter_map(filter_valid_lines) {
         if line == STDIN_SUCCESS {
@@ -74,7 +81,7 @@ fn scan_process_stdout_until_success(
         }
 
         match get_matches(&line, stdin_regex) {
-            Some((payload, signature)) => handle_request(payload, signature, &mut writer),
+            Some((payload, signature)) => handle_request(payload, signature, writer),
             None => println!("{}", &line),
         }
     }
@@ -83,10 +90,7 @@ fn scan_process_stdout_until_success(
 // Helpers
 
 fn filter_valid_lines(line: Result<String, io::Error>) -> Option<String> {
-    match line {
-        Ok(line) => Some(line),
-        Err(_) => None,
-    }
+    line.ok()
 }
 
 fn write_response(response: &str, writer: &mut std::process::ChildStdin) {
@@ -99,7 +103,7 @@ fn write_response(response: &str, writer: &mut std::process::ChildStdin) {
     };
 }
 
-fn get_matches<'a>(line: &'a str, stdin_regex: &Regex) -> Option<(&'a [u8], &'a str)> {
+pub fn get_matches<'a>(line: &'a str, stdin_regex: &Regex) -> Option<(&'a [u8], &'a str)> {
     let matches = stdin_regex.captures(line)?;
     let payload = matches.get(1)?.as_str().as_bytes();
     let signature = matches.get(2)?.as_str();
@@ -167,7 +171,7 @@ mod tests {
     fn test_stdio() {
         compile_test_bin();
 
-        let test_bin =
+        let mut test_bin =
             Command::new(fs::canonicalize("./src/event_handlers/stdio_test/test_bin").unwrap())
                 .stdin(Stdio::piped())
                 .stdout(Stdio::piped())
@@ -177,12 +181,17 @@ mod tests {
         let stdin_regex = Regex::new(STDIN_REGEX).unwrap();
 
         scan_process_stdout_until_success(
-            Rc::new(RefCell::new(test_bin)),
+            &mut ChildPipes {
+                stdin: test_bin.stdin.take().unwrap(),
+                stdout: test_bin.stdout.take().unwrap(),
+            },
             &stdin_regex,
             |_payload, _signature, writer| {
                 writer.write_all(b"ok\n").and(writer.flush()).unwrap();
             },
-        )
+        );
+
+        assert!(test_bin.wait().unwrap().success());
     }
 
     fn compile_test_bin() {
@@ -0,0 +1,245 @@
+use {
+    super::constants::{
+        FILE_WATCHER_ERROR, INVALID_WATCH_GLOB_ERROR, STAT_FILES_CHANGED, STAT_WATCHING_FILES,
+    },
+    super::types::WatchConfig,
+    anyhow::{Context, Result},
+    globset::{Glob, GlobSet, GlobSetBuilder},
+    ignore::gitignore::Gitignore,
+    log::{debug, error, info},
+    notify::{Event, RecommendedWatcher, RecursiveMode, Watcher},
+    std::{
+        cell::RefCell,
+        collections::HashMap,
+        ffi::OsStr,
+        future::Future,
+        path::{Path, PathBuf},
+        time::Duration,
+    },
+    tokio::{sync::mpsc, time},
+};
+
+// How long the tree has to stay quiet before a burst of changes triggers a restart
+const DEBOUNCE: Duration = Duration::from_millis(300);
+
+pub async fn listen<S, F>(
+    root: &Path,
+    watch_config: &WatchConfig,
+    state: S,
+    handler: impl Fn(S) -> F,
+) -> Result<()>
+where
+    S: Clone,
+    F: Future<Output = Result<()>>,
+{
+    let root = root.canonicalize().with_context(|| FILE_WATCHER_ERROR)?;
+    let filter = PathFilter::new(&root, watch_config)?;
+
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let mut watcher = RecommendedWatcher::new(
+        move |event: notify::Result<Event>| {
+            let _ = tx.send(event);
+        },
+        notify::Config::default(),
+    )
+    .with_context(|| FILE_WATCHER_ERROR)?;
+
+    watcher
+        .watch(&root, RecursiveMode::Recursive)
+        .with_context(|| FILE_WATCHER_ERROR)?;
+
+    info!("{}", STAT_WATCHING_FILES);
+
+    while let Some(event) = rx.recv().await {
+        if !filter.is_relevant_event(event) {
+            continue;
+        }
+
+        // Wait for the burst of changes to settle down before restarting
+        while let Ok(Some(_)) = time::timeout(DEBOUNCE, rx.recv()).await {}
+
+        info!("{}", STAT_FILES_CHANGED);
+
+        if let Err(e) = handler(state.clone()).await {
+            error!("{}", e);
+        }
+    }
+
+    Ok(())
+}
+
+struct PathFilter {
+    root: PathBuf,
+    /// `.gitignore` files by the directory they're in, relative to the root,
+    /// read when a change under it is first seen
+    gitignores: RefCell<HashMap<PathBuf, Gitignore>>,
+    include: Option<GlobSet>,
+    exclude: GlobSet,
+}
+
+impl PathFilter {
+    fn new(root: &Path, watch_config: &WatchConfig) -> Result<Self> {
+        Ok(PathFilter {
+            root: root.to_owned(),
+            gitignores: Default::default(),
+            include: if watch_config.include.is_empty() {
+                None
+            } else {
+                Some(build_glob_set(&watch_config.include)?)
+            },
+            exclude: build_glob_set(&watch_config.exclude)?,
+        })
+    }
+
+    fn is_relevant_event(&self, event: notify::Result<Event>) -> bool {
+        match event {
+            Ok(event) if !event.kind.is_access() => {
+                event.paths.iter().any(|path| self.is_relevant(path))
+            }
+            Ok(_) => false,
+            Err(e) => {
+                error!("{}: {}", FILE_WATCHER_ERROR, e);
+                false
+            }
+        }
+    }
+
+    fn is_relevant(&self, path: &Path) -> bool {
+        let relative = match path.strip_prefix(&self.root) {
+            Ok(relative) => relative,
+            Err(_) => return false,
+        };
+
+        let relevant = !relative.starts_with(".git")
+            && !self.is_ignored(relative, path.is_dir())
+            && !self.exclude.is_match(relative)
+            && self
+                .include
+                .as_ref()
+                .is_none_or(|include| include.is_match(relative));
+
+        debug!("Changed: {:?} (relevant: {})", relative, relevant);
+        relevant
+    }
+
+    /// Checks the `.gitignore` files of every directory above `relative`,
+    /// where deeper ones take precedence as in git
+    fn is_ignored(&self, relative: &Path, is_dir: bool) -> bool {
+        let mut gitignores = self.gitignores.borrow_mut();
+        if relative.file_name() == Some(OsStr::new(".gitignore")) {
+            // Read again when next needed
+            gitignores.remove(relative.parent().unwrap_or(Path::new("")));
+        }
+
+        let path = self.root.join(relative);
+        relative
+            .ancestors()
+            .skip(1)
+            .find_map(|dir| {
+                let gitignore = gitignores
+                    .entry(dir.to_owned())
+                    // Missing or unreadable .gitignore files simply don't
+                    // ignore anything
+                    .or_insert_with(|| Gitignore::new(self.root.join(dir).join(".gitignore")).0);
+                let matched = gitignore.matched_path_or_any_parents(&path, is_dir);
+                (!matched.is_none()).then(|| matched.is_ignore())
+            })
+            .unwrap_or(false)
+    }
+}
+
+fn build_glob_set(globs: &[String]) -> Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+
+    for glob in globs {
+        builder.add(
+            Glob::new(glob).with_context(|| format!("{}: {}", INVALID_WATCH_GLOB_ERROR, glob))?,
+        );
+    }
+
+    builder.build().with_context(|| INVALID_WATCH_GLOB_ERROR)
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, std::fs};
+
+    #[test]
+    fn filters_paths() {
+        let root = prepare_tree("test_repo_watch", "target/\n*.log\n");
+
+        let filter = PathFilter::new(
+            &root,
+            &WatchConfig {
+                include: vec![],
+                exclude: vec!["docs/**".to_owned()],
+            },
+        )
+        .unwrap();
+
+        assert!(filter.is_relevant(&root.join("src/main.rs")));
+        assert!(!filter.is_relevant(&root.join("target/debug/app")));
+        assert!(!filter.is_relevant(&root.join("server.log")));
+        assert!(!filter.is_relevant(&root.join("docs/index.md")));
+        assert!(!filter.is_relevant(&root.join(".git/index")));
+        assert!(!filter.is_relevant(Path::new("/somewhere/else")));
+
+        // Nested .gitignore files apply below their directory, over the root's,
+        // and are read again when the watcher sees them change
+        let write_gitignore = |contents: &str| {
+            fs::create_dir_all(root.join("src")).unwrap();
+            fs::write(root.join("src/.gitignore"), contents).unwrap();
+            filter.is_relevant(&root.join("src/.gitignore"));
+        };
+        write_gitignore("*.gen.rs\n!keep.log\n");
+        assert!(!filter.is_relevant(&root.join("src/api.gen.rs")));
+        assert!(filter.is_relevant(&root.join("api.gen.rs")));
+        assert!(filter.is_relevant(&root.join("src/keep.log")));
+        assert!(!filter.is_relevant(&root.join("src/other.log")));
+
+        write_gitignore("");
+        assert!(filter.is_relevant(&root.join("src/api.gen.rs")));
+    }
+
+    #[test]
+    fn filters_included_paths() {
+        let root = prepare_tree("test_repo_watch_include", "");
+
+        let filter = PathFilter::new(
+            &root,
+            &WatchConfig {
+                include: vec!["src/**/*.rs".to_owned()],
+                exclude: vec!["src/generated/**".to_owned()],
+            },
+        )
+        .unwrap();
+
+        assert!(filter.is_relevant(&root.join("src/main.rs")));
+        assert!(filter.is_relevant(&root.join("src/nested/mod.rs")));
+        assert!(!filter.is_relevant(&root.join("src/generated/api.rs")));
+        assert!(!filter.is_relevant(&root.join("README.md")));
+    }
+
+    #[test]
+    fn rejects_invalid_globs() {
+        let root = prepare_tree("test_repo_watch_invalid", "");
+
+        let filter = PathFilter::new(
+            &root,
+            &WatchConfig {
+                include: vec!["src/[".to_owned()],
+                exclude: vec![],
+            },
+        );
+
+        assert!(filter.is_err());
+    }
+
+    fn prepare_tree(name: &str, gitignore: &str) -> PathBuf {
+        let _ = fs::remove_dir_all(name);
+        fs::create_dir_all(name).expect("Failed to create test tree");
+        fs::write(Path::new(name).join(".gitignore"), gitignore)
+            .expect("Failed to write .gitignore");
+        fs::canonicalize(name).unwrap()
+    }
+}
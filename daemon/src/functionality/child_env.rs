@@ -0,0 +1,101 @@
+use {
+    super::constants::{ENV_FILE_TRACKED_WARN, INVALID_ENV_FILE_ERROR},
+    super::git_updater,
+    anyhow::{bail, Context, Result},
+    log::warn,
+    std::{
+        collections::BTreeMap,
+        fs,
+        path::Path,
+        time::{SystemTime, UNIX_EPOCH},
+    },
+};
+
+/// Variables describing the deploy a process was started by
+pub fn deploy_metadata(root: &Path, app: Option<&str>) -> BTreeMap<String, String> {
+    let mut metadata = BTreeMap::new();
+
+    if let Ok(commit) = git_updater::rev_parse(Some(root), "HEAD") {
+        metadata.insert("REPL_DEPLOY_COMMIT".to_owned(), commit);
+    }
+    if let Ok(now) = SystemTime::now().duration_since(UNIX_EPOCH) {
+        metadata.insert(
+            "REPL_DEPLOY_DEPLOYED_AT".to_owned(),
+            now.as_secs().to_string(),
+        );
+    }
+    if let Some(app) = app {
+        metadata.insert("REPL_DEPLOY_APP".to_owned(), app.to_owned());
+    }
+
+    metadata
+}
+
+/// Reads the `env_file`, relative to the repository root. It's read again for
+/// every start, so edits apply on the next deploy or restart. Fails when it's
+/// missing or invalid, rather than starting processes without their secrets.
+pub fn read_env_file(root: &Path, env_file: &str) -> Result<BTreeMap<String, String>> {
+    if git_updater::is_tracked(Some(root), env_file) {
+        warn!("{} ({})", ENV_FILE_TRACKED_WARN, env_file);
+    }
+
+    let path = root.join(env_file);
+    fs::read_to_string(&path)
+        .map_err(anyhow::Error::from)
+        .and_then(|contents| parse_env_file(&contents))
+        .with_context(|| format!("{} ({})", INVALID_ENV_FILE_ERROR, path.display()))
+}
+
+/// Parses `KEY=value` lines, skipping blank lines and `#` comments. Values may
+/// be quoted and lines may start with `export`, as in most `.env` files.
+pub fn parse_env_file(contents: &str) -> Result<BTreeMap<String, String>> {
+    let mut env = BTreeMap::new();
+
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let (key, value) = match line.split_once('=') {
+            Some((key, value)) if !key.trim().is_empty() => (key.trim(), value.trim()),
+            _ => bail!("line {}: expected KEY=value", i + 1),
+        };
+
+        let value = [('"', '"'), ('\'', '\'')]
+            .iter()
+            .find_map(|(open, close)| {
+                value
+                    .strip_prefix(*open)
+                    .and_then(|value| value.strip_suffix(*close))
+            })
+            .unwrap_or(value);
+
+        env.insert(key.to_owned(), value.to_owned());
+    }
+
+    Ok(env)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_env_files() {
+        let env = parse_env_file(
+            "# Secrets\nAPI_TOKEN=abc=123\n\nexport NAME = \"repl deploy\"\nQUOTED='single'\nEMPTY=\n",
+        )
+        .unwrap();
+
+        assert_eq!(env["API_TOKEN"], "abc=123");
+        assert_eq!(env["NAME"], "repl deploy");
+        assert_eq!(env["QUOTED"], "single");
+        assert_eq!(env["EMPTY"], "");
+        assert_eq!(env.len(), 4);
+
+        assert!(parse_env_file("NOT A VARIABLE").is_err());
+        assert!(parse_env_file("=value").is_err());
+    }
+}
@@ -0,0 +1,348 @@
+use {
+    super::constants::{
+        ALLOWLIST_IN_REPOSITORY_ERROR, MISSING_ALLOWLIST_ERROR, UNSIGNED_COMMIT_ERROR,
+        UNTRUSTED_SIGNATURE_ERROR,
+    },
+    super::types::VerifyConfig,
+    anyhow::{bail, Context, Result},
+    std::{
+        path::{Path, PathBuf},
+        process::Command,
+    },
+};
+
+enum Signature {
+    Gpg,
+    Ssh,
+}
+
+/// Checks that `rev` carries a good signature from a key in the allowlist: the
+/// tag's own signature for annotated tags, otherwise the commit's
+pub fn verify(cwd: Option<&Path>, rev: &str, verify_config: &VerifyConfig) -> Result<()> {
+    let root = cwd.unwrap_or_else(|| Path::new("."));
+    let kind = match git(root, &["cat-file", "-t", rev]) {
+        Some(kind) if kind.trim() == "tag" => "tag",
+        _ => "commit",
+    };
+
+    let object = git(root, &["cat-file", kind, rev]).unwrap_or_default();
+    let signature = match signature(&object, kind) {
+        Some(signature) => signature,
+        None => bail!("{} ({})", UNSIGNED_COMMIT_ERROR, rev),
+    };
+
+    let mut verify = Command::new("git");
+    match signature {
+        Signature::Ssh => {
+            let signers = allowlist(
+                root,
+                verify_config.allowed_signers.as_deref(),
+                "allowed_signers",
+            )?;
+            verify
+                .arg("-c")
+                .arg(format!("gpg.ssh.allowedSignersFile={}", signers.display()));
+        }
+        Signature::Gpg => {
+            let home = allowlist(root, verify_config.gpg_home.as_deref(), "gpg_home")?;
+            verify.env("GNUPGHOME", home);
+        }
+    }
+
+    let verify_command = format!("verify-{}", kind);
+    let output = verify
+        .args([verify_command.as_str(), rev])
+        .current_dir(root)
+        .output()
+        .with_context(|| format!("{} ({})", UNTRUSTED_SIGNATURE_ERROR, rev))?;
+
+    if !output.status.success() {
+        bail!("{} ({})", UNTRUSTED_SIGNATURE_ERROR, rev);
+    }
+
+    Ok(())
+}
+
+/// The type of a commit's `gpgsig` header, or of the block a tag's message
+/// ends with, so that markers quoted in messages don't count
+fn signature(object: &str, kind: &str) -> Option<Signature> {
+    let marker = match kind {
+        "tag" => object.trim_end().lines().last()?,
+        _ => object
+            .lines()
+            .take_while(|line| !line.is_empty())
+            .find_map(|line| line.strip_prefix("gpgsig "))?,
+    };
+
+    match marker {
+        "-----BEGIN SSH SIGNATURE-----" | "-----END SSH SIGNATURE-----" => Some(Signature::Ssh),
+        "-----BEGIN PGP SIGNATURE-----" | "-----END PGP SIGNATURE-----" => Some(Signature::Gpg),
+        _ => None,
+    }
+}
+
+/// Resolves an allowlist path, relative to the repository at `root`, refusing
+/// ones inside it, which a malicious push could change along with the commit
+fn allowlist(root: &Path, path: Option<&str>, key: &str) -> Result<PathBuf> {
+    let path = match path {
+        Some(path) => root.join(path),
+        None => bail!("{}: git.verify.{}", MISSING_ALLOWLIST_ERROR, key),
+    };
+
+    let path = path
+        .canonicalize()
+        .with_context(|| format!("{}: {}", MISSING_ALLOWLIST_ERROR, path.display()))?;
+    let root = root.canonicalize()?;
+    if path.starts_with(&root) {
+        bail!("{} ({})", ALLOWLIST_IN_REPOSITORY_ERROR, path.display());
+    }
+
+    Ok(path)
+}
+
+fn git(root: &Path, args: &[&str]) -> Option<String> {
+    let output = Command::new("git")
+        .args(args)
+        .current_dir(root)
+        .output()
+        .ok()?;
+
+    match output.status.success() {
+        true => Some(String::from_utf8_lossy(&output.stdout).into_owned()),
+        false => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, std::fs};
+
+    #[test]
+    fn verifies_ssh_signatures() {
+        let dir = fs::canonicalize(prepare_dir("test_repo_verify")).unwrap();
+        let repo = dir.join("repo");
+        fs::create_dir_all(&repo).unwrap();
+        run(&repo, "git", &["init", "-q"]);
+
+        let allowed = ssh_key(&dir, "allowed");
+        let other = ssh_key(&dir, "other");
+        let signers = dir.join("allowed_signers");
+        fs::write(
+            &signers,
+            format!(
+                "deploy@example.com {}",
+                fs::read_to_string(dir.join("allowed.pub")).unwrap()
+            ),
+        )
+        .unwrap();
+
+        let verify_config = VerifyConfig {
+            allowed_signers: Some(signers.display().to_string()),
+            gpg_home: None,
+        };
+        let check = |key: Option<&Path>| {
+            commit(&repo, key);
+            verify(Some(&repo), "HEAD", &verify_config)
+        };
+
+        assert!(check(Some(&allowed)).is_ok());
+        assert!(check(Some(&other)).is_err());
+        assert!(check(None).is_err());
+
+        // Signed tags are checked instead of the commit they point to
+        run(
+            &repo,
+            "git",
+            &signing_args(&allowed, &["tag", "-s", "-m", "release", "v1"]),
+        );
+        assert!(verify(Some(&repo), "v1", &verify_config).is_ok());
+
+        // Relative allowlists are found from the repository, not the daemon's
+        // working directory
+        let relative = VerifyConfig {
+            allowed_signers: Some("../allowed_signers".to_owned()),
+            gpg_home: None,
+        };
+        assert!(verify(Some(&repo), "v1", &relative).is_ok());
+
+        // Allowlists in the repository could be changed by the push itself
+        fs::copy(&signers, repo.join("allowed_signers")).unwrap();
+        let in_repo = VerifyConfig {
+            allowed_signers: Some(repo.join("allowed_signers").display().to_string()),
+            gpg_home: None,
+        };
+        commit(&repo, Some(&allowed));
+        assert!(verify(Some(&repo), "HEAD", &in_repo).is_err());
+
+        // Quoting a signature in the message doesn't make a commit signed
+        run(
+            &repo,
+            "git",
+            &signing_args(
+                Path::new(""),
+                &[
+                    "commit",
+                    "-q",
+                    "--allow-empty",
+                    "-m",
+                    "-----BEGIN SSH SIGNATURE-----",
+                ],
+            ),
+        );
+        let e = verify(Some(&repo), "HEAD", &verify_config).unwrap_err();
+        assert!(e.to_string().starts_with(UNSIGNED_COMMIT_ERROR));
+    }
+
+    #[test]
+    fn verifies_gpg_signatures() {
+        let dir = fs::canonicalize(prepare_dir("test_repo_verify_gpg")).unwrap();
+        let (repo, home, other) = (dir.join("repo"), dir.join("gnupg"), dir.join("other"));
+        fs::create_dir_all(&repo).unwrap();
+        run(&repo, "git", &["init", "-q"]);
+        for home in [&home, &other] {
+            fs::create_dir_all(home).unwrap();
+            fs::set_permissions(home, std::os::unix::fs::PermissionsExt::from_mode(0o700)).unwrap();
+            gpg(
+                home,
+                &[
+                    "--batch",
+                    "--passphrase",
+                    "",
+                    "--quick-gen-key",
+                    "Deploy <deploy@example.com>",
+                    "ed25519",
+                    "sign",
+                    "never",
+                ],
+            );
+        }
+
+        let verify_config = VerifyConfig {
+            allowed_signers: None,
+            gpg_home: Some(home.display().to_string()),
+        };
+        let gpg_commit = |home: &Path, message: &str| {
+            let output = Command::new("git")
+                .args([
+                    "-c",
+                    "user.name=Test Runner",
+                    "-c",
+                    "user.email=deploy@example.com",
+                    "-c",
+                    "user.signingkey=deploy@example.com",
+                    "commit",
+                    "-q",
+                    "-S",
+                    "--allow-empty",
+                    "-m",
+                    message,
+                ])
+                .env("GNUPGHOME", home)
+                .current_dir(&repo)
+                .output()
+                .unwrap();
+            assert!(
+                output.status.success(),
+                "{}",
+                String::from_utf8_lossy(&output.stderr)
+            );
+        };
+
+        gpg_commit(&home, "signed");
+        assert!(verify(Some(&repo), "HEAD", &verify_config).is_ok());
+        // A key outside the allowed keyring
+        gpg_commit(&other, "signed elsewhere");
+        assert!(verify(Some(&repo), "HEAD", &verify_config).is_err());
+        // Goes to GPG despite the SSH marker in the message
+        gpg_commit(&home, "-----BEGIN SSH SIGNATURE-----");
+        assert!(verify(Some(&repo), "HEAD", &verify_config).is_ok());
+
+        for home in [&home, &other] {
+            let _ = Command::new("gpgconf")
+                .args(["--kill", "gpg-agent"])
+                .env("GNUPGHOME", home)
+                .output();
+        }
+    }
+
+    fn gpg(home: &Path, args: &[&str]) {
+        let output = Command::new("gpg")
+            .args(args)
+            .env("GNUPGHOME", home)
+            .output()
+            .expect("Failed to run gpg");
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+
+    fn prepare_dir(name: &str) -> &str {
+        let _ = fs::remove_dir_all(name);
+        fs::create_dir_all(name).unwrap();
+        name
+    }
+
+    fn ssh_key(dir: &Path, name: &str) -> PathBuf {
+        let key = dir.join(name);
+        run(
+            dir,
+            "ssh-keygen",
+            &[
+                "-q",
+                "-t",
+                "ed25519",
+                "-N",
+                "",
+                "-C",
+                name,
+                "-f",
+                key.to_str().unwrap(),
+            ],
+        );
+        key
+    }
+
+    fn signing_args<'a>(key: &'a Path, args: &[&'a str]) -> Vec<String> {
+        let mut all = vec![
+            "-c".to_owned(),
+            "gpg.format=ssh".to_owned(),
+            "-c".to_owned(),
+            format!("user.signingkey={}", key.display()),
+            "-c".to_owned(),
+            "user.name=Test Runner".to_owned(),
+            "-c".to_owned(),
+            "user.email=deploy@example.com".to_owned(),
+        ];
+        all.extend(args.iter().map(|arg| arg.to_string()));
+        all
+    }
+
+    fn commit(repo: &Path, key: Option<&Path>) {
+        let args = match key {
+            Some(key) => signing_args(
+                key,
+                &["commit", "-q", "-S", "--allow-empty", "-m", "signed"],
+            ),
+            None => signing_args(
+                Path::new(""),
+                &["commit", "-q", "--allow-empty", "-m", "unsigned"],
+            ),
+        };
+        run(repo, "git", &args);
+    }
+
+    fn run(dir: &Path, program: &str, args: &[impl AsRef<std::ffi::OsStr>]) {
+        let output = Command::new(program)
+            .args(args)
+            .current_dir(dir)
+            .output()
+            .expect("Failed to run command");
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+}
@@ -0,0 +1,536 @@
+use {
+    super::constants::{
+        INVALID_CONFIG_ERROR, MISSING_CONFIG_FILE_ERROR, MISSING_ENDPOINT_ERROR, NESTED_APPS_ERROR,
+        PATH_OUTSIDE_REPOSITORY_ERROR, REPLIT_DEPLOY_CONFIG_FILE_NAMES,
+    },
+    super::types::Config,
+    anyhow::{anyhow, bail, Context, Result},
+    log::debug,
+    regex::Regex,
+    serde_json::{Map, Value},
+    std::{
+        env, fs,
+        path::{Component, Path, PathBuf},
+    },
+};
+
+// Environment variables that override config keys, `true` marking comma
+// separated lists
+const ENV_OVERRIDES: &[(&str, &str, bool)] = &[
+    ("REPL_DEPLOY_ENDPOINT", "endpoint", false),
+    ("REPL_DEPLOY_ENDPOINTS", "endpoints", true),
+    ("REPL_DEPLOY_PUBLIC_KEY", "public_key", false),
+    ("REPL_DEPLOY_GIT_REMOTE", "git.remote", false),
+    ("REPL_DEPLOY_GIT_BRANCH", "git.branch", false),
+    ("REPL_DEPLOY_WATCH_INCLUDE", "watch.include", true),
+    ("REPL_DEPLOY_WATCH_EXCLUDE", "watch.exclude", true),
+];
+
+// Keys whose values are never shown
+const SECRET_KEY_PARTS: &[&str] = &["secret", "token", "password"];
+pub const REDACTED: &str = "<redacted>";
+
+/// Where the config comes from. Layers are merged lowest precedence first:
+/// defaults, the config file, `REPL_DEPLOY_*` environment variables and then
+/// these command line flags.
+#[derive(Default, Clone)]
+pub struct ConfigOptions {
+    /// Unlike the default path, an explicitly passed config file must exist
+    pub path: Option<PathBuf>,
+    pub endpoint: Option<String>,
+    pub public_key: Option<String>,
+    pub remote: Option<String>,
+    pub branch: Option<String>,
+    /// Loads the config of an app from its repository root instead, without
+    /// environment or flag overrides, which are meant for the daemon's own
+    pub app_root: Option<PathBuf>,
+}
+
+/// A config key set by the environment or a flag rather than the file
+pub struct Override {
+    pub key: &'static str,
+    pub value: Value,
+    pub source: String,
+}
+
+pub struct LoadedConfig {
+    pub config: Config,
+    pub path: PathBuf,
+    pub file_found: bool,
+    pub overrides: Vec<Override>,
+}
+
+impl LoadedConfig {
+    /// The config file path, noting when it doesn't exist
+    pub fn describe_path(&self) -> String {
+        if self.file_found {
+            self.path.display().to_string()
+        } else {
+            format!("{} (not found)", self.path.display())
+        }
+    }
+}
+
+pub fn load_config(options: &ConfigOptions) -> Result<Config> {
+    load_layered(options, env::vars()).map(|loaded| loaded.config)
+}
+
+pub fn load_layered(
+    options: &ConfigOptions,
+    vars: impl IntoIterator<Item = (String, String)>,
+) -> Result<LoadedConfig> {
+    let path = match &options.app_root {
+        Some(root) => default_config_path(root),
+        None => options
+            .path
+            .clone()
+            .unwrap_or_else(|| default_config_path(Path::new("."))),
+    };
+
+    let (mut merged, file_found) = match fs::read_to_string(&path) {
+        Ok(contents) => {
+            let config = parse_config(&contents, ConfigFormat::from_path(&path))
+                .with_context(|| format!("{} ({})", INVALID_CONFIG_ERROR, path.display()))?;
+            (serde_json::to_value(config)?, true)
+        }
+        Err(e) if options.path.is_some() || options.app_root.is_some() => {
+            return Err(e)
+                .with_context(|| format!("{} ({})", MISSING_CONFIG_FILE_ERROR, path.display()))
+        }
+        Err(_) => (Value::Object(Map::new()), false),
+    };
+
+    let mut overrides = vec![];
+    if options.app_root.is_none() {
+        overrides.extend(env_overrides(vars));
+        overrides.extend(flag_overrides(options));
+    }
+
+    for Override { key, value, source } in &overrides {
+        debug!("Config {} set by {}", key, source);
+        set_key(&mut merged, key, value.clone())?;
+    }
+
+    let config: Config = serde_json::from_value(merged).with_context(|| INVALID_CONFIG_ERROR)?;
+
+    if options.app_root.is_some() && !config.apps.is_empty() {
+        bail!("{} ({})", NESTED_APPS_ERROR, path.display());
+    }
+
+    // Deploys copy, move and read these, so they mustn't reach out of the repository
+    let repository_paths = config.git.preserve.iter().map(|p| ("git.preserve", p));
+    for (key, value) in repository_paths.chain(config.env_file.iter().map(|p| ("env_file", p))) {
+        if !is_inside_repository(value) {
+            bail!("{} ({}: {})", PATH_OUTSIDE_REPOSITORY_ERROR, key, value);
+        }
+    }
+
+    if config.accepted_endpoints().next().is_none() && config.apps.is_empty() {
+        if file_found {
+            bail!("{} ({})", MISSING_ENDPOINT_ERROR, path.display());
+        }
+        bail!("{} ({})", MISSING_CONFIG_FILE_ERROR, path.display());
+    }
+
+    Ok(LoadedConfig {
+        config,
+        path,
+        file_found,
+        overrides,
+    })
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ConfigFormat {
+    Json,
+    Toml,
+    Yaml,
+}
+
+impl ConfigFormat {
+    /// Picks the format from the extension, falling back to JSON
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("toml") => ConfigFormat::Toml,
+            Some("yaml") | Some("yml") => ConfigFormat::Yaml,
+            _ => ConfigFormat::Json,
+        }
+    }
+}
+
+/// The first of replit-deploy.json, .toml, .yaml and .yml in `root` that exists
+fn default_config_path(root: &Path) -> PathBuf {
+    REPLIT_DEPLOY_CONFIG_FILE_NAMES
+        .iter()
+        .map(|name| root.join(name))
+        .find(|path| path.exists())
+        .unwrap_or_else(|| root.join(REPLIT_DEPLOY_CONFIG_FILE_NAMES[0]))
+}
+
+/// Parses a config file, keeping the parser's line and column in errors and
+/// suggesting the closest known key for unknown ones
+pub fn parse_config(contents: &str, format: ConfigFormat) -> Result<Config> {
+    let parsed = match format {
+        ConfigFormat::Json => serde_json::from_str(contents).map_err(|e| e.to_string()),
+        ConfigFormat::Toml => toml::from_str(contents).map_err(|e| e.to_string()),
+        ConfigFormat::Yaml => serde_yaml::from_str(contents).map_err(|e| e.to_string()),
+    };
+
+    parsed.map_err(|message| anyhow!(with_suggestion(message.trim_end())))
+}
+
+pub fn config_schema() -> String {
+    serde_json::to_string_pretty(&schemars::schema_for!(Config)).unwrap()
+}
+
+fn with_suggestion(message: &str) -> String {
+    let unknown_field = Regex::new(r"unknown field `([^`]*)`, expected (.*)").unwrap();
+    let captures = match unknown_field.captures(message) {
+        Some(captures) => captures,
+        None => return message.to_owned(),
+    };
+
+    let unknown = &captures[1];
+    let suggestion = Regex::new(r"`([^`]*)`")
+        .unwrap()
+        .captures_iter(&captures[2])
+        .map(|expected| expected[1].to_owned())
+        .map(|expected| (edit_distance(unknown, &expected), expected))
+        .filter(|(distance, _)| *distance <= (unknown.len() / 3).max(1))
+        .min();
+
+    match suggestion {
+        Some((_, expected)) => format!("{}, did you mean `{}`?", message, expected),
+        None => message.to_owned(),
+    }
+}
+
+/// Levenshtein distance, good enough to catch typos in key names
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+
+    for (i, a_char) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a_char != *b_char);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+
+    previous[b.len()]
+}
+
+fn env_overrides(vars: impl IntoIterator<Item = (String, String)>) -> Vec<Override> {
+    let mut vars: Vec<_> = vars
+        .into_iter()
+        .filter_map(|(name, value)| {
+            let &(_, key, is_list) = ENV_OVERRIDES.iter().find(|(n, ..)| *n == name)?;
+            let value = if is_list {
+                Value::from(
+                    value
+                        .split(',')
+                        .map(str::trim)
+                        .filter(|item| !item.is_empty())
+                        .collect::<Vec<_>>(),
+                )
+            } else {
+                Value::from(value)
+            };
+
+            Some(Override {
+                key,
+                value,
+                source: name,
+            })
+        })
+        .collect();
+
+    // Keeps the order independent of how the environment happens to be laid out
+    vars.sort_by_key(|o| ENV_OVERRIDES.iter().position(|(_, key, _)| *key == o.key));
+    vars
+}
+
+fn flag_overrides(options: &ConfigOptions) -> Vec<Override> {
+    [
+        ("endpoint", "--endpoint", &options.endpoint),
+        ("public_key", "--public-key", &options.public_key),
+        ("git.remote", "--remote", &options.remote),
+        ("git.branch", "--branch", &options.branch),
+    ]
+    .iter()
+    .filter_map(|&(key, flag, value)| {
+        Some(Override {
+            key,
+            value: Value::from(value.clone()?),
+            source: flag.to_owned(),
+        })
+    })
+    .collect()
+}
+
+/// Whether the value at a dotted key is hidden when configs are shown or
+/// diffed: names that look secret, and anything in an `env` table, which is
+/// there to hold secrets such as `DATABASE_URL`
+pub fn is_secret(key: &str) -> bool {
+    let key = key.to_lowercase();
+    let in_env = key
+        .rsplit_once('.')
+        .is_some_and(|(parents, _)| parents.split('.').any(|part| part == "env"));
+
+    in_env || SECRET_KEY_PARTS.iter().any(|part| key.contains(part))
+}
+
+/// Whether a path is relative and has no `..` parts, so that joining it onto the
+/// repository root stays inside it
+fn is_inside_repository(path: &str) -> bool {
+    Path::new(path)
+        .components()
+        .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+}
+
+/// Sets a dotted key such as `git.branch`, creating tables along the way
+fn set_key(root: &mut Value, key: &str, value: Value) -> Result<()> {
+    let mut parts = key.split('.').peekable();
+    let mut current = root;
+
+    while let Some(part) = parts.next() {
+        let table = match current {
+            Value::Object(table) => table,
+            _ => bail!("{}: {} isn't a table", INVALID_CONFIG_ERROR, key),
+        };
+
+        if parts.peek().is_none() {
+            table.insert(part.to_owned(), value);
+            return Ok(());
+        }
+
+        current = table
+            .entry(part)
+            .or_insert_with(|| Value::Object(Map::new()));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn later_layers_take_precedence() {
+        let path = write_config(
+            "test_repo_config_layers",
+            r#"{"endpoint":"https://file/refresh","git":{"remote":"upstream","branch":"main"}}"#,
+        );
+
+        let loaded = load_layered(
+            &ConfigOptions {
+                path: Some(path),
+                branch: Some("production".to_owned()),
+                ..Default::default()
+            },
+            vec![
+                ("REPL_DEPLOY_GIT_BRANCH".to_owned(), "staging".to_owned()),
+                (
+                    "REPL_DEPLOY_ENDPOINT".to_owned(),
+                    "https://env/refresh".to_owned(),
+                ),
+                (
+                    "REPL_DEPLOY_WATCH_EXCLUDE".to_owned(),
+                    "docs/**, *.md".to_owned(),
+                ),
+                ("UNRELATED".to_owned(), "value".to_owned()),
+            ],
+        )
+        .unwrap();
+
+        let config = loaded.config;
+        assert_eq!(config.endpoint, "https://env/refresh");
+        assert_eq!(config.git.remote, "upstream");
+        assert_eq!(config.git.branch, "production");
+        assert_eq!(config.watch.exclude, vec!["docs/**", "*.md"]);
+
+        let sources: Vec<_> = loaded.overrides.iter().map(|o| o.source.as_str()).collect();
+        assert_eq!(
+            sources,
+            vec![
+                "REPL_DEPLOY_ENDPOINT",
+                "REPL_DEPLOY_GIT_BRANCH",
+                "REPL_DEPLOY_WATCH_EXCLUDE",
+                "--branch"
+            ]
+        );
+    }
+
+    #[test]
+    fn config_file_is_optional_with_endpoint_override() {
+        let missing = Path::new("test_repo_config_missing/replit-deploy.json");
+
+        let options = ConfigOptions {
+            path: Some(missing.to_owned()),
+            endpoint: Some("https://flag/refresh".to_owned()),
+            ..Default::default()
+        };
+        assert!(load_layered(&options, vec![]).is_err());
+
+        let loaded = load_layered(
+            &ConfigOptions {
+                path: None,
+                ..options.clone()
+            },
+            vec![],
+        );
+        // The default path only has to exist if nothing else sets the endpoint,
+        // and the tests don't run next to a replit-deploy.json
+        let loaded = loaded.unwrap();
+        assert!(!loaded.file_found);
+        assert_eq!(loaded.config.endpoint, "https://flag/refresh");
+        assert_eq!(loaded.config.git.branch, "main");
+
+        assert!(load_layered(&ConfigOptions::default(), vec![]).is_err());
+    }
+
+    #[test]
+    fn endpoints_alone_are_enough() {
+        let path = write_config(
+            "test_repo_config_endpoints",
+            r#"{"endpoints":["https://custom/refresh"]}"#,
+        );
+        let options = ConfigOptions {
+            path: Some(path.clone()),
+            ..Default::default()
+        };
+        let loaded = load_layered(&options, vec![]).unwrap();
+        assert_eq!(
+            loaded.config.accepted_endpoints().collect::<Vec<_>>(),
+            vec!["https://custom/refresh"]
+        );
+
+        fs::write(&path, r#"{"endpoint":"","endpoints":[""]}"#).unwrap();
+        assert!(load_layered(&options, vec![]).is_err());
+    }
+
+    #[test]
+    fn app_configs_ignore_overrides() {
+        write_config(
+            "test_repo_config_app",
+            r#"{"endpoint":"https://app/refresh"}"#,
+        );
+        let options = ConfigOptions {
+            app_root: Some(PathBuf::from("test_repo_config_app")),
+            endpoint: Some("https://flag/refresh".to_owned()),
+            ..Default::default()
+        };
+
+        let vars = vec![(
+            "REPL_DEPLOY_ENDPOINT".to_owned(),
+            "https://env/refresh".to_owned(),
+        )];
+        let loaded = load_layered(&options, vars).unwrap();
+        assert_eq!(loaded.config.endpoint, "https://app/refresh");
+        assert!(loaded.overrides.is_empty());
+
+        write_config(
+            "test_repo_config_app",
+            r#"{"apps":[{"name":"nested","path":"nested"}]}"#,
+        );
+        assert!(load_layered(&options, vec![]).is_err());
+    }
+
+    #[test]
+    fn rejects_paths_outside_the_repository() {
+        let path = write_config(
+            "test_repo_config_paths",
+            r#"{"endpoint":"https://app/refresh","env_file":"./.env","git":{"preserve":["data/app.db"]}}"#,
+        );
+        let options = ConfigOptions {
+            path: Some(path.clone()),
+            ..Default::default()
+        };
+        assert!(load_layered(&options, vec![]).is_ok());
+
+        for config in [
+            r#"{"endpoint":"https://app/refresh","git":{"preserve":["/etc/passwd"]}}"#,
+            r#"{"endpoint":"https://app/refresh","git":{"preserve":["data/../../secrets"]}}"#,
+            r#"{"endpoint":"https://app/refresh","env_file":"../shared/.env"}"#,
+            r#"{"endpoint":"https://app/refresh","env_file":"/run/secrets/.env"}"#,
+        ] {
+            fs::write(&path, config).unwrap();
+            let e = load_layered(&options, vec![]).err().unwrap();
+            assert!(
+                e.to_string().starts_with(PATH_OUTSIDE_REPOSITORY_ERROR),
+                "{}",
+                config
+            );
+        }
+    }
+
+    #[test]
+    fn parses_all_formats() {
+        let json = r#"{"endpoint":"https://app/refresh","git":{"branch":"prod"}}"#;
+        let toml = "endpoint = \"https://app/refresh\"\n[git]\nbranch = \"prod\"\n";
+        let yaml = "endpoint: https://app/refresh\ngit:\n  branch: prod\n";
+
+        for (contents, format) in [
+            (json, ConfigFormat::Json),
+            (toml, ConfigFormat::Toml),
+            (yaml, ConfigFormat::Yaml),
+        ] {
+            let config = parse_config(contents, format).unwrap();
+            assert_eq!(config.endpoint, "https://app/refresh");
+            assert_eq!(config.git.branch, "prod");
+            assert_eq!(config.git.remote, "origin");
+        }
+
+        assert_eq!(
+            ConfigFormat::from_path(Path::new("replit-deploy.yml")),
+            ConfigFormat::Yaml
+        );
+    }
+
+    #[test]
+    fn suggests_fixes_for_unknown_keys() {
+        let error = parse_config(
+            "{\n  \"endpont\": \"https://app/refresh\"\n}",
+            ConfigFormat::Json,
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(error.contains("line 2"), "{}", error);
+        assert!(error.ends_with("did you mean `endpoint`?"), "{}", error);
+
+        let error = parse_config(
+            "endpoint = \"x\"\n[git]\nbrnach = \"main\"\n",
+            ConfigFormat::Toml,
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(error.contains("line 3"), "{}", error);
+        assert!(error.ends_with("did you mean `branch`?"), "{}", error);
+
+        let error = parse_config("endpoint: x\nsomething: else\n", ConfigFormat::Yaml)
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("unknown field `something`"), "{}", error);
+        assert!(!error.contains("did you mean"), "{}", error);
+    }
+
+    #[test]
+    fn published_schema_is_up_to_date() {
+        let published = fs::read_to_string("../replit-deploy.schema.json").unwrap_or_default();
+        assert!(
+            published.trim_end() == config_schema(),
+            "Run `repl.deploy config schema > replit-deploy.schema.json` in the repository root"
+        );
+    }
+
+    fn write_config(dir: &str, contents: &str) -> PathBuf {
+        let _ = fs::remove_dir_all(dir);
+        fs::create_dir_all(dir).unwrap();
+        let path = Path::new(dir).join("replit-deploy.json");
+        fs::write(&path, contents).unwrap();
+        path
+    }
+}
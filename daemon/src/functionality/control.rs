@@ -0,0 +1,208 @@
+use {
+    super::constants::{
+        CONTROL_SOCKET_ERROR, CONTROL_SOCKET_FILE_NAME, DAEMON_UNREACHABLE_ERROR, STAT_APPROVED,
+        STAT_FROZEN, STAT_UNFROZEN, UNKNOWN_CONTROL_ACTION_ERROR,
+    },
+    super::deploy_approval::{Approvals, PendingDeploy},
+    super::deploy_history,
+    super::deploy_schedule,
+    super::git_updater,
+    super::shared_config::SharedConfig,
+    super::types::{Approval, Config},
+    anyhow::{anyhow, Context, Result},
+    chrono::Utc,
+    log::{error, info},
+    serde::{Deserialize, Serialize},
+    std::{
+        fs,
+        io::{Read, Write},
+        os::unix::{fs::PermissionsExt, net::UnixStream},
+        path::{Path, PathBuf},
+    },
+    tokio::{
+        io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+        net::UnixListener,
+    },
+};
+
+// Starts replies to commands that failed on the control socket
+const ERROR_PREFIX: &str = "error: ";
+
+/// What the control socket and the control HTTP endpoints do
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Status,
+    Freeze(String),
+    Unfreeze,
+    Approve(String),
+}
+
+impl Command {
+    /// `action` as in /control/<action>, with the request body as `arg`
+    pub fn parse(action: &str, arg: &str) -> Option<Self> {
+        let arg = arg.trim().to_owned();
+
+        match action {
+            "status" => Some(Command::Status),
+            "freeze" => Some(Command::Freeze(arg)),
+            "unfreeze" => Some(Command::Unfreeze),
+            "approve" => Some(Command::Approve(arg)),
+            _ => None,
+        }
+    }
+}
+
+/// Answer to `Command::Status`, as JSON
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Status {
+    pub approval: Approval,
+    /// Why deploys are held by the schedule, if they are
+    pub hold: Option<String>,
+    pub pending: Option<PendingDeploy>,
+}
+
+/// Runs `command` for the repository at `root`. Failures that aren't the
+/// daemon's fault are `StatusReply` errors.
+pub fn run(
+    root: &Path,
+    config: &Config,
+    approvals: &Approvals,
+    command: Command,
+) -> Result<String> {
+    let message = match command {
+        Command::Status => {
+            let status = Status {
+                approval: config.approval,
+                hold: deploy_schedule::hold(root, config.schedule.as_ref(), Utc::now())?
+                    .map(|hold| hold.to_string()),
+                pending: approvals.pending(deploy_history::now()),
+            };
+            return Ok(serde_json::to_string_pretty(&status)?);
+        }
+        Command::Freeze(reason) => deploy_schedule::freeze(root, &reason).map(|()| STAT_FROZEN)?,
+        Command::Unfreeze => deploy_schedule::unfreeze(root).map(|_| STAT_UNFROZEN)?,
+        Command::Approve(id) => approvals
+            .approve(&id, deploy_history::now())
+            .map(|_| STAT_APPROVED)?,
+    };
+
+    info!("{}", message);
+    Ok(message.to_owned())
+}
+
+/// Serves commands to the owner of the repository at `root` on a Unix socket
+/// in its git directory, one line such as `approve 1a2b3c4d` per connection
+pub async fn listen(root: PathBuf, config: SharedConfig, approvals: Approvals) -> Result<()> {
+    let path = socket_path(&root)?;
+    // Left over from a daemon that didn't exit cleanly
+    let _ = fs::remove_file(&path);
+    let listener = UnixListener::bind(&path)
+        .and_then(|listener| {
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).map(|()| listener)
+        })
+        .with_context(|| format!("{} ({})", CONTROL_SOCKET_ERROR, path.display()))?;
+
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                error!("{}: {}", CONTROL_SOCKET_ERROR, e);
+                continue;
+            }
+        };
+
+        let (root, config, approvals) = (root.clone(), config.clone(), approvals.clone());
+        tokio::spawn(async move {
+            let (read, mut write) = stream.into_split();
+            let mut line = String::new();
+            if BufReader::new(read).read_line(&mut line).await.is_err() {
+                return;
+            }
+
+            let (action, arg) = line.trim().split_once(' ').unwrap_or((line.trim(), ""));
+            let reply = match Command::parse(action, arg) {
+                Some(command) => run(&root, &config.get(), &approvals, command)
+                    .unwrap_or_else(|e| format!("{}{:#}", ERROR_PREFIX, e)),
+                None => format!("{}{}", ERROR_PREFIX, UNKNOWN_CONTROL_ACTION_ERROR),
+            };
+            let _ = write.write_all(reply.as_bytes()).await;
+        });
+    }
+}
+
+/// Sends a command to the daemon deploying the repository at `root`,
+/// returning its reply
+pub fn send(root: &Path, action: &str, arg: &str) -> Result<String> {
+    let mut stream =
+        UnixStream::connect(socket_path(root)?).with_context(|| DAEMON_UNREACHABLE_ERROR)?;
+    writeln!(stream, "{} {}", action, arg.trim())?;
+
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply)?;
+    match reply.strip_prefix(ERROR_PREFIX) {
+        Some(e) => Err(anyhow!(e.to_owned())),
+        None => Ok(reply),
+    }
+}
+
+fn socket_path(root: &Path) -> Result<PathBuf> {
+    Ok(git_updater::git_dir(Some(root))?.join(CONTROL_SOCKET_FILE_NAME))
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, std::process::Command as Process, tokio::task};
+
+    #[tokio::test]
+    async fn answers_on_the_socket() {
+        let root = PathBuf::from("test_repo_socket");
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        Process::new("git")
+            .arg("init")
+            .current_dir(&root)
+            .output()
+            .expect("Failed to create repo");
+
+        let (approvals, mut approved) = Approvals::new(root.clone());
+        let (pending, _) = approvals.request(60, deploy_history::now());
+        let config = SharedConfig::new(Default::default(), Default::default());
+        tokio::spawn(listen(root.clone(), config, approvals));
+
+        let send = |action: &'static str, arg: String| {
+            let root = root.clone();
+            async move {
+                // Waits for the listener to bind
+                for _ in 0..50 {
+                    if socket_path(&root).unwrap().exists() {
+                        break;
+                    }
+                    tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+                }
+                task::spawn_blocking(move || send(&root, action, &arg))
+                    .await
+                    .unwrap()
+            }
+        };
+
+        let status: Status = serde_json::from_str(&send("status", "".to_owned()).await.unwrap())
+            .expect("Status isn't JSON");
+        assert_eq!(status.pending.as_ref(), Some(&pending));
+        assert!(send("approve", "deadbeef".to_owned()).await.is_err());
+        assert!(send("thaw", "".to_owned()).await.is_err());
+
+        assert_eq!(
+            send("approve", pending.id.clone()).await.unwrap(),
+            STAT_APPROVED
+        );
+        assert_eq!(approved.recv().await, Some(pending));
+        assert_eq!(
+            send("freeze", "incident #12".to_owned()).await.unwrap(),
+            STAT_FROZEN
+        );
+        assert_eq!(
+            deploy_schedule::frozen(&root).unwrap().as_deref(),
+            Some("incident #12")
+        );
+    }
+}
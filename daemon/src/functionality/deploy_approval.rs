@@ -0,0 +1,176 @@
+use {
+    super::constants::{APPROVALS_STOPPED_ERROR, APPROVAL_EXPIRED_ERROR, UNKNOWN_APPROVAL_ERROR},
+    super::deploy_history::{self, DeployRecord, Outcome},
+    super::types::StatusReply,
+    anyhow::{anyhow, bail, Result},
+    rand::Rng,
+    serde::{Deserialize, Serialize},
+    std::{
+        path::PathBuf,
+        sync::{Arc, Mutex, MutexGuard},
+    },
+    tokio::sync::mpsc,
+    warp::http::StatusCode,
+};
+
+/// A deploy request waiting for `repl.deploy approve <id>`
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PendingDeploy {
+    pub id: String,
+    /// Unix seconds
+    pub requested_at: u64,
+    pub expires_at: u64,
+}
+
+#[derive(Default)]
+struct State {
+    pending: Option<PendingDeploy>,
+    /// Id of the last deploy that expired, to tell approving it apart from a
+    /// typo
+    expired: Option<String>,
+}
+
+/// The pending deploy of an app. There's at most one, as requests made while
+/// one is pending join it, and approving it deploys whatever is latest then.
+/// Kept in memory only, so restarting the daemon drops it.
+#[derive(Clone)]
+pub struct Approvals {
+    root: PathBuf,
+    state: Arc<Mutex<State>>,
+    approved: mpsc::UnboundedSender<PendingDeploy>,
+}
+
+impl Approvals {
+    /// Approved deploys are sent to the returned receiver
+    pub fn new(root: PathBuf) -> (Self, mpsc::UnboundedReceiver<PendingDeploy>) {
+        let (approved, receiver) = mpsc::unbounded_channel();
+        let approvals = Approvals {
+            root,
+            state: Default::default(),
+            approved,
+        };
+
+        (approvals, receiver)
+    }
+
+    /// The pending deploy a request joins, created if there's none, and whether
+    /// it was created
+    pub fn request(&self, expiry_secs: u64, now: u64) -> (PendingDeploy, bool) {
+        let mut state = self.lock(now);
+        if let Some(pending) = &state.pending {
+            return (pending.clone(), false);
+        }
+
+        let created = PendingDeploy {
+            id: format!("{:08x}", rand::thread_rng().gen::<u32>()),
+            requested_at: now,
+            expires_at: now.saturating_add(expiry_secs),
+        };
+        deploy_history::record(
+            &self.root,
+            DeployRecord::held(Outcome::Pending, &created.id),
+        );
+        state.pending = Some(created.clone());
+        (created, true)
+    }
+
+    pub fn pending(&self, now: u64) -> Option<PendingDeploy> {
+        self.lock(now).pending.clone()
+    }
+
+    /// Fails with a `StatusReply` when no deploy with `id` is pending
+    pub fn approve(&self, id: &str, now: u64) -> Result<PendingDeploy> {
+        let mut state = self.lock(now);
+        let approved = match state.pending.take_if(|pending| pending.id == id) {
+            Some(approved) => approved,
+            None if state.expired.as_deref() == Some(id) => bail!(StatusReply {
+                status: StatusCode::GONE,
+                message: APPROVAL_EXPIRED_ERROR.to_owned(),
+            }),
+            None => bail!(StatusReply {
+                status: StatusCode::NOT_FOUND,
+                message: format!("{} ({})", UNKNOWN_APPROVAL_ERROR, id),
+            }),
+        };
+
+        deploy_history::record(&self.root, DeployRecord::held(Outcome::Approved, id));
+        self.approved
+            .send(approved.clone())
+            .map_err(|_| anyhow!(APPROVALS_STOPPED_ERROR))?;
+        Ok(approved)
+    }
+
+    /// Locks the pending deploy, dropping it first if it expired by `now`
+    fn lock(&self, now: u64) -> MutexGuard<'_, State> {
+        let mut state = match self.state.lock() {
+            Ok(state) => state,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+
+        if let Some(expired) = state.pending.take_if(|pending| pending.expires_at <= now) {
+            deploy_history::record(
+                &self.root,
+                DeployRecord::held(Outcome::Expired, &expired.id),
+            );
+            state.expired = Some(expired.id);
+        }
+
+        state
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, std::fs, std::process::Command};
+
+    #[test]
+    fn expires_pending_deploys() {
+        let root = PathBuf::from("test_repo_approval");
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        Command::new("git")
+            .arg("init")
+            .current_dir(&root)
+            .output()
+            .expect("Failed to create repo");
+
+        let (approvals, mut approved) = Approvals::new(root.clone());
+        let (pending, created) = approvals.request(60, 1000);
+        assert!(created);
+        assert_eq!(pending.expires_at, 1060);
+        // Later requests join the pending deploy
+        assert_eq!(approvals.request(60, 1030), (pending.clone(), false));
+
+        assert!(approvals.approve("deadbeef", 1030).is_err());
+        assert_eq!(approvals.approve(&pending.id, 1030).unwrap(), pending);
+        assert_eq!(approved.try_recv().unwrap(), pending);
+        assert_eq!(approvals.pending(1030), None);
+
+        let (stale, _) = approvals.request(60, 2000);
+        assert_eq!(approvals.pending(2059), Some(stale.clone()));
+        let e = approvals.approve(&stale.id, 2060).unwrap_err();
+        assert_eq!(
+            e.downcast::<StatusReply>().unwrap().status,
+            StatusCode::GONE
+        );
+        assert!(approved.try_recv().is_err());
+        let (forever, _) = approvals.request(u64::MAX, 3000);
+        assert_eq!(forever.expires_at, u64::MAX);
+
+        let outcomes: Vec<_> = deploy_history::read(&root)
+            .unwrap()
+            .into_iter()
+            .map(|record| record.outcome)
+            .collect();
+        assert_eq!(
+            outcomes,
+            vec![
+                Outcome::Pending,
+                Outcome::Approved,
+                Outcome::Pending,
+                Outcome::Expired,
+                Outcome::Pending
+            ]
+        );
+    }
+}
@@ -0,0 +1,238 @@
+use {
+    super::constants::{DEPLOY_HISTORY_FILE_NAME, DEPLOY_HISTORY_WRITE_FAILED_WARN},
+    super::git_updater::{self, Update},
+    anyhow::{Context, Result},
+    log::{error, warn},
+    serde::{Deserialize, Serialize},
+    std::{
+        fs,
+        path::Path,
+        sync::Mutex,
+        time::{SystemTime, UNIX_EPOCH},
+    },
+};
+
+// Older entries are dropped
+const MAX_ENTRIES: usize = 100;
+
+// Held while rewriting a history, so concurrent records aren't lost
+static WRITES: Mutex<()> = Mutex::new(());
+
+/// One line of the deploy history, kept in the git directory so deploys don't
+/// reset it
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DeployRecord {
+    /// Unix seconds
+    pub time: u64,
+    pub outcome: Outcome,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub commit: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fetch_ms: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fetch_bytes: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Outcome {
+    Deployed,
+    Failed,
+    /// Nothing relevant changed, so processes weren't restarted
+    Skipped,
+    /// Held until deploys are allowed again
+    Queued,
+    /// Refused because deploys weren't allowed
+    Rejected,
+    /// Waiting for `repl.deploy approve`
+    Pending,
+    Approved,
+    /// Wasn't approved in time
+    Expired,
+}
+
+impl Outcome {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Outcome::Deployed => "deployed",
+            Outcome::Failed => "failed",
+            Outcome::Skipped => "skipped",
+            Outcome::Queued => "queued",
+            Outcome::Rejected => "rejected",
+            Outcome::Pending => "pending",
+            Outcome::Approved => "approved",
+            Outcome::Expired => "expired",
+        }
+    }
+}
+
+impl DeployRecord {
+    /// A deploy that fetched `update`, if it got that far, and failed with
+    /// `error`, if it did
+    pub fn new(update: Option<&Update>, error: Option<&anyhow::Error>) -> Self {
+        DeployRecord {
+            time: now(),
+            outcome: match error {
+                Some(_) => Outcome::Failed,
+                None => Outcome::Deployed,
+            },
+            commit: update.map(|update| update.commit.clone()),
+            fetch_ms: update.map(|update| update.fetch.duration.as_millis() as u64),
+            fetch_bytes: update.map(|update| update.fetch.bytes),
+            error: error.map(|e| format!("{:#}", e)),
+        }
+    }
+
+    /// A deploy request that wasn't run right away, and why or which pending
+    /// deploy it is
+    pub fn held(outcome: Outcome, reason: &str) -> Self {
+        DeployRecord {
+            time: now(),
+            outcome,
+            commit: None,
+            fetch_ms: None,
+            fetch_bytes: None,
+            error: Some(reason.to_owned()),
+        }
+    }
+
+    /// A deploy of `update` that didn't restart anything
+    pub fn skipped(update: &Update) -> Self {
+        DeployRecord {
+            outcome: Outcome::Skipped,
+            ..DeployRecord::new(Some(update), None)
+        }
+    }
+}
+
+/// Appends to the history of the repository at `root`, only logging failures
+/// so that they never fail a deploy
+pub fn record(root: &Path, record: DeployRecord) {
+    if let Err(e) = append(root, record) {
+        error!("{:#}", e);
+        warn!("{}", DEPLOY_HISTORY_WRITE_FAILED_WARN);
+    }
+}
+
+fn append(root: &Path, record: DeployRecord) -> Result<()> {
+    let path = git_updater::git_dir(Some(root))?.join(DEPLOY_HISTORY_FILE_NAME);
+    let _writing = match WRITES.lock() {
+        Ok(writing) => writing,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    let mut records = read_file(&path)?;
+    records.push(record);
+
+    let start = records.len().saturating_sub(MAX_ENTRIES);
+    let mut contents = String::new();
+    for record in &records[start..] {
+        contents.push_str(&serde_json::to_string(record)?);
+        contents.push('\n');
+    }
+
+    fs::write(&path, contents).with_context(|| format!("{}", path.display()))
+}
+
+/// Deploys of the repository at `root`, oldest first
+pub fn read(root: &Path) -> Result<Vec<DeployRecord>> {
+    read_file(&git_updater::git_dir(Some(root))?.join(DEPLOY_HISTORY_FILE_NAME))
+}
+
+/// Skips lines it can't parse, such as ones from a newer version
+fn read_file(path: &Path) -> Result<Vec<DeployRecord>> {
+    match fs::read_to_string(path) {
+        Ok(contents) => Ok(contents
+            .lines()
+            .filter_map(|line| serde_json::from_str(line).ok())
+            .collect()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(vec![]),
+        Err(e) => Err(e).with_context(|| format!("{}", path.display())),
+    }
+}
+
+/// Unix seconds, as in the history
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::git_updater::FetchStats,
+        anyhow::anyhow,
+        std::{process::Command, time::Duration},
+    };
+
+    #[test]
+    fn keeps_recent_deploys() {
+        let root = Path::new("test_repo_history");
+        let _ = fs::remove_dir_all(root);
+        fs::create_dir_all(root).unwrap();
+        Command::new("git")
+            .arg("init")
+            .current_dir(root)
+            .output()
+            .expect("Failed to create repo");
+
+        let update = Update {
+            commit: "abc".to_owned(),
+            changed: None,
+            relevant: true,
+            seen: false,
+            reset: true,
+            fetch: FetchStats {
+                duration: Duration::from_millis(1500),
+                bytes: 2048,
+            },
+        };
+        for _ in 0..MAX_ENTRIES {
+            record(root, DeployRecord::new(Some(&update), None));
+        }
+        record(
+            root,
+            DeployRecord::new(None, Some(&anyhow!("fetch failed"))),
+        );
+
+        let records = read(root).unwrap();
+        assert_eq!(records.len(), MAX_ENTRIES);
+        assert_eq!(records[0].fetch_ms, Some(1500));
+        assert_eq!(records[0].fetch_bytes, Some(2048));
+
+        let last = records.last().unwrap();
+        assert_eq!(last.outcome, Outcome::Failed);
+        assert_eq!(last.error.as_deref(), Some("fetch failed"));
+        assert_eq!(last.commit, None);
+    }
+
+    #[test]
+    fn concurrent_records_are_kept() {
+        let root = Path::new("test_repo_history_concurrent");
+        let _ = fs::remove_dir_all(root);
+        fs::create_dir_all(root).unwrap();
+        Command::new("git")
+            .arg("init")
+            .current_dir(root)
+            .output()
+            .expect("Failed to create repo");
+
+        let writers: Vec<_> = (0..8)
+            .map(|_| {
+                std::thread::spawn(move || {
+                    for _ in 0..5 {
+                        record(root, DeployRecord::held(Outcome::Queued, "frozen"));
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+
+        assert_eq!(read(root).unwrap().len(), 40);
+    }
+}
@@ -0,0 +1,173 @@
+use {
+    super::constants::{DEPLOY_QUEUE_STOPPED_ERROR, STAT_DEPLOY_COALESCED},
+    anyhow::{anyhow, Result},
+    log::{debug, info},
+    std::sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    tokio::{
+        sync::{watch, Notify},
+        task,
+    },
+};
+
+/// Runs deploys one at a time on a blocking thread. Requests that arrive while
+/// a deploy is running are coalesced into a single follow-up "deploy latest".
+#[derive(Clone)]
+pub struct DeployQueue {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    requested: AtomicU64,
+    wake_worker: Notify,
+    completed: watch::Receiver<Completed>,
+}
+
+#[derive(Clone)]
+struct Completed {
+    generation: u64,
+    result: Result<(), String>,
+}
+
+impl DeployQueue {
+    /// Must be called from within a tokio runtime.
+    pub fn new(deploy: impl FnMut() -> Result<()> + Send + 'static) -> Self {
+        let (completed_tx, completed_rx) = watch::channel(Completed {
+            generation: 0,
+            result: Ok(()),
+        });
+
+        let inner = Arc::new(Inner {
+            requested: AtomicU64::new(0),
+            wake_worker: Notify::new(),
+            completed: completed_rx,
+        });
+
+        tokio::spawn(worker(
+            inner.clone(),
+            Arc::new(Mutex::new(deploy)),
+            completed_tx,
+        ));
+
+        DeployQueue { inner }
+    }
+
+    /// Queues a deploy and resolves once a deploy that started after this
+    /// call has finished, with that deploy's result.
+    pub async fn request(&self) -> Result<()> {
+        let mut completed = self.inner.completed.clone();
+        let generation = self.inner.requested.fetch_add(1, Ordering::SeqCst) + 1;
+        self.inner.wake_worker.notify_one();
+
+        loop {
+            {
+                let latest = completed.borrow();
+                if latest.generation >= generation {
+                    return latest.result.clone().map_err(|e| anyhow!(e));
+                }
+            }
+
+            if completed.changed().await.is_err() {
+                return Err(anyhow!(DEPLOY_QUEUE_STOPPED_ERROR));
+            }
+        }
+    }
+}
+
+async fn worker<F: FnMut() -> Result<()> + Send + 'static>(
+    inner: Arc<Inner>,
+    deploy: Arc<Mutex<F>>,
+    completed: watch::Sender<Completed>,
+) {
+    let mut last_completed = 0;
+
+    loop {
+        let target = inner.requested.load(Ordering::SeqCst);
+        if target == last_completed {
+            inner.wake_worker.notified().await;
+            continue;
+        }
+
+        // Requests beyond the first one since the last deploy are coalesced
+        if target - last_completed > 1 {
+            info!("{}", STAT_DEPLOY_COALESCED);
+        }
+
+        debug!("Starting deploy for request #{}", target);
+
+        let deploy = deploy.clone();
+        let result = match task::spawn_blocking(move || {
+            // A panicked deploy shouldn't stop every later one from running
+            let mut deploy = deploy.lock().unwrap_or_else(|e| e.into_inner());
+            deploy()
+        })
+        .await
+        {
+            Ok(result) => result.map_err(|e| e.to_string()),
+            Err(e) => Err(e.to_string()),
+        };
+
+        last_completed = target;
+        let _ = completed.send(Completed {
+            generation: target,
+            result,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        anyhow::bail,
+        std::{sync::mpsc, thread, time::Duration},
+    };
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn coalesces_requests_during_deploy() {
+        let runs = Arc::new(AtomicU64::new(0));
+        let (started_tx, started_rx) = mpsc::channel();
+
+        let queue = {
+            let runs = runs.clone();
+            DeployQueue::new(move || {
+                runs.fetch_add(1, Ordering::SeqCst);
+                let _ = started_tx.send(());
+                thread::sleep(Duration::from_millis(200));
+                Ok(())
+            })
+        };
+
+        let first = tokio::spawn({
+            let queue = queue.clone();
+            async move { queue.request().await }
+        });
+
+        task::spawn_blocking(move || started_rx.recv().unwrap())
+            .await
+            .unwrap();
+
+        let queued: Vec<_> = (0..5)
+            .map(|_| {
+                let queue = queue.clone();
+                tokio::spawn(async move { queue.request().await })
+            })
+            .collect();
+
+        first.await.unwrap().expect("First deploy failed");
+        for request in queued {
+            request.await.unwrap().expect("Queued deploy failed");
+        }
+
+        assert_eq!(runs.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn reports_deploy_errors() {
+        let queue = DeployQueue::new(|| bail!("deploy failed"));
+        let result = queue.request().await;
+        assert_eq!(result.unwrap_err().to_string(), "deploy failed");
+    }
+}
@@ -0,0 +1,240 @@
+use {
+    super::constants::{
+        DEPLOYS_FROZEN_ERROR, FREEZE_FAILED_ERROR, FREEZE_FILE_NAME, INVALID_TIMEZONE_ERROR,
+        INVALID_WINDOW_TIME_ERROR, OUTSIDE_DEPLOY_WINDOW_ERROR,
+    },
+    super::git_updater,
+    super::types::{DeployWindow, ScheduleConfig, Weekday},
+    anyhow::{anyhow, Context, Result},
+    chrono::{DateTime, Datelike, Duration, NaiveDate, NaiveDateTime, NaiveTime, TimeZone, Utc},
+    chrono_tz::Tz,
+    std::{
+        fmt, fs, io,
+        path::{Path, PathBuf},
+    },
+};
+
+/// Why a deploy can't happen yet
+#[derive(Debug, PartialEq)]
+pub enum Hold {
+    /// With the reason given when freezing, if any
+    Frozen(String),
+    /// With when the next window opens
+    Closed(Option<DateTime<Utc>>),
+}
+
+impl fmt::Display for Hold {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Hold::Frozen(reason) if reason.is_empty() => write!(f, "{}", DEPLOYS_FROZEN_ERROR),
+            Hold::Frozen(reason) => write!(f, "{}: {}", DEPLOYS_FROZEN_ERROR, reason),
+            Hold::Closed(Some(opens)) => write!(
+                f,
+                "{}, the next one opens at {}",
+                OUTSIDE_DEPLOY_WINDOW_ERROR,
+                opens.format("%Y-%m-%d %H:%M UTC")
+            ),
+            Hold::Closed(None) => write!(f, "{}", OUTSIDE_DEPLOY_WINDOW_ERROR),
+        }
+    }
+}
+
+/// Whether deploys of the repository at `root` have to wait, as of `now`
+pub fn hold(
+    root: &Path,
+    schedule: Option<&ScheduleConfig>,
+    now: DateTime<Utc>,
+) -> Result<Option<Hold>> {
+    if let Some(reason) = frozen(root)? {
+        return Ok(Some(Hold::Frozen(reason)));
+    }
+
+    let schedule = match schedule {
+        Some(schedule) if !schedule.windows.is_empty() => schedule,
+        _ => return Ok(None),
+    };
+
+    let timezone = timezone(schedule)?;
+    let local = now.with_timezone(&timezone).naive_local();
+    for window in &schedule.windows {
+        if is_open(window, local)? {
+            return Ok(None);
+        }
+    }
+
+    Ok(Some(Hold::Closed(next_opening(schedule, timezone, now)?)))
+}
+
+fn is_open(window: &DeployWindow, local: NaiveDateTime) -> Result<bool> {
+    let (start, end) = (parse_time(&window.start)?, parse_time(&window.end)?);
+    let (date, time) = (local.date(), local.time());
+
+    Ok(if start < end {
+        starts_on(window, date) && start <= time && time < end
+    } else if start > end {
+        // Past midnight, so it may have started the day before
+        (starts_on(window, date) && start <= time)
+            || (date.pred_opt().is_some_and(|day| starts_on(window, day)) && time < end)
+    } else {
+        starts_on(window, date)
+    })
+}
+
+/// The start of the first window after `now`, looking a week ahead
+fn next_opening(
+    schedule: &ScheduleConfig,
+    timezone: Tz,
+    now: DateTime<Utc>,
+) -> Result<Option<DateTime<Utc>>> {
+    let today = now.with_timezone(&timezone).date_naive();
+    let mut opening: Option<DateTime<Utc>> = None;
+
+    for days in 0..=7 {
+        let date = today + Duration::days(days);
+        for window in schedule.windows.iter().filter(|w| starts_on(w, date)) {
+            let start = date.and_time(parse_time(&window.start)?);
+            // Skips starts that a DST change jumps over
+            let start = match timezone.from_local_datetime(&start).earliest() {
+                Some(start) => start.with_timezone(&Utc),
+                None => continue,
+            };
+
+            if start > now && opening.is_none_or(|opening| start < opening) {
+                opening = Some(start);
+            }
+        }
+
+        if opening.is_some() {
+            break;
+        }
+    }
+
+    Ok(opening)
+}
+
+fn starts_on(window: &DeployWindow, date: NaiveDate) -> bool {
+    let day = match date.weekday() {
+        chrono::Weekday::Mon => Weekday::Mon,
+        chrono::Weekday::Tue => Weekday::Tue,
+        chrono::Weekday::Wed => Weekday::Wed,
+        chrono::Weekday::Thu => Weekday::Thu,
+        chrono::Weekday::Fri => Weekday::Fri,
+        chrono::Weekday::Sat => Weekday::Sat,
+        chrono::Weekday::Sun => Weekday::Sun,
+    };
+
+    window.days.is_empty() || window.days.contains(&day)
+}
+
+fn timezone(schedule: &ScheduleConfig) -> Result<Tz> {
+    schedule
+        .timezone
+        .parse()
+        .map_err(|_| anyhow!("{}: {}", INVALID_TIMEZONE_ERROR, schedule.timezone))
+}
+
+fn parse_time(time: &str) -> Result<NaiveTime> {
+    NaiveTime::parse_from_str(time, "%H:%M")
+        .with_context(|| format!("{}: {}", INVALID_WINDOW_TIME_ERROR, time))
+}
+
+/// Holds deploys until `unfreeze`, kept in the git directory so that it
+/// outlives restarts of the daemon
+pub fn freeze(root: &Path, reason: &str) -> Result<()> {
+    fs::write(freeze_path(root)?, reason.trim()).with_context(|| FREEZE_FAILED_ERROR)
+}
+
+/// Returns whether deploys were frozen
+pub fn unfreeze(root: &Path) -> Result<bool> {
+    match fs::remove_file(freeze_path(root)?) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e).with_context(|| FREEZE_FAILED_ERROR),
+    }
+}
+
+/// The reason deploys were frozen with, if they are
+pub fn frozen(root: &Path) -> Result<Option<String>> {
+    match fs::read_to_string(freeze_path(root)?) {
+        Ok(reason) => Ok(Some(reason.trim().to_owned())),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).with_context(|| FREEZE_FAILED_ERROR),
+    }
+}
+
+fn freeze_path(root: &Path) -> Result<PathBuf> {
+    Ok(git_updater::git_dir(Some(root))?.join(FREEZE_FILE_NAME))
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, std::process::Command};
+
+    fn at(time: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(time)
+            .unwrap()
+            .with_timezone(&Utc)
+    }
+
+    #[test]
+    fn holds_deploys_outside_windows() {
+        let root = Path::new("test_repo_schedule");
+        let _ = fs::remove_dir_all(root);
+        fs::create_dir_all(root).unwrap();
+        Command::new("git")
+            .arg("init")
+            .current_dir(root)
+            .output()
+            .expect("Failed to create repo");
+
+        let schedule = ScheduleConfig {
+            timezone: "Europe/Berlin".to_owned(),
+            windows: vec![
+                DeployWindow {
+                    days: vec![Weekday::Mon, Weekday::Tue, Weekday::Wed, Weekday::Thu],
+                    start: "09:00".to_owned(),
+                    end: "17:00".to_owned(),
+                },
+                DeployWindow {
+                    days: vec![Weekday::Fri],
+                    start: "22:00".to_owned(),
+                    end: "02:00".to_owned(),
+                },
+            ],
+            ..Default::default()
+        };
+        let hold_at = |time| hold(root, Some(&schedule), at(time)).unwrap();
+
+        // 2024-01-08 is a Monday, Berlin is an hour ahead of UTC in winter
+        assert_eq!(hold_at("2024-01-08T08:30:00Z"), None);
+        assert_eq!(
+            hold_at("2024-01-08T16:00:00Z"),
+            Some(Hold::Closed(Some(at("2024-01-09T08:00:00Z"))))
+        );
+        assert_eq!(
+            hold_at("2024-01-11T18:00:00Z"),
+            Some(Hold::Closed(Some(at("2024-01-12T21:00:00Z"))))
+        );
+        // Friday's window runs into Saturday
+        assert_eq!(hold_at("2024-01-13T00:30:00Z"), None);
+        assert_eq!(
+            hold_at("2024-01-13T01:00:00Z"),
+            Some(Hold::Closed(Some(at("2024-01-15T08:00:00Z"))))
+        );
+
+        freeze(root, "incident #12\n").unwrap();
+        assert_eq!(
+            hold_at("2024-01-08T08:30:00Z"),
+            Some(Hold::Frozen("incident #12".to_owned()))
+        );
+        assert!(unfreeze(root).unwrap());
+        assert!(!unfreeze(root).unwrap());
+        assert_eq!(hold(root, None, at("2024-01-13T01:00:00Z")).unwrap(), None);
+
+        let unknown = ScheduleConfig {
+            timezone: "Mars/Olympus_Mons".to_owned(),
+            ..schedule.clone()
+        };
+        assert!(hold(root, Some(&unknown), at("2024-01-08T08:30:00Z")).is_err());
+    }
+}
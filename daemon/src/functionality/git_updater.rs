@@ -1,64 +1,1156 @@
 use {
-    super::constants::{GIT_FETCH_FAILED_ERROR, GIT_RESET_FAILED_ERROR},
-    anyhow::{bail, Result},
-    std::{path::Path, process::Command},
+    super::commit_verifier,
+    super::constants::{
+        DIFF_FAILED_WARN, GIT_CLEAN_FAILED_ERROR, GIT_FETCH_FAILED_ERROR, GIT_LFS_FAILED_ERROR,
+        GIT_RESET_FAILED_ERROR, GIT_REV_PARSE_FAILED_ERROR, GIT_STATUS_FAILED_ERROR,
+        GIT_SUBMODULE_FAILED_ERROR, INVALID_PATHS_GLOB_ERROR, INVALID_TAG_PATTERN_ERROR,
+        NO_GIT_REMOTE_ERROR, NO_MATCHING_TAG_ERROR, PINNED_COMMIT_MISSING_ERROR,
+        PINNED_COMMIT_NOT_ON_BRANCH_ERROR, PRESERVE_FAILED_ERROR, RESTORE_FAILED_ERROR,
+        SKIPPED_TARGET_ERROR, SKIPPED_TARGET_FILE_NAME, STAT_FETCHED,
+    },
+    super::git_auth::{self, Credentials},
+    super::types::{DeployTarget, GitConfig, PathsConfig},
+    anyhow::{bail, Context, Result},
+    globset::{Glob, GlobSet, GlobSetBuilder},
+    log::{debug, error, info, warn},
+    regex::Regex,
+    semver::Version,
+    std::{
+        env, fs, io,
+        path::{Path, PathBuf},
+        process::{Command, Output},
+        time::{Duration, Instant},
+    },
 };
 
-pub fn update_git_from_remote(cwd: Option<&Path>) -> Result<()> {
-    let git_fetch = match cwd {
-        Some(path) => Command::new("git")
-            .args(&["fetch", "--all"])
-            .current_dir(path)
-            .output(),
-        None => Command::new("git").args(&["fetch", "--all"]).output(),
+/// The steps of a deploy that depend on the git implementation: the command
+/// line one, or libgit2 when built with the `native-git` feature
+pub trait Updater {
+    /// Fetches only the deploy branch (or the tags) from the remote, as deep
+    /// as configured
+    fn fetch(&self, cwd: Option<&Path>, git_config: &GitConfig) -> Result<FetchStats>;
+    /// Resolves a revision to a commit hash, failing if it isn't a commit
+    fn resolve_commit(&self, cwd: Option<&Path>, rev: &str) -> Result<String>;
+    /// Names of the local tags
+    fn tags(&self, cwd: Option<&Path>) -> Result<Vec<String>>;
+    /// Files added, modified or deleted between two commits
+    fn changed_paths(&self, cwd: Option<&Path>, from: &str, to: &str) -> Result<Vec<String>>;
+    /// Whether `ancestor` is `descendant` or in its history
+    fn is_ancestor(&self, cwd: Option<&Path>, ancestor: &str, descendant: &str) -> Result<bool>;
+    /// Resets the index and working tree to the commit, discarding changes
+    fn reset_hard(&self, cwd: Option<&Path>, commit: &str) -> Result<()>;
+}
+
+/// Runs the `git` binary
+pub struct CommandLine;
+
+impl Updater for CommandLine {
+    fn fetch(&self, cwd: Option<&Path>, git_config: &GitConfig) -> Result<FetchStats> {
+        let started = Instant::now();
+        let refspec = fetch_refspec(git_config);
+        let depth = git_config.depth.map(|depth| format!("--depth={}", depth));
+        let filter = git_config
+            .filter
+            .as_ref()
+            .map(|filter| format!("--filter={}", filter));
+
+        // --progress makes git report the size it received even without a tty
+        let mut args = vec!["fetch", "--no-tags", "--progress"];
+        args.extend(depth.iter().chain(&filter).map(String::as_str));
+        args.extend([git_config.remote.as_str(), &refspec]);
+
+        let credentials = credentials(cwd, git_config)?;
+        let output = run_remote_git(
+            cwd,
+            &args,
+            credentials.as_ref(),
+            format!("{} ({})", GIT_FETCH_FAILED_ERROR, git_config.remote_ref()),
+        )?;
+
+        Ok(FetchStats {
+            duration: started.elapsed(),
+            bytes: received_bytes(&String::from_utf8_lossy(&output.stderr)),
+        })
+    }
+
+    fn resolve_commit(&self, cwd: Option<&Path>, rev: &str) -> Result<String> {
+        rev_parse(cwd, &format!("{}^{{commit}}", rev))
+    }
+
+    fn tags(&self, cwd: Option<&Path>) -> Result<Vec<String>> {
+        match run_git(cwd, &["tag", "--list"]) {
+            Some(output) => Ok(stdout(&output).lines().map(str::to_owned).collect()),
+            None => bail!(GIT_REV_PARSE_FAILED_ERROR),
+        }
+    }
+
+    fn changed_paths(&self, cwd: Option<&Path>, from: &str, to: &str) -> Result<Vec<String>> {
+        // Renames are listed as a deletion and an addition, so both paths match
+        match run_git(
+            cwd,
+            &["diff", "--name-only", "--no-renames", "-z", from, to],
+        ) {
+            Some(output) => Ok(stdout(&output)
+                .split('\0')
+                .filter(|path| !path.is_empty())
+                .map(str::to_owned)
+                .collect()),
+            None => bail!("{} ({}..{})", GIT_REV_PARSE_FAILED_ERROR, from, to),
+        }
+    }
+
+    fn is_ancestor(&self, cwd: Option<&Path>, ancestor: &str, descendant: &str) -> Result<bool> {
+        let args = ["merge-base", "--is-ancestor", ancestor, descendant];
+        match git_output(cwd, &args, None)?.status.code() {
+            Some(0) => Ok(true),
+            Some(1) => Ok(false),
+            _ => bail!(
+                "{} ({}..{})",
+                GIT_REV_PARSE_FAILED_ERROR,
+                ancestor,
+                descendant
+            ),
+        }
+    }
+
+    fn reset_hard(&self, cwd: Option<&Path>, commit: &str) -> Result<()> {
+        if run_git(cwd, &["reset", "--hard", commit]).is_none() {
+            bail!("{} ({})", GIT_RESET_FAILED_ERROR, commit);
+        }
+
+        Ok(())
+    }
+}
+
+/// How long a fetch took and how much it transferred
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FetchStats {
+    pub duration: Duration,
+    pub bytes: u64,
+}
+
+/// What a deploy fetched, and whether it was reset to it
+#[derive(Debug)]
+pub struct Update {
+    pub commit: String,
+    pub fetch: FetchStats,
+    /// Files that differ from the commit checked out before, unless that's
+    /// unknown
+    pub changed: Option<Vec<String>>,
+    /// Whether processes need restarting: false when a tag or pinned target
+    /// didn't move, or when none of `git.paths` changed
+    pub relevant: bool,
+    /// Whether `commit` was deployed or skipped before, so nothing is new
+    pub seen: bool,
+    /// Whether the working tree was reset to `commit`, which it isn't for
+    /// irrelevant changes when `git.paths.update_tree` is off
+    pub reset: bool,
+}
+
+/// Updates only the remote-tracking branch of the deploy branch, or the tags
+/// when deploying tags, which may have been moved
+pub fn fetch_refspec(git_config: &GitConfig) -> String {
+    match git_config.target {
+        DeployTarget::TagPattern { .. } => "+refs/tags/*:refs/tags/*".to_owned(),
+        _ => format!(
+            "+refs/heads/{}:refs/remotes/{}",
+            git_config.branch,
+            git_config.remote_ref()
+        ),
+    }
+}
+
+/// The revision deploys are reset to, as of the last fetch
+pub fn target_rev(cwd: Option<&Path>, git_config: &GitConfig) -> Result<String> {
+    match &git_config.target {
+        DeployTarget::Branch => Ok(git_config.remote_ref()),
+        DeployTarget::Pinned { commit } => {
+            // Otherwise a typo or an unpushed commit fails as a bare rev-parse
+            updater()
+                .resolve_commit(cwd, commit)
+                .with_context(|| format!("{} ({})", PINNED_COMMIT_MISSING_ERROR, commit))?;
+            // Commits fetched from other branches or pull requests aren't deployed
+            let branch = git_config.remote_ref();
+            if !updater().is_ancestor(cwd, commit, &branch)? {
+                bail!(
+                    "{} ({}, {})",
+                    PINNED_COMMIT_NOT_ON_BRANCH_ERROR,
+                    commit,
+                    branch
+                );
+            }
+            Ok(commit.clone())
+        }
+        DeployTarget::TagPattern { pattern } => {
+            let tags = updater().tags(cwd)?;
+            let tag = latest_tag(&tags, pattern)?
+                .with_context(|| format!("{} ({})", NO_MATCHING_TAG_ERROR, pattern))?;
+            Ok(format!("refs/tags/{}", tag))
+        }
+    }
+}
+
+/// The tag matching the pattern with the highest version. Prereleases only
+/// count when the pattern has a `-` after its first wildcard or digit, such as
+/// `v*-*` or `v2.0.0-rc*`.
+fn latest_tag<'a>(tags: &'a [String], pattern: &str) -> Result<Option<&'a String>> {
+    let matcher = Glob::new(pattern)
+        .with_context(|| format!("{} ({})", INVALID_TAG_PATTERN_ERROR, pattern))?
+        .compile_matcher();
+    let prereleases = pattern
+        .find(|c: char| c.is_ascii_digit() || "*?[{".contains(c))
+        .is_some_and(|start| pattern[start..].contains('-'));
+
+    Ok(tags
+        .iter()
+        .filter(|tag| matcher.is_match(tag))
+        .filter_map(|tag| {
+            let version = tag.trim_start_matches(|c: char| !c.is_ascii_digit());
+            Some((Version::parse(version).ok()?, tag))
+        })
+        .filter(|(version, _)| prereleases || version.pre.is_empty())
+        .max()
+        .map(|(_, tag)| tag))
+}
+
+/// The size in git's last "Receiving objects" progress line, which is missing
+/// when there was nothing to receive
+fn received_bytes(progress: &str) -> u64 {
+    let pattern = Regex::new(r"Receiving objects:[^\r\n]*?, ([\d.]+) (bytes|KiB|MiB|GiB)").unwrap();
+
+    pattern
+        .captures_iter(progress)
+        .last()
+        .and_then(|captures| {
+            let amount: f64 = captures[1].parse().ok()?;
+            let unit = match &captures[2] {
+                "KiB" => 1 << 10,
+                "MiB" => 1 << 20,
+                "GiB" => 1 << 30,
+                _ => 1,
+            };
+            Some((amount * unit as f64) as u64)
+        })
+        .unwrap_or(0)
+}
+
+pub fn updater() -> &'static dyn Updater {
+    #[cfg(feature = "native-git")]
+    {
+        &super::native_git::Native
+    }
+    #[cfg(not(feature = "native-git"))]
+    {
+        &CommandLine
+    }
+}
+
+/// Fetches and resets to the deploy target. `env_file` is the config's, which
+/// cleaning leaves alone like `git.auth.token_file`.
+pub fn update_git_from_remote(
+    cwd: Option<&Path>,
+    git_config: &GitConfig,
+    env_file: Option<&str>,
+) -> Result<Update> {
+    let updater = updater();
+    let fetch = updater.fetch(cwd, git_config)?;
+    info!(
+        "{} {} in {:.1?} ({} bytes)",
+        STAT_FETCHED,
+        git_config.describe_target(),
+        fetch.duration,
+        fetch.bytes
+    );
+
+    let target = target_rev(cwd, git_config)?;
+    let commit = updater.resolve_commit(cwd, &target)?;
+    if let Some(verify_config) = &git_config.verify {
+        // Checks the tag's own signature when deploying annotated tags
+        commit_verifier::verify(cwd, &target, verify_config)?;
+    }
+
+    let previous = updater.resolve_commit(cwd, "HEAD").ok();
+    let changed = match &previous {
+        Some(previous) => match updater.changed_paths(cwd, previous, &commit) {
+            Ok(changed) => Some(changed),
+            Err(e) => {
+                error!("{:#}", e);
+                warn!("{}", DIFF_FAILED_WARN);
+                None
+            }
+        },
+        None => None,
+    };
+    let skipped = skipped_target(cwd)?;
+    let relevant = match (&changed, &git_config.paths) {
+        // Refreshes still restart when following a branch
+        (Some(_), _) if previous.as_ref() == Some(&commit) => git_config.target.is_branch(),
+        _ if skipped.as_ref() == Some(&commit) => false,
+        (Some(changed), Some(paths)) => any_relevant(changed, paths)?,
+        _ => true,
     };
 
-    if git_fetch.is_err() {
-        bail!(GIT_FETCH_FAILED_ERROR);
+    let mut update = Update {
+        seen: previous.as_ref() == Some(&commit) || skipped.as_ref() == Some(&commit),
+        commit,
+        fetch,
+        changed,
+        relevant,
+        reset: false,
+    };
+    if !relevant
+        && git_config
+            .paths
+            .as_ref()
+            .is_some_and(|paths| !paths.update_tree)
+    {
+        // Remembered so that polls don't keep finding it
+        set_skipped_target(cwd, Some(&update.commit))?;
+        return Ok(update);
     }
 
-    let git_reset = match cwd {
-        Some(path) => Command::new("git")
-            .args(&["reset", "--hard", "origin/main"])
-            .current_dir(path)
-            .output(),
-        None => Command::new("git")
-            .args(&["reset", "--hard", "origin/main"])
-            .output(),
+    set_skipped_target(cwd, None)?;
+    let backup = backup_preserved(cwd, &git_config.preserve)?;
+    let result = reset_and_clean(updater, cwd, git_config, env_file, &update.commit);
+    // Restore even if resetting failed halfway
+    restore_preserved(cwd, &backup, &git_config.preserve)?;
+
+    result.map(|()| {
+        update.reset = true;
+        update
+    })
+}
+
+/// The target last left out of the working tree because none of its changes
+/// were relevant
+fn skipped_target(cwd: Option<&Path>) -> Result<Option<String>> {
+    match fs::read_to_string(git_dir(cwd)?.join(SKIPPED_TARGET_FILE_NAME)) {
+        Ok(commit) => Ok(Some(commit.trim().to_owned())),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).with_context(|| SKIPPED_TARGET_ERROR),
+    }
+}
+
+fn set_skipped_target(cwd: Option<&Path>, commit: Option<&str>) -> Result<()> {
+    let path = git_dir(cwd)?.join(SKIPPED_TARGET_FILE_NAME);
+
+    match commit {
+        Some(commit) => fs::write(path, commit),
+        None => remove(&path),
+    }
+    .with_context(|| SKIPPED_TARGET_ERROR)
+}
+
+/// Whether any of the changed files is included and not excluded
+fn any_relevant(changed: &[String], paths: &PathsConfig) -> Result<bool> {
+    let include = glob_set(&paths.include, INVALID_PATHS_GLOB_ERROR)?;
+    let exclude = glob_set(&paths.exclude, INVALID_PATHS_GLOB_ERROR)?;
+
+    Ok(changed.iter().any(|path| {
+        let relevant =
+            !exclude.is_match(path) && (paths.include.is_empty() || include.is_match(path));
+        debug!("Changed: {} (relevant: {})", path, relevant);
+        relevant
+    }))
+}
+
+pub fn glob_set(globs: &[String], error: &str) -> Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+
+    for glob in globs {
+        builder.add(Glob::new(glob).with_context(|| format!("{}: {}", error, glob))?);
+    }
+
+    builder.build().with_context(|| error.to_owned())
+}
+
+/// Submodules, LFS and cleaning always use the `git` binary, as libgit2
+/// doesn't do them all
+fn reset_and_clean(
+    updater: &dyn Updater,
+    cwd: Option<&Path>,
+    git_config: &GitConfig,
+    env_file: Option<&str>,
+    target: &str,
+) -> Result<()> {
+    updater.reset_hard(cwd, target)?;
+
+    let credentials = if git_config.submodules || git_config.lfs {
+        credentials(cwd, git_config)?
+    } else {
+        None
     };
 
-    if git_reset.is_err() {
-        bail!(GIT_RESET_FAILED_ERROR);
+    if git_config.submodules {
+        // Sync first, in case a submodule's URL changed
+        for args in [
+            &["submodule", "sync", "--recursive"][..],
+            &["submodule", "update", "--init", "--recursive", "--force"],
+        ] {
+            let error = GIT_SUBMODULE_FAILED_ERROR.to_owned();
+            run_remote_git(cwd, args, credentials.as_ref(), error)?;
+        }
+    }
+
+    if git_config.lfs {
+        for args in [
+            &["lfs", "fetch", &git_config.remote][..],
+            &["lfs", "checkout"],
+        ] {
+            let error = GIT_LFS_FAILED_ERROR.to_owned();
+            run_remote_git(cwd, args, credentials.as_ref(), error)?;
+        }
+    }
+
+    if let Some(clean) = &git_config.clean {
+        // Secrets are usually ignored files, which -x would remove
+        let token_file = git_config
+            .auth
+            .as_ref()
+            .and_then(|auth| auth.token_file.as_deref());
+        let secrets = env_file.into_iter().chain(token_file);
+
+        let mut args = vec!["clean", "-fdx"];
+        for pattern in clean.exclude.iter().chain(&git_config.preserve) {
+            args.extend(["-e", pattern]);
+        }
+        for path in secrets {
+            args.extend(["-e", path]);
+        }
+
+        if run_git(cwd, &args).is_none() {
+            bail!(GIT_CLEAN_FAILED_ERROR);
+        }
     }
 
     Ok(())
 }
 
+/// The `.git` directory, which resets and cleans leave alone
+pub fn git_dir(cwd: Option<&Path>) -> Result<PathBuf> {
+    let root = cwd.unwrap_or_else(|| Path::new("."));
+
+    match run_git(cwd, &["rev-parse", "--git-dir"]) {
+        Some(output) => Ok(root.join(stdout(&output).trim())),
+        None => bail!(GIT_REV_PARSE_FAILED_ERROR),
+    }
+}
+
+/// Copies the preserved paths that exist into the git directory
+fn backup_preserved(cwd: Option<&Path>, preserve: &[String]) -> Result<PathBuf> {
+    let root = cwd.unwrap_or_else(|| Path::new("."));
+    let backup = git_dir(cwd)?.join(PRESERVE_DIR);
+
+    // Left over from a deploy that was interrupted
+    if backup.exists() {
+        fs::remove_dir_all(&backup).with_context(|| PRESERVE_FAILED_ERROR)?;
+    }
+
+    for path in preserve {
+        let from = root.join(path);
+        if from.symlink_metadata().is_ok() {
+            copy_recursively(&from, &backup.join(path))
+                .with_context(|| format!("{} {}", PRESERVE_FAILED_ERROR, path))?;
+        }
+    }
+
+    Ok(backup)
+}
+
+fn restore_preserved(cwd: Option<&Path>, backup: &Path, preserve: &[String]) -> Result<()> {
+    let root = cwd.unwrap_or_else(|| Path::new("."));
+
+    for path in preserve {
+        let (from, to) = (backup.join(path), root.join(path));
+        if from.symlink_metadata().is_err() {
+            continue;
+        }
+
+        let restored = remove(&to).and_then(|()| {
+            if let Some(parent) = to.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::rename(&from, &to)
+        });
+        restored.with_context(|| format!("{} {}", RESTORE_FAILED_ERROR, path))?;
+    }
+
+    if backup.exists() {
+        fs::remove_dir_all(backup).with_context(|| RESTORE_FAILED_ERROR)?;
+    }
+
+    Ok(())
+}
+
+fn copy_recursively(from: &Path, to: &Path) -> io::Result<()> {
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    if from.is_dir() {
+        fs::create_dir_all(to)?;
+        for entry in fs::read_dir(from)? {
+            let entry = entry?;
+            copy_recursively(&entry.path(), &to.join(entry.file_name()))?;
+        }
+        Ok(())
+    } else {
+        fs::copy(from, to).map(|_| ())
+    }
+}
+
+/// Removes a file or directory, if it exists
+fn remove(path: &Path) -> io::Result<()> {
+    match path.symlink_metadata() {
+        Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(path),
+        Ok(_) => fs::remove_file(path),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Fetches from the remote and returns the deploy target's commit if it has
+/// moved away from the currently checked out commit, and wasn't skipped already
+pub fn fetch_and_check_for_update(
+    cwd: Option<&Path>,
+    git_config: &GitConfig,
+) -> Result<Option<String>> {
+    let updater = updater();
+    updater.fetch(cwd, git_config)?;
+
+    let target = updater.resolve_commit(cwd, &target_rev(cwd, git_config)?)?;
+    let new = updater.resolve_commit(cwd, "HEAD")? != target
+        && skipped_target(cwd)?.as_ref() != Some(&target);
+    Ok(new.then_some(target))
+}
+
+/// Fetches only the configured branch, to check that fetching works at all
+pub fn fetch_branch(cwd: Option<&Path>, git_config: &GitConfig) -> Result<()> {
+    if run_git(cwd, &["fetch", &git_config.remote, &git_config.branch]).is_none() {
+        bail!("{} ({})", GIT_FETCH_FAILED_ERROR, git_config.remote_ref());
+    }
+
+    Ok(())
+}
+
+pub fn rev_parse(cwd: Option<&Path>, rev: &str) -> Result<String> {
+    match run_git(cwd, &["rev-parse", "--verify", rev]) {
+        Some(output) => Ok(stdout(&output).trim().to_owned()),
+        None => bail!("{} ({})", GIT_REV_PARSE_FAILED_ERROR, rev),
+    }
+}
+
+/// Prefers `origin`, falling back to the first configured remote
+pub fn default_remote(cwd: Option<&Path>) -> Result<String> {
+    let output = match run_git(cwd, &["remote"]) {
+        Some(output) => output,
+        None => bail!(NO_GIT_REMOTE_ERROR),
+    };
+
+    let remotes: Vec<_> = stdout(&output).lines().map(String::from).collect();
+    match remotes.iter().find(|remote| *remote == "origin") {
+        Some(origin) => Ok(origin.clone()),
+        None => match remotes.into_iter().next() {
+            Some(remote) => Ok(remote),
+            None => bail!(NO_GIT_REMOTE_ERROR),
+        },
+    }
+}
+
+/// Asks the remote which branch its HEAD points to, falling back to the
+/// locally checked out branch
+pub fn default_branch(cwd: Option<&Path>, remote: &str) -> Option<String> {
+    let remote_head = format!("refs/remotes/{}/HEAD", remote);
+    if let Some(output) = run_git(cwd, &["symbolic-ref", "--short", &remote_head]) {
+        let prefix = format!("{}/", remote);
+        if let Some(branch) = stdout(&output).trim().strip_prefix(&prefix) {
+            return Some(branch.to_owned());
+        }
+    }
+
+    if let Some(output) = run_git(cwd, &["ls-remote", "--symref", remote, "HEAD"]) {
+        let branch = stdout(&output).lines().find_map(|line| {
+            line.strip_prefix("ref: refs/heads/")
+                .and_then(|rest| rest.split_whitespace().next())
+                .map(String::from)
+        });
+
+        if branch.is_some() {
+            return branch;
+        }
+    }
+
+    run_git(cwd, &["symbolic-ref", "--short", "HEAD"])
+        .map(|output| stdout(&output).trim().to_owned())
+}
+
+/// Tracked files with local changes, which `git reset --hard` would discard
+pub fn uncommitted_changes(cwd: Option<&Path>) -> Result<Vec<String>> {
+    match run_git(cwd, &["status", "--porcelain", "--untracked-files=no"]) {
+        Some(output) => Ok(stdout(&output)
+            .lines()
+            .filter_map(|line| line.get(3..))
+            .map(String::from)
+            .collect()),
+        None => bail!(GIT_STATUS_FAILED_ERROR),
+    }
+}
+
+/// Whether git tracks the file, so that deploys reset it
+pub fn is_tracked(cwd: Option<&Path>, path: &str) -> bool {
+    run_git(cwd, &["ls-files", "--error-unmatch", "--", path]).is_some()
+}
+
+/// Brings a bare mirror (made with `git clone --mirror`) up to date
+pub fn update_mirror(mirror: &Path) -> Result<()> {
+    if run_git(Some(mirror), &["remote", "update", "--prune"]).is_none() {
+        bail!("{} ({})", GIT_FETCH_FAILED_ERROR, mirror.display());
+    }
+
+    Ok(())
+}
+
+/// Contents of a file at a commit, or `None` if the file doesn't exist there
+pub fn read_file_at_commit(cwd: Option<&Path>, commit: &str, path: &str) -> Result<Option<String>> {
+    rev_parse(cwd, &format!("{}^{{commit}}", commit))?;
+
+    Ok(run_git(cwd, &["show", &format!("{}:{}", commit, path)]).map(|output| stdout(&output)))
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+fn run_git(cwd: Option<&Path>, args: &[&str]) -> Option<Output> {
+    match git_output(cwd, args, None) {
+        Ok(output) if output.status.success() => Some(output),
+        _ => None,
+    }
+}
+
+/// Runs a git command that talks to the remote, failing with `error` and the
+/// (redacted) reason git gave
+fn run_remote_git(
+    cwd: Option<&Path>,
+    args: &[&str],
+    credentials: Option<&Credentials>,
+    error: String,
+) -> Result<Output> {
+    let output = git_output(cwd, args, credentials).with_context(|| error.clone())?;
+    if output.status.success() {
+        return Ok(output);
+    }
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let reason = stderr
+        .lines()
+        .rev()
+        .map(str::trim)
+        .find(|line| !line.is_empty())
+        .unwrap_or_default();
+    let reason = match credentials {
+        Some(credentials) => credentials.redact(reason),
+        None => git_auth::redact(reason, None),
+    };
+
+    bail!("{}: {}", error, reason)
+}
+
+fn git_output(
+    cwd: Option<&Path>,
+    args: &[&str],
+    credentials: Option<&Credentials>,
+) -> io::Result<Output> {
+    let mut git = Command::new("git");
+    if let Some(credentials) = credentials {
+        credentials.configure(&mut git);
+    }
+    git.args(args);
+
+    if let Some(path) = cwd {
+        git.current_dir(path);
+    }
+
+    git.output()
+}
+
+/// The credentials in `git.auth`, if any
+pub fn credentials(cwd: Option<&Path>, git_config: &GitConfig) -> Result<Option<Credentials>> {
+    let root = cwd.unwrap_or_else(|| Path::new("."));
+
+    git_config
+        .auth
+        .as_ref()
+        .map(|auth| git_auth::load(root, auth, |name| env::var(name).ok()))
+        .transpose()
+}
+
+// Where preserved paths are kept during a deploy, inside the git directory
+const PRESERVE_DIR: &str = "repl-deploy-preserve";
+
 #[cfg(test)]
 mod tests {
     use {
         super::*,
-        std::{fs, path::Path},
+        crate::types::{CleanConfig, GitAuth},
     };
 
     #[test]
     fn try_reset() {
-        prepare_repos();
-        update_git_from_remote(Some(Path::new("./test_repo2"))).expect("Failed to update from git");
+        prepare_repos("test_repo");
+        update_git_from_remote(Some(Path::new("./test_repo2")), &GitConfig::default(), None)
+            .expect("Failed to update from git");
         let file_contents = fs::read_to_string("./test_repo2/temp")
             .expect("Failed to read contents of test_repo2/temp");
         assert_eq!(file_contents, "hi")
     }
 
-    fn prepare_repos() {
+    #[test]
+    fn updaters_fetch_and_reset() {
+        let updaters: Vec<(&str, &dyn Updater)> = vec![
+            ("cli", &CommandLine),
+            #[cfg(feature = "native-git")]
+            ("native", &crate::native_git::Native),
+        ];
+
+        for (name, updater) in updaters {
+            let prefix = format!("test_repo_updater_{}", name);
+            prepare_repos(&prefix);
+            let (remote, dir) = (
+                format!("{}1", prefix),
+                PathBuf::from(format!("{}2", prefix)),
+            );
+            let local = Some(dir.as_path());
+            let git_config = GitConfig::default();
+
+            updater.fetch(local, &git_config).unwrap();
+            let first = updater.resolve_commit(local, "origin/main").unwrap();
+            updater.reset_hard(local, &first).unwrap();
+            assert_eq!(updater.resolve_commit(local, "HEAD").unwrap(), first);
+
+            commit_to_remote(&remote, "bye");
+            fs::write(dir.join("temp"), "local change").unwrap();
+            updater.fetch(local, &git_config).unwrap();
+            let second = updater.resolve_commit(local, "origin/main").unwrap();
+            assert_ne!(first, second, "{}", name);
+
+            updater.reset_hard(local, &second).unwrap();
+            assert_eq!(fs::read_to_string(dir.join("temp")).unwrap(), "bye");
+            assert!(updater.resolve_commit(local, "origin/missing").is_err());
+        }
+    }
+
+    #[test]
+    fn parses_received_bytes() {
+        let progress = "Receiving objects:  50% (1/2)\rReceiving objects: 100% (2/2), 1.50 KiB | 1.50 MiB/s, done.\n";
+        assert_eq!(received_bytes(progress), 1536);
+        assert_eq!(
+            received_bytes("Receiving objects: 100% (3/3), 251 bytes | 251.00 KiB/s, done."),
+            251
+        );
+        assert_eq!(received_bytes("Receiving objects: 100% (1/1), done."), 0);
+        assert_eq!(received_bytes(""), 0);
+    }
+
+    #[test]
+    fn shallow_and_partial_fetch() {
+        prepare_repos("test_repo_shallow");
+        let (remote, local) = ("test_repo_shallow1", Path::new("./test_repo_shallow2"));
+        git(remote, &["config", "uploadpack.allowFilter", "true"]);
+        commit_to_remote(remote, "bye");
+        git(remote, &["branch", "other"]);
+
+        let git_config = GitConfig {
+            depth: Some(1),
+            filter: Some("blob:none".to_owned()),
+            ..Default::default()
+        };
+        let update = update_git_from_remote(Some(local), &git_config, None)
+            .expect("Failed to update from git");
+        assert!(update.fetch.bytes > 0);
+        assert_eq!(fs::read_to_string(local.join("temp")).unwrap(), "bye");
+
+        // Only the deploy branch is fetched, with one commit of history
+        assert!(rev_parse(Some(local), "origin/other").is_err());
+        assert!(rev_parse(Some(local), "HEAD~1").is_err());
+    }
+
+    #[test]
+    fn check_for_update() {
+        prepare_repos("test_repo_poll");
+        let local = Path::new("./test_repo_poll2");
+        let git_config = GitConfig::default();
+        let check = || fetch_and_check_for_update(Some(local), &git_config);
+
+        update_git_from_remote(Some(local), &git_config, None).expect("Failed to update from git");
+        assert_eq!(check().expect("Failed to check for update"), None);
+
+        commit_to_remote("test_repo_poll1", "bye");
+        assert_eq!(
+            check().expect("Failed to check for update"),
+            Some(rev_parse(Some(local), "origin/main").unwrap())
+        );
+
+        update_git_from_remote(Some(local), &git_config, None).expect("Failed to update from git");
+        assert_eq!(check().expect("Failed to check for update"), None);
+    }
+
+    #[test]
+    fn deploy_tags_and_pinned_commits() {
+        prepare_repos("test_repo_tags");
+        let (remote, local) = ("test_repo_tags1", Path::new("./test_repo_tags2"));
+        for (contents, tags) in [
+            ("one", &["v1.2.0"][..]),
+            ("ten", &["v1.10.0"]),
+            ("nine", &["v1.9.0", "release-2.0.0", "latest"]),
+        ] {
+            commit_to_remote(remote, contents);
+            for tag in tags {
+                git(remote, &["tag", "-a", "-m", tag, tag]);
+            }
+        }
+        let first = rev_parse(Some(Path::new(remote)), "v1.2.0^{commit}").unwrap();
+
+        let git_config = GitConfig {
+            target: DeployTarget::TagPattern {
+                pattern: "v*".to_owned(),
+            },
+            ..Default::default()
+        };
+        update_git_from_remote(Some(local), &git_config, None).expect("Failed to update from git");
+        assert_eq!(fs::read_to_string(local.join("temp")).unwrap(), "ten");
+
+        // Commits without a new tag don't change what's deployed
+        commit_to_remote(remote, "untagged");
+        assert_eq!(
+            fetch_and_check_for_update(Some(local), &git_config).unwrap(),
+            None
+        );
+        let update = update_git_from_remote(Some(local), &git_config, None).unwrap();
+        assert!(update.seen && !update.relevant);
+        assert_eq!(fs::read_to_string(local.join("temp")).unwrap(), "ten");
+
+        git(remote, &["tag", "v1.10.1"]);
+        assert!(fetch_and_check_for_update(Some(local), &git_config)
+            .unwrap()
+            .is_some());
+        update_git_from_remote(Some(local), &git_config, None).expect("Failed to update from git");
+        assert_eq!(fs::read_to_string(local.join("temp")).unwrap(), "untagged");
+
+        let pinned = GitConfig {
+            target: DeployTarget::Pinned { commit: first },
+            ..Default::default()
+        };
+        update_git_from_remote(Some(local), &pinned, None).expect("Failed to update from git");
+        assert_eq!(fs::read_to_string(local.join("temp")).unwrap(), "one");
+
+        let missing = GitConfig {
+            target: DeployTarget::TagPattern {
+                pattern: "nightly-*".to_owned(),
+            },
+            ..Default::default()
+        };
+        assert!(update_git_from_remote(Some(local), &missing, None).is_err());
+
+        let unknown = GitConfig {
+            target: DeployTarget::Pinned {
+                commit: "0123456789abcdef0123456789abcdef01234567".to_owned(),
+            },
+            ..Default::default()
+        };
+        let e = update_git_from_remote(Some(local), &unknown, None).unwrap_err();
+        assert!(e.to_string().starts_with(PINNED_COMMIT_MISSING_ERROR));
+
+        // Fetched, but only on another branch
+        git(remote, &["checkout", "-q", "-b", "feature"]);
+        commit_to_remote(remote, "feature");
+        git(remote, &["checkout", "-q", "main"]);
+        git(
+            local.to_str().unwrap(),
+            &["fetch", "-q", "origin", "feature"],
+        );
+        let feature = rev_parse(Some(Path::new(remote)), "feature").unwrap();
+        let off_branch = GitConfig {
+            target: DeployTarget::Pinned { commit: feature },
+            ..Default::default()
+        };
+        let e = update_git_from_remote(Some(local), &off_branch, None).unwrap_err();
+        assert!(e.to_string().starts_with(PINNED_COMMIT_NOT_ON_BRANCH_ERROR));
+        assert_eq!(fs::read_to_string(local.join("temp")).unwrap(), "one");
+    }
+
+    #[test]
+    fn skip_prerelease_tags() {
+        let tags: Vec<_> = [
+            "v1.9.0",
+            "v2.0.0-rc1",
+            "release-1.0.0",
+            "release-2.0.0-beta",
+        ]
+        .iter()
+        .map(|tag| tag.to_string())
+        .collect();
+        let latest = |pattern| latest_tag(&tags, pattern).unwrap().map(String::as_str);
+
+        assert_eq!(latest("v*"), Some("v1.9.0"));
+        assert_eq!(latest("release-*"), Some("release-1.0.0"));
+        assert_eq!(latest("v*-*"), Some("v2.0.0-rc1"));
+        assert_eq!(latest("v2.0.0-rc*"), Some("v2.0.0-rc1"));
+    }
+
+    #[test]
+    fn filter_changed_paths() {
+        prepare_repos("test_repo_paths");
+        let (remote, local) = ("test_repo_paths1", Path::new("./test_repo_paths2"));
+        update_git_from_remote(Some(local), &GitConfig::default(), None)
+            .expect("Failed to update from git");
+
+        let mut git_config = GitConfig {
+            paths: Some(PathsConfig {
+                include: vec!["src/**".to_owned()],
+                exclude: vec!["**/*.md".to_owned()],
+                update_tree: true,
+            }),
+            ..Default::default()
+        };
+        let push = |path: &str, git_config: &GitConfig| {
+            let file = Path::new(remote).join(path);
+            fs::create_dir_all(file.parent().unwrap()).unwrap();
+            fs::write(file, path).unwrap();
+            git(remote, &["add", "."]);
+            git(remote, &["commit", "-m", path]);
+            update_git_from_remote(Some(local), git_config, None)
+                .expect("Failed to update from git")
+        };
+
+        assert!(!push("docs/guide.txt", &git_config).relevant);
+        assert!(local.join("docs/guide.txt").exists());
+        assert!(!push("src/README.md", &git_config).relevant);
+        assert!(push("src/main.rs", &git_config).relevant);
+
+        // Without update_tree, irrelevant pushes leave the tree alone until a
+        // relevant one arrives
+        git_config.paths.as_mut().unwrap().update_tree = false;
+        let skipped = push("docs/other.txt", &git_config);
+        assert!(!skipped.relevant && !skipped.seen && !skipped.reset);
+        assert!(!local.join("docs/other.txt").exists());
+        assert_eq!(
+            fetch_and_check_for_update(Some(local), &git_config).unwrap(),
+            None
+        );
+        let again = update_git_from_remote(Some(local), &git_config, None).unwrap();
+        assert!(!again.relevant && again.seen);
+        assert!(push("src/lib.rs", &git_config).relevant);
+        assert!(local.join("docs/other.txt").exists());
+    }
+
+    #[test]
+    fn detect_remote_and_branch() {
+        prepare_repos("test_repo_detect");
+        let local = Path::new("./test_repo_detect2");
+
+        let remote = default_remote(Some(local)).expect("Failed to detect remote");
+        assert_eq!(remote, "origin");
+        assert_eq!(
+            default_branch(Some(local), &remote).as_deref(),
+            Some("main")
+        );
+    }
+
+    #[test]
+    fn detect_uncommitted_changes() {
+        prepare_repos("test_repo_changes");
+        let local = Path::new("./test_repo_changes2");
+
+        update_git_from_remote(Some(local), &GitConfig::default(), None)
+            .expect("Failed to update from git");
+        assert!(uncommitted_changes(Some(local)).unwrap().is_empty());
+
+        fs::write(local.join("temp"), "local change").unwrap();
+        fs::write(local.join("untracked"), "survives a reset").unwrap();
+        assert_eq!(uncommitted_changes(Some(local)).unwrap(), vec!["temp"]);
+    }
+
+    #[test]
+    fn preserve_and_clean() {
+        prepare_repos("test_repo_preserve");
+        let local = Path::new("./test_repo_preserve2");
+        let git_config = GitConfig {
+            preserve: vec!["temp".to_owned(), "uploads".to_owned()],
+            clean: Some(CleanConfig {
+                exclude: vec!["node_modules".to_owned()],
+            }),
+            auth: Some(GitAuth {
+                token_file: Some(".token".to_owned()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+
+        // Secrets survive cleaning without being excluded
+        for secret in [".token", ".env"] {
+            fs::write(local.join(secret), "secret").unwrap();
+        }
+        update_git_from_remote(Some(local), &git_config, Some(".env"))
+            .expect("Failed to update from git");
+        fs::write(local.join("temp"), "local database").unwrap();
+        fs::create_dir_all(local.join("uploads/avatars")).unwrap();
+        fs::write(local.join("uploads/avatars/1.png"), "png").unwrap();
+        for stale in ["node_modules", "target"] {
+            fs::create_dir_all(local.join(stale)).unwrap();
+            fs::write(local.join(stale).join("out"), "built").unwrap();
+        }
+
+        commit_to_remote("test_repo_preserve1", "bye");
+        update_git_from_remote(Some(local), &git_config, Some(".env"))
+            .expect("Failed to update from git");
+
+        assert_eq!(
+            fs::read_to_string(local.join("temp")).unwrap(),
+            "local database"
+        );
+        assert!(local.join("uploads/avatars/1.png").exists());
+        assert!(local.join("node_modules").exists());
+        assert!(!local.join("target").exists());
+        assert!(local.join(".token").exists() && local.join(".env").exists());
+        assert_eq!(
+            rev_parse(Some(local), "HEAD").unwrap(),
+            rev_parse(Some(local), "origin/main").unwrap()
+        );
+    }
+
+    #[test]
+    fn update_submodules() {
+        prepare_repos("test_repo_submodule");
+        prepare_repos("test_repo_submodule_lib");
+        let (remote, local) = ("test_repo_submodule1", Path::new("./test_repo_submodule2"));
+        let lib = fs::canonicalize("test_repo_submodule_lib1").unwrap();
+
+        // Newer versions of git refuse local submodules unless allowed. Clones
+        // don't read the superproject's config, so the test clones it itself,
+        // and allows fetching in the submodule's own config.
+        let allow = ["-c", "protocol.file.allow=always"];
+        git(
+            remote,
+            &[
+                &allow[..],
+                &["submodule", "add", lib.to_str().unwrap(), "lib"],
+            ]
+            .concat(),
+        );
+        git(remote, &["commit", "-m", "add_submodule"]);
+        let local_dir = local.to_str().unwrap();
+        git(local_dir, &["fetch", "origin"]);
+        git(local_dir, &["reset", "--hard", "origin/main"]);
+        git(
+            local_dir,
+            &[&allow[..], &["submodule", "update", "--init"]].concat(),
+        );
+        git(
+            &format!("{}/lib", local_dir),
+            &["config", "protocol.file.allow", "always"],
+        );
+
+        let git_config = GitConfig {
+            submodules: true,
+            ..Default::default()
+        };
+        update_git_from_remote(Some(local), &git_config, None).expect("Failed to update from git");
+        assert_eq!(fs::read_to_string(local.join("lib/temp")).unwrap(), "hi");
+
+        commit_to_remote("test_repo_submodule_lib1", "bye");
+        git(&format!("{}/lib", remote), &["pull", "origin", "main"]);
+        git(remote, &["commit", "-am", "bump_submodule"]);
+
+        update_git_from_remote(Some(local), &git_config, None).expect("Failed to update from git");
+        assert_eq!(fs::read_to_string(local.join("lib/temp")).unwrap(), "bye");
+    }
+
+    #[test]
+    fn lfs_failures_are_reported() {
+        prepare_repos("test_repo_lfs");
+        let git_config = GitConfig {
+            lfs: true,
+            ..Default::default()
+        };
+
+        let result = update_git_from_remote(Some(Path::new("./test_repo_lfs2")), &git_config, None);
+        // Without git-lfs there's nothing to check but the error
+        if run_git(None, &["lfs", "version"]).is_some() {
+            result.expect("Failed to update from git");
+        } else {
+            assert!(result
+                .unwrap_err()
+                .to_string()
+                .starts_with(GIT_LFS_FAILED_ERROR));
+        }
+    }
+
+    #[test]
+    fn read_from_mirror() {
+        prepare_repos("test_repo_mirror");
+        let mirror = Path::new("./test_repo_mirror_bare");
+        let _ = fs::remove_dir_all(mirror);
+        Command::new("git")
+            .args([
+                "clone",
+                "--mirror",
+                "test_repo_mirror1",
+                "test_repo_mirror_bare",
+            ])
+            .output()
+            .expect("Failed to create mirror");
+
+        commit_to_remote("test_repo_mirror1", "bye");
+        update_mirror(mirror).expect("Failed to update mirror");
+
+        let commit = rev_parse(Some(mirror), "main").unwrap();
+        assert_eq!(
+            read_file_at_commit(Some(mirror), &commit, "temp")
+                .unwrap()
+                .as_deref(),
+            Some("bye")
+        );
+        assert_eq!(
+            read_file_at_commit(Some(mirror), &commit, "missing").unwrap(),
+            None
+        );
+        assert!(read_file_at_commit(Some(mirror), "0123456789abcdef", "temp").is_err());
+    }
+
+    fn prepare_repos(prefix: &str) {
         println!(
             "{}",
             String::from_utf8_lossy(
                 &Command::new("./src/functionality/git_updater_test_prep.sh")
+                    .arg(prefix)
                     .output()
                     .expect("Failed to prepare repos")
                     .stdout
             )
         );
     }
+
+    fn git(repo: &str, args: &[&str]) {
+        let output = Command::new("git")
+            .args(args)
+            .current_dir(repo)
+            .output()
+            .expect("Failed to run git");
+        assert!(
+            output.status.success(),
+            "git {:?}: {}",
+            args,
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+
+    fn commit_to_remote(repo: &str, contents: &str) {
+        fs::write(Path::new(repo).join("temp"), contents).expect("Failed to write to remote");
+        Command::new("git")
+            .args(["commit", "-am", "another_commit"])
+            .current_dir(repo)
+            .output()
+            .expect("Failed to commit to remote");
+    }
 }
@@ -1,16 +1,19 @@
 #!/bin/bash
 set -e
 
+# Repos are created as <prefix>1 (remote) and <prefix>2 (local clone)
+PREFIX="${1:-test_repo}"
+
 # Clean old test results
-rm -rf test_repo1 test_repo2
+rm -rf "${PREFIX}1" "${PREFIX}2"
 
-mkdir test_repo1
-cd test_repo1
+mkdir "${PREFIX}1"
+cd "${PREFIX}1"
 git init
 
 cd ..
-git clone ./test_repo1 test_repo2
-cd test_repo1
+git clone "./${PREFIX}1" "${PREFIX}2"
+cd "${PREFIX}1"
 printf "hi" > temp
 
 git config user.email "test-runner@example.com"
@@ -25,7 +28,7 @@ printf "Parent dir:\n"
 ls ..
 
 printf "\nTest repo 1:\n"
-ls ../test_repo1
+ls "../${PREFIX}1"
 
 printf "\nTest repo 2:\n"
-ls ../test_repo2
+ls "../${PREFIX}2"
@@ -0,0 +1,206 @@
+use {
+    super::constants::{
+        GIT_AUTH_FAILED_ERROR, GIT_FETCH_FAILED_ERROR, GIT_RESET_FAILED_ERROR,
+        GIT_REV_PARSE_FAILED_ERROR, NATIVE_FILTER_UNSUPPORTED_WARN, NOT_A_REPOSITORY_ERROR,
+    },
+    super::git_auth,
+    super::git_updater::{self, fetch_refspec, CommandLine, FetchStats, Updater},
+    super::types::GitConfig,
+    anyhow::{bail, Context, Result},
+    git2::{
+        build::CheckoutBuilder, AutotagOption, Cred, CredentialType, FetchOptions, ObjectType,
+        RemoteCallbacks, Repository, ResetType,
+    },
+    log::{trace, warn},
+    std::{path::Path, time::Instant},
+};
+
+/// Updates in process with libgit2, so no `git` binary is needed for deploys
+pub struct Native;
+
+impl Updater for Native {
+    fn fetch(&self, cwd: Option<&Path>, git_config: &GitConfig) -> Result<FetchStats> {
+        if git_config.filter.is_some() {
+            warn!("{}", NATIVE_FILTER_UNSUPPORTED_WARN);
+            return CommandLine.fetch(cwd, git_config);
+        }
+
+        let started = Instant::now();
+        let repo = open(cwd)?;
+        let mut remote = repo
+            .find_remote(&git_config.remote)
+            .with_context(|| format!("{} ({})", GIT_FETCH_FAILED_ERROR, git_config.remote))?;
+
+        let credentials = git_updater::credentials(cwd, git_config)?;
+        let mut attempts = 0;
+
+        let mut callbacks = RemoteCallbacks::new();
+        callbacks.credentials(|_url, username_from_url, allowed| {
+            attempts += 1;
+            let credentials = match &credentials {
+                // libgit2 asks again after a rejection, which would never end
+                Some(credentials) if attempts == 1 => credentials,
+                _ => return Err(git2::Error::from_str(GIT_AUTH_FAILED_ERROR)),
+            };
+
+            match (&credentials.ssh_key, &credentials.token) {
+                (Some(key), _) if allowed.contains(CredentialType::SSH_KEY) => {
+                    Cred::ssh_key(username_from_url.unwrap_or("git"), None, key, None)
+                }
+                (_, Some(token)) if allowed.contains(CredentialType::USER_PASS_PLAINTEXT) => {
+                    Cred::userpass_plaintext(&credentials.username, token)
+                }
+                _ => Err(git2::Error::from_str(GIT_AUTH_FAILED_ERROR)),
+            }
+        });
+        callbacks.transfer_progress(|progress| {
+            trace!(
+                "Fetching: {}/{} objects",
+                progress.received_objects(),
+                progress.total_objects()
+            );
+            true
+        });
+
+        let mut options = FetchOptions::new();
+        options
+            .remote_callbacks(callbacks)
+            .download_tags(AutotagOption::None);
+        if let Some(depth) = git_config.depth {
+            options.depth(depth as i32);
+        }
+        let fetched = remote.fetch(&[&fetch_refspec(git_config)], Some(&mut options), None);
+        if let Err(e) = fetched {
+            let token = credentials.as_ref().and_then(|c| c.token.as_deref());
+            bail!(
+                "{} ({}): {}",
+                GIT_FETCH_FAILED_ERROR,
+                git_config.remote_ref(),
+                git_auth::redact(e.message(), token)
+            );
+        }
+
+        Ok(FetchStats {
+            duration: started.elapsed(),
+            bytes: remote.stats().received_bytes() as u64,
+        })
+    }
+
+    fn resolve_commit(&self, cwd: Option<&Path>, rev: &str) -> Result<String> {
+        let context = || format!("{} ({})", GIT_REV_PARSE_FAILED_ERROR, rev);
+        let repo = open(cwd)?;
+        let commit = repo
+            .revparse_single(rev)
+            .and_then(|object| object.peel(ObjectType::Commit))
+            .with_context(context)?;
+
+        Ok(commit.id().to_string())
+    }
+
+    fn tags(&self, cwd: Option<&Path>) -> Result<Vec<String>> {
+        let tags = open(cwd)?
+            .tag_names(None)
+            .with_context(|| GIT_REV_PARSE_FAILED_ERROR)?;
+
+        Ok(tags.iter().flatten().map(str::to_owned).collect())
+    }
+
+    fn is_ancestor(&self, cwd: Option<&Path>, ancestor: &str, descendant: &str) -> Result<bool> {
+        let context = || {
+            format!(
+                "{} ({}..{})",
+                GIT_REV_PARSE_FAILED_ERROR, ancestor, descendant
+            )
+        };
+        let repo = open(cwd)?;
+        let commit = |rev: &str| {
+            repo.revparse_single(rev)
+                .and_then(|object| object.peel(ObjectType::Commit))
+                .map(|commit| commit.id())
+        };
+        let (ancestor, descendant) = (
+            commit(ancestor).with_context(context)?,
+            commit(descendant).with_context(context)?,
+        );
+
+        Ok(ancestor == descendant
+            || repo
+                .graph_descendant_of(descendant, ancestor)
+                .with_context(context)?)
+    }
+
+    fn changed_paths(&self, cwd: Option<&Path>, from: &str, to: &str) -> Result<Vec<String>> {
+        let context = || format!("{} ({}..{})", GIT_REV_PARSE_FAILED_ERROR, from, to);
+        let repo = open(cwd)?;
+        let tree = |rev: &str| {
+            repo.revparse_single(rev)
+                .and_then(|object| object.peel_to_tree())
+        };
+        let diff = repo
+            .diff_tree_to_tree(
+                Some(&tree(from).with_context(context)?),
+                Some(&tree(to).with_context(context)?),
+                None,
+            )
+            .with_context(context)?;
+
+        let mut paths = vec![];
+        for delta in diff.deltas() {
+            for file in [delta.old_file(), delta.new_file()] {
+                if let Some(path) = file.path().and_then(Path::to_str) {
+                    if !paths.iter().any(|known| known == path) {
+                        paths.push(path.to_owned());
+                    }
+                }
+            }
+        }
+
+        Ok(paths)
+    }
+
+    fn reset_hard(&self, cwd: Option<&Path>, commit: &str) -> Result<()> {
+        let context = || format!("{} ({})", GIT_RESET_FAILED_ERROR, commit);
+        let repo = open(cwd)?;
+
+        // libgit2 can't fetch the blobs a partial clone leaves out
+        if is_partial_clone(&repo) {
+            return CommandLine.reset_hard(cwd, commit);
+        }
+
+        let target = repo
+            .revparse_single(commit)
+            .and_then(|object| object.peel(ObjectType::Commit))
+            .with_context(context)?;
+
+        repo.reset(
+            &target,
+            ResetType::Hard,
+            Some(CheckoutBuilder::new().force()),
+        )
+        .with_context(context)
+    }
+}
+
+fn is_partial_clone(repo: &Repository) -> bool {
+    let config = match repo.config() {
+        Ok(config) => config,
+        Err(_) => return false,
+    };
+    if config.get_string("extensions.partialclone").is_ok() {
+        return true;
+    }
+
+    let mut promisor = false;
+    if let Ok(mut entries) = config.entries(Some(r"remote\..*\.promisor")) {
+        while let Some(Ok(entry)) = entries.next() {
+            promisor |= entry.value() == Some("true");
+        }
+    }
+    promisor
+}
+
+fn open(cwd: Option<&Path>) -> Result<Repository> {
+    let path = cwd.unwrap_or_else(|| Path::new("."));
+    Repository::discover(path)
+        .with_context(|| format!("{} ({})", NOT_A_REPOSITORY_ERROR, path.display()))
+}
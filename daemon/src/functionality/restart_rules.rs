@@ -0,0 +1,147 @@
+use {
+    super::constants::{INVALID_RESTART_RULE_GLOB_ERROR, MISSING_INSTALL_COMMAND_ERROR},
+    super::git_updater::glob_set,
+    super::types::{RestartAction, RestartRule},
+    anyhow::{bail, Result},
+    log::debug,
+};
+
+const DEFAULT_SIGNAL: &str = "SIGHUP";
+
+/// What a deploy does with the processes
+#[derive(Debug, PartialEq)]
+pub struct Plan {
+    pub action: RestartAction,
+    /// Signals to send for `signal`
+    pub signals: Vec<String>,
+    /// Commands to run before restarting for `reinstall-and-restart`
+    pub installs: Vec<String>,
+}
+
+/// Applies the rules to the files a deploy changed. Without rules, or without
+/// knowing which files changed, processes are restarted as usual.
+pub fn plan(rules: &[RestartRule], changed: Option<&[String]>) -> Result<Plan> {
+    let mut plan = Plan {
+        action: RestartAction::Restart,
+        signals: vec![],
+        installs: vec![],
+    };
+
+    let changed = match changed {
+        Some(changed) if !rules.is_empty() && !changed.is_empty() => changed,
+        _ => return Ok(plan),
+    };
+
+    let matchers = rules
+        .iter()
+        .map(|rule| glob_set(&rule.paths, INVALID_RESTART_RULE_GLOB_ERROR))
+        .collect::<Result<Vec<_>>>()?;
+
+    plan.action = RestartAction::None;
+    for path in changed {
+        let rule = matchers
+            .iter()
+            .position(|matcher| matcher.is_match(path))
+            .map(|i| &rules[i]);
+        debug!(
+            "Changed: {} (rule: {:?})",
+            path,
+            rule.map(|rule| rule.action)
+        );
+
+        let rule = match rule {
+            Some(rule) => rule,
+            None => {
+                plan.action = plan.action.max(RestartAction::Restart);
+                continue;
+            }
+        };
+
+        plan.action = plan.action.max(rule.action);
+        match rule.action {
+            RestartAction::Signal => push_new(
+                &mut plan.signals,
+                rule.signal.as_deref().unwrap_or(DEFAULT_SIGNAL),
+            ),
+            RestartAction::ReinstallAndRestart => match &rule.install {
+                Some(install) => push_new(&mut plan.installs, install),
+                None => bail!(
+                    "{} ({})",
+                    MISSING_INSTALL_COMMAND_ERROR,
+                    rule.paths.join(", ")
+                ),
+            },
+            RestartAction::None | RestartAction::Restart => {}
+        }
+    }
+
+    Ok(plan)
+}
+
+fn push_new(list: &mut Vec<String>, item: &str) {
+    if !list.iter().any(|known| known == item) {
+        list.push(item.to_owned());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(paths: &[&str], action: RestartAction) -> RestartRule {
+        RestartRule {
+            paths: paths.iter().map(|path| path.to_string()).collect(),
+            action,
+            signal: None,
+            install: None,
+        }
+    }
+
+    #[test]
+    fn plans_from_changed_files() {
+        let rules = vec![
+            rule(&["public/**", "*.md"], RestartAction::None),
+            rule(&["config/*.toml"], RestartAction::Signal),
+            RestartRule {
+                install: Some("npm ci".to_owned()),
+                ..rule(&["package*.json"], RestartAction::ReinstallAndRestart)
+            },
+        ];
+        let plan_for = |changed: &[&str]| {
+            let changed: Vec<_> = changed.iter().map(|path| path.to_string()).collect();
+            plan(&rules, Some(&changed)).unwrap()
+        };
+
+        assert_eq!(
+            plan_for(&["public/logo.svg", "README.md"]).action,
+            RestartAction::None
+        );
+        assert_eq!(
+            plan_for(&["README.md", "config/app.toml"]),
+            Plan {
+                action: RestartAction::Signal,
+                signals: vec!["SIGHUP".to_owned()],
+                installs: vec![],
+            }
+        );
+        assert_eq!(
+            plan_for(&["config/app.toml", "src/main.js"]).action,
+            RestartAction::Restart
+        );
+        assert_eq!(
+            plan_for(&["package.json", "package-lock.json", "public/app.css"]),
+            Plan {
+                action: RestartAction::ReinstallAndRestart,
+                signals: vec![],
+                installs: vec!["npm ci".to_owned()],
+            }
+        );
+
+        // Refreshes that change nothing, and unknown changes, restart as usual
+        assert_eq!(plan_for(&[]).action, RestartAction::Restart);
+        assert_eq!(plan(&rules, None).unwrap().action, RestartAction::Restart);
+
+        let no_install = vec![rule(&["package.json"], RestartAction::ReinstallAndRestart)];
+        assert!(plan(&no_install, Some(&["package.json".to_owned()])).is_err());
+    }
+}
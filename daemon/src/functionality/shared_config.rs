@@ -0,0 +1,203 @@
+use {
+    super::config_loader::{self, is_secret, ConfigOptions, REDACTED},
+    super::constants::{CONFIG_RELOAD_FAILED_WARN, PUBLIC_KEY_CHANGED_WARN, STAT_CONFIG_RELOADED},
+    super::types::Config,
+    log::{debug, error, info, warn},
+    serde_json::Value,
+    std::sync::{Arc, RwLock},
+};
+
+/// The config in use, re-read after each deploy. Readers get a snapshot that
+/// stays the same for the whole request, even if a reload swaps it meanwhile.
+#[derive(Clone)]
+pub struct SharedConfig {
+    options: ConfigOptions,
+    current: Arc<RwLock<Arc<Config>>>,
+}
+
+impl SharedConfig {
+    pub fn new(options: ConfigOptions, config: Config) -> Self {
+        SharedConfig {
+            options,
+            current: Arc::new(RwLock::new(Arc::new(config))),
+        }
+    }
+
+    pub fn get(&self) -> Arc<Config> {
+        let current = match self.current.read() {
+            Ok(current) => current,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+
+        current.clone()
+    }
+
+    /// Re-reads the config from the same sources it was first loaded from,
+    /// keeping the current one if the new one doesn't load
+    pub fn reload(&self) {
+        let new = match config_loader::load_config(&self.options) {
+            Ok(new) => new,
+            Err(e) => {
+                error!("{:#}", e);
+                warn!("{}", CONFIG_RELOAD_FAILED_WARN);
+                return;
+            }
+        };
+
+        let old = self.get();
+        let changes = diff(&old, &new);
+        if changes.is_empty() {
+            debug!("Config unchanged");
+            return;
+        }
+
+        info!("{}", STAT_CONFIG_RELOADED);
+        for change in &changes {
+            info!("    {}", change);
+        }
+        if old.public_key != new.public_key {
+            warn!("{}", PUBLIC_KEY_CHANGED_WARN);
+        }
+
+        let mut current = match self.current.write() {
+            Ok(current) => current,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        *current = Arc::new(new);
+    }
+}
+
+/// Lists changed keys as `key: old -> new`, with secrets redacted
+fn diff(old: &Config, new: &Config) -> Vec<String> {
+    let mut old_keys = Vec::new();
+    let mut new_keys = Vec::new();
+    flatten("", serde_json::to_value(old).unwrap(), &mut old_keys);
+    flatten("", serde_json::to_value(new).unwrap(), &mut new_keys);
+
+    let mut keys: Vec<&String> = old_keys.iter().chain(&new_keys).map(|(k, _)| k).collect();
+    keys.sort();
+    keys.dedup();
+
+    let lookup = |keys: &[(String, Value)], key: &str| {
+        keys.iter()
+            .find(|(k, _)| k == key)
+            .map_or(Value::Null, |(_, v)| v.clone())
+    };
+
+    keys.into_iter()
+        .filter_map(|key| {
+            let (old, new) = (lookup(&old_keys, key), lookup(&new_keys, key));
+            if old == new {
+                None
+            } else if is_secret(key) {
+                Some(format!("{}: {} changed", key, REDACTED))
+            } else {
+                Some(format!("{}: {} -> {}", key, old, new))
+            }
+        })
+        .collect()
+}
+
+fn flatten(prefix: &str, value: Value, keys: &mut Vec<(String, Value)>) {
+    match value {
+        Value::Object(table) => {
+            for (key, value) in table {
+                let key = if prefix.is_empty() {
+                    key
+                } else {
+                    format!("{}.{}", prefix, key)
+                };
+                flatten(&key, value, keys);
+            }
+        }
+        // Lists of tables, such as processes, are compared item by item
+        Value::Array(items) if !items.is_empty() && items.iter().all(Value::is_object) => {
+            for (i, item) in items.into_iter().enumerate() {
+                flatten(&format!("{}.{}", prefix, i), item, keys);
+            }
+        }
+        value => keys.push((prefix.to_owned(), value)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::types::{ProcessConfig, RestartPolicy},
+        std::{fs, path::Path},
+    };
+
+    #[test]
+    fn keeps_old_config_when_new_one_is_invalid() {
+        let dir = Path::new("test_repo_shared_config");
+        let _ = fs::remove_dir_all(dir);
+        fs::create_dir_all(dir).unwrap();
+        let path = dir.join("replit-deploy.json");
+        fs::write(&path, r#"{"endpoint":"https://old/refresh"}"#).unwrap();
+
+        let options = ConfigOptions {
+            path: Some(path.clone()),
+            ..Default::default()
+        };
+        let shared = SharedConfig::new(
+            options.clone(),
+            config_loader::load_config(&options).unwrap(),
+        );
+        let snapshot = shared.get();
+
+        fs::write(&path, r#"{"endpont":"https://typo/refresh"}"#).unwrap();
+        shared.reload();
+        assert_eq!(shared.get().endpoint, "https://old/refresh");
+
+        fs::write(&path, r#"{"endpoint":"https://new/refresh"}"#).unwrap();
+        shared.reload();
+        assert_eq!(shared.get().endpoint, "https://new/refresh");
+        assert_eq!(snapshot.endpoint, "https://old/refresh");
+    }
+
+    #[test]
+    fn diffs_changed_keys() {
+        let old = Config {
+            endpoint: "https://old/refresh".to_owned(),
+            ..Default::default()
+        };
+        let mut new = old.clone();
+        new.git.branch = "production".to_owned();
+        new.public_key = Some("relay.pub.pem".to_owned());
+
+        assert_eq!(
+            diff(&old, &new),
+            vec![
+                r#"git.branch: "main" -> "production""#,
+                r#"public_key: null -> "relay.pub.pem""#,
+            ]
+        );
+        assert!(diff(&old, &old).is_empty());
+
+        let mut secrets = old.clone();
+        secrets
+            .env
+            .insert("DATABASE_URL".to_owned(), "postgres://db/app".to_owned());
+        assert_eq!(
+            diff(&old, &secrets),
+            vec![format!("env.DATABASE_URL: {} changed", REDACTED)]
+        );
+
+        let mut restarted = new.clone();
+        restarted.processes = vec![ProcessConfig {
+            name: "worker".to_owned(),
+            command: "node".to_owned(),
+            args: vec![],
+            cwd: None,
+            env: Default::default(),
+            restart: RestartPolicy::Always,
+        }];
+        let mut never = restarted.clone();
+        never.processes[0].restart = RestartPolicy::Never;
+        assert_eq!(
+            diff(&restarted, &never),
+            vec![r#"processes.0.restart: "always" -> "never""#]
+        );
+    }
+}
@@ -1,25 +1,45 @@
 use {
     super::constants::{
         BAD_ENDPOINT_ERROR, BAD_PAYLOAD_ERROR, INVALID_SIGNATURE_ERROR, OK, PAYLOAD_TOO_OLD_ERROR,
+        PUBLIC_KEY_FILE_ERROR, PUBLIC_KEY_PARSE_ERROR, STAT_ENDPOINT_MATCHED,
     },
     super::types::{Config, Payload, ValidationResult},
-    anyhow::Result,
-    base64,
+    anyhow::{Context, Result},
+    log::info,
     rsa::{hash, PaddingScheme, PublicKey, RSAPublicKey},
-    serde_json,
     sha2::{Digest, Sha256},
-    std::time::{SystemTime, UNIX_EPOCH},
+    std::{
+        convert::TryFrom,
+        fs,
+        path::Path,
+        time::{SystemTime, UNIX_EPOCH},
+    },
+    url::Url,
     warp::http::StatusCode,
 };
 
+/// Reads a PEM encoded RSA public key, or returns repl.deploy's own key
+pub fn load_public_key(path: Option<&Path>) -> Result<RSAPublicKey> {
+    match path {
+        Some(path) => {
+            let context = || format!("{} ({})", PUBLIC_KEY_FILE_ERROR, path.display());
+            let pem = fs::read_to_string(path).with_context(context)?;
+            let pem = pem::parse(pem).with_context(context)?;
+            RSAPublicKey::try_from(pem).with_context(context)
+        }
+        None => RSAPublicKey::from_pkcs1(crate::REPL_DEPLOY_PUBLIC_KEY)
+            .with_context(|| PUBLIC_KEY_PARSE_ERROR),
+    }
+}
+
 pub fn validate_payload_and_signature(
     payload: &[u8],
     signature: &str,
     config: &Config,
     public_key: &RSAPublicKey,
 ) -> Result<ValidationResult, ValidationResult> {
-    validate_payload(&payload, &config)?;
-    validate_signature(&payload, &signature, &public_key)
+    validate_payload(payload, config)?;
+    validate_signature(payload, signature, public_key)
 }
 
 fn validate_payload(body: &[u8], config: &Config) -> Result<ValidationResult, ValidationResult> {
@@ -40,12 +60,15 @@ fn validate_payload(body: &[u8], config: &Config) -> Result<ValidationResult, Va
         });
     };
 
-    if config.endpoint != payload.endpoint {
-        return Err(ValidationResult {
-            body: BAD_ENDPOINT_ERROR,
-            status: StatusCode::FORBIDDEN,
-        });
-    };
+    match matching_endpoint(config, &payload.endpoint) {
+        Some(endpoint) => info!("{} {}", STAT_ENDPOINT_MATCHED, endpoint),
+        None => {
+            return Err(ValidationResult {
+                body: BAD_ENDPOINT_ERROR,
+                status: StatusCode::FORBIDDEN,
+            });
+        }
+    }
 
     Ok(ValidationResult {
         body: OK,
@@ -53,6 +76,38 @@ fn validate_payload(body: &[u8], config: &Config) -> Result<ValidationResult, Va
     })
 }
 
+/// The configured endpoint that a payload's endpoint refers to, if any
+pub fn matching_endpoint<'a>(config: &'a Config, endpoint: &str) -> Option<&'a str> {
+    let endpoint = normalize_endpoint(endpoint);
+
+    config
+        .accepted_endpoints()
+        .find(|accepted| normalize_endpoint(accepted) == endpoint)
+        .map(String::as_str)
+}
+
+/// Lowercases the scheme and host and drops default ports and trailing
+/// slashes, so that equivalent spellings of a URL compare equal
+fn normalize_endpoint(endpoint: &str) -> String {
+    let endpoint = endpoint.trim();
+
+    match Url::parse(endpoint) {
+        Ok(url) => format!(
+            "{}://{}{}{}{}",
+            url.scheme(),
+            url.host_str().unwrap_or_default(),
+            url.port()
+                .map(|port| format!(":{}", port))
+                .unwrap_or_default(),
+            url.path().trim_end_matches('/'),
+            url.query()
+                .map(|query| format!("?{}", query))
+                .unwrap_or_default()
+        ),
+        Err(_) => endpoint.trim_end_matches('/').to_owned(),
+    }
+}
+
 fn validate_signature(
     body: &[u8],
     signature: &str,
@@ -94,12 +149,19 @@ fn validate_signature(
     })
 }
 
+fn is_older_than_fifteen_seconds(ts: u128) -> bool {
+    ts < (SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis()
+        - 15000)
+}
+
 #[cfg(test)]
 mod tests {
     use {
         super::*,
-        rsa::{hash::Hash, PaddingScheme, RSAPrivateKey},
-        serde_json,
+        rsa::RSAPrivateKey,
         std::time::{SystemTime, UNIX_EPOCH},
     };
 
@@ -110,6 +172,7 @@ mod tests {
         let correct_payload = serde_json::to_vec(&Payload {
             timestamp: now_ms(),
             endpoint: TEST_ENDPOINT.to_owned(),
+            commit: None,
         })
         .unwrap();
 
@@ -117,6 +180,7 @@ mod tests {
             &correct_payload,
             &Config {
                 endpoint: TEST_ENDPOINT.to_owned(),
+                ..Default::default()
             },
         );
 
@@ -131,6 +195,7 @@ mod tests {
             invalid_json_payload,
             &Config {
                 endpoint: TEST_ENDPOINT.to_owned(),
+                ..Default::default()
             },
         );
 
@@ -142,6 +207,7 @@ mod tests {
         let old_payload = serde_json::to_vec(&Payload {
             timestamp: now_ms() - 20000,
             endpoint: TEST_ENDPOINT.to_owned(),
+            commit: None,
         })
         .unwrap();
 
@@ -149,6 +215,7 @@ mod tests {
             &old_payload,
             &Config {
                 endpoint: TEST_ENDPOINT.to_owned(),
+                ..Default::default()
             },
         );
 
@@ -160,6 +227,7 @@ mod tests {
         let mismatch_payload = serde_json::to_vec(&Payload {
             timestamp: now_ms(),
             endpoint: TEST_ENDPOINT.to_owned(),
+            commit: None,
         })
         .unwrap();
 
@@ -167,12 +235,39 @@ mod tests {
             &mismatch_payload,
             &Config {
                 endpoint: "https://endpoint.bad-example.com/".to_owned(),
+                ..Default::default()
             },
         );
 
         assert!(result.is_err());
     }
 
+    #[test]
+    fn matches_equivalent_endpoints() {
+        let config = Config {
+            endpoint: "https://app.user.repl.co/refresh".to_owned(),
+            endpoints: vec!["https://Deploy.Example.com:443/refresh/".to_owned()],
+            ..Default::default()
+        };
+
+        assert_eq!(
+            matching_endpoint(&config, "HTTPS://APP.user.repl.co/refresh/"),
+            Some("https://app.user.repl.co/refresh")
+        );
+        assert_eq!(
+            matching_endpoint(&config, "https://deploy.example.com/refresh"),
+            Some("https://Deploy.Example.com:443/refresh/")
+        );
+        assert_eq!(
+            matching_endpoint(&config, "https://deploy.example.com:8443/refresh"),
+            None
+        );
+        assert_eq!(
+            matching_endpoint(&config, "https://app.user.repl.co/REFRESH"),
+            None
+        );
+    }
+
     #[test]
     fn correct_signature() {
         const SAMPLE_BODY: &[u8] = "signature-body-test".as_bytes();
@@ -198,6 +293,7 @@ mod tests {
         let payload = serde_json::to_vec(&Payload {
             timestamp: now_ms(),
             endpoint: TEST_ENDPOINT.to_owned(),
+            commit: None,
         })
         .unwrap();
 
@@ -208,6 +304,7 @@ mod tests {
             &signature,
             &Config {
                 endpoint: TEST_ENDPOINT.to_owned(),
+                ..Default::default()
             },
             &pub_key,
         );
@@ -228,16 +325,7 @@ mod tests {
     }
 
     fn sign_and_hash(body: &[u8], priv_key: &RSAPrivateKey) -> String {
-        base64::encode(
-            priv_key
-                .sign(
-                    PaddingScheme::PKCS1v15Sign {
-                        hash: Some(Hash::SHA2_256),
-                    },
-                    &hash(body),
-                )
-                .expect("signing request failed"),
-        )
+        crate::signer::sign(body, priv_key).expect("signing request failed")
     }
 
     fn now_ms() -> u128 {
@@ -246,18 +334,4 @@ mod tests {
             .unwrap()
             .as_millis()
     }
-
-    fn hash(body: &[u8]) -> Vec<u8> {
-        let mut hasher = Sha256::new();
-        hasher.update(body);
-        Vec::from(hasher.finalize().as_slice())
-    }
-}
-
-fn is_older_than_fifteen_seconds(ts: u128) -> bool {
-    ts < (SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap()
-        .as_millis()
-        - 15000)
 }
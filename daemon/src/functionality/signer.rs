@@ -0,0 +1,91 @@
+use {
+    super::constants::{PRIVATE_KEY_PARSE_ERROR, SIGNING_FAILED_ERROR},
+    anyhow::{Context, Result},
+    rsa::{hash, PaddingScheme, RSAPrivateKey},
+    sha2::{Digest, Sha256},
+    std::{convert::TryFrom, fs, path::Path},
+};
+
+/// Reads a PKCS#1 or PKCS#8 PEM encoded RSA private key
+pub fn load_private_key(path: &Path) -> Result<RSAPrivateKey> {
+    let pem = fs::read_to_string(path)
+        .with_context(|| format!("{} ({})", PRIVATE_KEY_PARSE_ERROR, path.display()))?;
+
+    parse_private_key(&pem)
+}
+
+pub fn parse_private_key(pem: &str) -> Result<RSAPrivateKey> {
+    let pem = pem::parse(pem).with_context(|| PRIVATE_KEY_PARSE_ERROR)?;
+    RSAPrivateKey::try_from(pem).with_context(|| PRIVATE_KEY_PARSE_ERROR)
+}
+
+/// Signs the SHA-256 hash of the body, returning a base64 encoded signature as
+/// expected by the `Signature` header
+pub fn sign(body: &[u8], key: &RSAPrivateKey) -> Result<String> {
+    let mut hasher = Sha256::new();
+    hasher.update(body);
+    let hashed = hasher.finalize();
+
+    let signature = key
+        .sign(
+            PaddingScheme::PKCS1v15Sign {
+                hash: Some(hash::Hash::SHA2_256),
+            },
+            hashed.as_slice(),
+        )
+        .with_context(|| SIGNING_FAILED_ERROR)?;
+
+    Ok(base64::encode(signature))
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::{
+            signature_verifier::validate_payload_and_signature,
+            types::{Config, Payload},
+        },
+        rsa::{PrivateKeyPemEncoding, RSAPublicKey},
+        std::time::{SystemTime, UNIX_EPOCH},
+    };
+
+    const TEST_ENDPOINT: &str = "https://endpoint.example.com/";
+
+    #[test]
+    fn signed_payload_is_accepted() {
+        use rand::rngs::OsRng;
+        let generated = RSAPrivateKey::new(&mut OsRng, 2048).expect("Failed to generate key");
+        let key = parse_private_key(&generated.to_pem_pkcs1().unwrap())
+            .expect("Failed to parse generated key");
+
+        let payload = serde_json::to_vec(&Payload {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_millis(),
+            endpoint: TEST_ENDPOINT.to_owned(),
+            commit: None,
+        })
+        .unwrap();
+
+        let signature = sign(&payload, &key).expect("Failed to sign payload");
+
+        let result = validate_payload_and_signature(
+            &payload,
+            &signature,
+            &Config {
+                endpoint: TEST_ENDPOINT.to_owned(),
+                ..Default::default()
+            },
+            &RSAPublicKey::from(&generated),
+        );
+
+        assert!(result.is_ok(), "{:#}", result.unwrap_err());
+    }
+
+    #[test]
+    fn rejects_invalid_keys() {
+        assert!(parse_private_key("not a key").is_err());
+    }
+}
@@ -0,0 +1,631 @@
+use {
+    super::child_env,
+    super::constants::{
+        DUPLICATE_PROCESS_NAME_ERROR, FAILED_TO_KILL_CHILD_PROCESS_ERROR,
+        FAILED_TO_START_CHILD_PROCESS_ERROR, INVALID_PROCESSES_WARN, NO_PROCESSES_ERROR,
+        SIGNAL_FAILED_ERROR, STAT_PROCESS_EXITED, STAT_PROGRAM_STARTED, UNKNOWN_PROCESS_ERROR,
+    },
+    super::types::{Config, ProcessConfig, RestartPolicy},
+    anyhow::{bail, Context, Result},
+    log::{error, info, warn},
+    std::{
+        collections::BTreeMap,
+        io::{self, BufRead, BufReader, Read, Write},
+        path::{Path, PathBuf},
+        process::{Child, ChildStdin, ChildStdout, Command, Stdio},
+        sync::{Arc, Mutex, MutexGuard},
+        thread,
+        time::Duration,
+    },
+};
+
+// How often exited processes are checked for
+const MONITOR_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Name of the process started from the command passed to `run`
+pub const MAIN_PROCESS: &str = "main";
+
+/// Pipes to the process whose output is scanned in stdio mode
+pub struct ChildPipes {
+    pub stdin: ChildStdin,
+    pub stdout: ChildStdout,
+}
+
+/// Runs the command passed to `run` together with the processes declared in
+/// the config. Output is prefixed with the process name when there's more
+/// than one, or with the app and process name for apps.
+pub struct Supervisor {
+    main: Option<ProcessConfig>,
+    /// Set when supervising one of several apps
+    app: Option<String>,
+    /// Directory process `cwd`s are relative to
+    root: PathBuf,
+    /// The config's `env` and `env_file`
+    env: BTreeMap<String, String>,
+    env_file: Option<String>,
+    /// `REPL_DEPLOY_*` variables for the last deploy
+    metadata: BTreeMap<String, String>,
+    /// The process piped for the stdio event handler, if any
+    stdio_process: Option<String>,
+    processes: Vec<Process>,
+}
+
+struct Process {
+    config: ProcessConfig,
+    child: Option<Child>,
+    pipes: Option<ChildPipes>,
+}
+
+impl Supervisor {
+    /// With `stdio`, the main process (or the first declared one) gets piped
+    /// stdin and stdout for the stdio event handler
+    pub fn new(main: Option<ProcessConfig>, stdio: bool, config: &Config) -> Result<Self> {
+        let mut supervisor = Supervisor {
+            main,
+            app: None,
+            root: PathBuf::from("."),
+            env: config.env.clone(),
+            env_file: config.env_file.clone(),
+            metadata: BTreeMap::new(),
+            stdio_process: None,
+            processes: vec![],
+        };
+
+        supervisor.processes = supervisor.plan(config)?;
+        if stdio {
+            supervisor.stdio_process = supervisor
+                .main
+                .as_ref()
+                .or_else(|| config.processes.first())
+                .map(|process| process.name.clone());
+        }
+
+        Ok(supervisor)
+    }
+
+    /// Runs the processes in `root`, prefixing their output with the app name
+    pub fn for_app(mut self, name: &str, root: &Path) -> Self {
+        self.app = Some(name.to_owned());
+        self.root = root.to_owned();
+        self
+    }
+
+    pub fn start(&mut self) -> Result<()> {
+        let env_file = self.read_env_file(self.env_file.as_deref())?;
+        self.start_with(env_file)
+    }
+
+    /// Starts every process, even when one of them fails to
+    fn start_with(&mut self, env_file: BTreeMap<String, String>) -> Result<()> {
+        self.metadata = child_env::deploy_metadata(&self.root, self.app.as_deref());
+        let env = self.layer_env(env_file);
+        let mut errors = vec![];
+
+        for i in 0..self.processes.len() {
+            let label = self.label(&self.processes[i].config.name);
+            let piped = self.stdio_process.as_ref() == Some(&self.processes[i].config.name);
+            if let Err(e) = self.processes[i].start(label.as_deref(), &self.root, &env, piped) {
+                errors.push(e);
+            }
+        }
+
+        combine(errors)
+    }
+
+    /// Variables for every process: deploy metadata, then `env`, then the
+    /// `env_file`, read again each time
+    fn child_env(&self) -> Result<BTreeMap<String, String>> {
+        let env_file = self.read_env_file(self.env_file.as_deref())?;
+        Ok(self.layer_env(env_file))
+    }
+
+    fn layer_env(&self, env_file: BTreeMap<String, String>) -> BTreeMap<String, String> {
+        let mut env = self.metadata.clone();
+        env.extend(self.env.clone());
+        env.extend(env_file);
+        env
+    }
+
+    fn read_env_file(&self, env_file: Option<&str>) -> Result<BTreeMap<String, String>> {
+        match env_file {
+            Some(env_file) => child_env::read_env_file(&self.root, env_file),
+            None => Ok(BTreeMap::new()),
+        }
+    }
+
+    /// The prefix for a process's output, if it needs one
+    fn label(&self, name: &str) -> Option<String> {
+        match &self.app {
+            Some(app) => Some(format!("{}/{}", app, name)),
+            None if self.processes.len() > 1 => Some(name.to_owned()),
+            None => None,
+        }
+    }
+
+    /// Stops every process in reverse restart order, then starts the ones
+    /// declared in `config` in order. Nothing is stopped when the new
+    /// `env_file` can't be read, and a process that fails to stop or start
+    /// doesn't keep the others down.
+    pub fn restart(&mut self, config: &Config) -> Result<()> {
+        let env_file = self.read_env_file(config.env_file.as_deref())?;
+        let planned = match self.plan(config) {
+            Ok(planned) => Some(planned),
+            Err(e) => {
+                error!("{:#}", e);
+                warn!("{}", INVALID_PROCESSES_WARN);
+                None
+            }
+        };
+
+        let mut errors = vec![];
+        for process in self.processes.iter_mut().rev() {
+            if let Err(e) = process.stop() {
+                errors.push(e.context(process.config.name.clone()));
+            }
+        }
+
+        if let Some(planned) = planned {
+            self.processes = planned;
+        }
+        self.env = config.env.clone();
+        self.env_file = config.env_file.clone();
+
+        if let Err(e) = self.start_with(env_file) {
+            errors.push(e);
+        }
+        combine(errors)
+    }
+
+    /// Sends a signal such as `SIGHUP` to every running process
+    pub fn signal(&mut self, signal: &str) -> Result<()> {
+        let signal = signal.trim_start_matches("SIG");
+
+        for process in &mut self.processes {
+            let child = match process.child.as_mut() {
+                Some(child) => child,
+                None => continue,
+            };
+            if !matches!(child.try_wait(), Ok(None)) {
+                continue;
+            }
+
+            let status = Command::new("kill")
+                .args(["-s", signal, &child.id().to_string()])
+                .status()
+                .with_context(|| SIGNAL_FAILED_ERROR)?;
+            if !status.success() {
+                bail!(
+                    "{} ({}, SIG{})",
+                    SIGNAL_FAILED_ERROR,
+                    process.config.name,
+                    signal
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// A shell command run from the repository root with the processes'
+    /// environment, such as an install step
+    pub fn shell(&self, command: &str) -> Result<Command> {
+        let mut shell = Command::new("sh");
+        shell
+            .args(["-c", command])
+            .envs(self.child_env()?)
+            .current_dir(&self.root);
+        Ok(shell)
+    }
+
+    pub fn take_pipes(&mut self) -> Option<ChildPipes> {
+        let stdio_process = self.stdio_process.as_ref()?;
+
+        self.processes
+            .iter_mut()
+            .find(|process| &process.config.name == stdio_process)?
+            .pipes
+            .take()
+    }
+
+    /// Restarts processes that exited by themselves, as their restart policy
+    /// says. The stdio process is left to the stdio event handler.
+    fn restart_exited(&mut self) {
+        let mut env = None;
+
+        for i in 0..self.processes.len() {
+            let label = self.label(&self.processes[i].config.name);
+            let process = &mut self.processes[i];
+            let status = match process.child.as_mut().map(Child::try_wait) {
+                Some(Ok(Some(status))) => status,
+                _ => continue,
+            };
+
+            process.child = None;
+            info!(
+                "{} ({}, {})",
+                STAT_PROCESS_EXITED,
+                label.as_deref().unwrap_or(&process.config.name),
+                status
+            );
+
+            let restart = match process.config.restart {
+                RestartPolicy::Never => false,
+                RestartPolicy::OnFailure => !status.success(),
+                RestartPolicy::Always => true,
+            };
+
+            if restart && self.stdio_process.as_ref() != Some(&process.config.name) {
+                let env = match env.get_or_insert_with(|| self.child_env()) {
+                    Ok(env) => env,
+                    Err(e) => {
+                        error!("{:#}", e);
+                        continue;
+                    }
+                };
+                let process = &mut self.processes[i];
+                if let Err(e) = process.start(label.as_deref(), &self.root, env, false) {
+                    error!("{:#}", e);
+                }
+            }
+        }
+    }
+
+    /// Processes in restart order
+    fn plan(&self, config: &Config) -> Result<Vec<Process>> {
+        let mut processes: Vec<_> = self.main.iter().chain(&config.processes).cloned().collect();
+
+        if processes.is_empty() {
+            bail!(NO_PROCESSES_ERROR);
+        }
+
+        for (i, process) in processes.iter().enumerate() {
+            if processes[..i]
+                .iter()
+                .any(|other| other.name == process.name)
+            {
+                bail!("{}: {}", DUPLICATE_PROCESS_NAME_ERROR, process.name);
+            }
+        }
+
+        for name in &config.restart_order {
+            if !processes.iter().any(|process| &process.name == name) {
+                bail!("{}: {}", UNKNOWN_PROCESS_ERROR, name);
+            }
+        }
+
+        // Stable, so processes that aren't listed keep their declared order
+        processes.sort_by_key(|process| {
+            config
+                .restart_order
+                .iter()
+                .position(|name| name == &process.name)
+                .unwrap_or(usize::MAX)
+        });
+
+        Ok(processes
+            .into_iter()
+            .map(|config| Process {
+                config,
+                child: None,
+                pipes: None,
+            })
+            .collect())
+    }
+}
+
+/// Applies restart policies in the background
+pub fn monitor(supervisor: Arc<Mutex<Supervisor>>) {
+    thread::spawn(move || loop {
+        thread::sleep(MONITOR_INTERVAL);
+        lock(&supervisor).restart_exited();
+    });
+}
+
+/// Locks the supervisor, carrying on if a restart panicked while holding it
+pub fn lock(supervisor: &Mutex<Supervisor>) -> MutexGuard<'_, Supervisor> {
+    match supervisor.lock() {
+        Ok(supervisor) => supervisor,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+impl Process {
+    fn start(
+        &mut self,
+        label: Option<&str>,
+        root: &Path,
+        env: &BTreeMap<String, String>,
+        piped: bool,
+    ) -> Result<()> {
+        let config = &self.config;
+        let prefix_output = label.is_some();
+        let mut command = Command::new(&config.command);
+        command
+            .args(&config.args)
+            .envs(env)
+            .envs(&config.env)
+            .current_dir(root.join(config.cwd.as_deref().unwrap_or(".")));
+
+        if piped {
+            command.stdin(Stdio::piped()).stdout(Stdio::piped());
+        } else if prefix_output {
+            command.stdout(Stdio::piped());
+        }
+        if prefix_output {
+            command.stderr(Stdio::piped());
+        }
+
+        let mut child = command.spawn().with_context(|| {
+            format!("{} ({})", FAILED_TO_START_CHILD_PROCESS_ERROR, config.name)
+        })?;
+
+        if let Some(label) = label {
+            if let Some(stdout) = (!piped).then(|| child.stdout.take()).flatten() {
+                forward_output(label, stdout, io::stdout());
+            }
+            if let Some(stderr) = child.stderr.take() {
+                forward_output(label, stderr, io::stderr());
+            }
+        }
+        if piped {
+            self.pipes = child
+                .stdin
+                .take()
+                .zip(child.stdout.take())
+                .map(|(stdin, stdout)| ChildPipes { stdin, stdout });
+        }
+
+        if let Some(label) = label {
+            info!("{} ({})", STAT_PROGRAM_STARTED, label);
+        } else {
+            info!("{}", STAT_PROGRAM_STARTED);
+        }
+
+        self.child = Some(child);
+        Ok(())
+    }
+
+    fn stop(&mut self) -> Result<()> {
+        self.pipes = None;
+
+        if let Some(mut child) = self.child.take() {
+            if child.try_wait()?.is_none() {
+                child
+                    .kill()
+                    .with_context(|| FAILED_TO_KILL_CHILD_PROCESS_ERROR)?;
+            }
+            child.wait()?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Fails with every error at once, if there are any
+fn combine(errors: Vec<anyhow::Error>) -> Result<()> {
+    if errors.is_empty() {
+        return Ok(());
+    }
+
+    let errors: Vec<_> = errors.iter().map(|e| format!("{:#}", e)).collect();
+    bail!("{}", errors.join("; "))
+}
+
+fn forward_output(
+    name: &str,
+    output: impl Read + Send + 'static,
+    mut to: impl Write + Send + 'static,
+) {
+    let prefix = format!("[{}] ", name);
+
+    thread::spawn(move || {
+        for line in BufReader::new(output).lines().map_while(|line| line.ok()) {
+            let _ = writeln!(to, "{}{}", prefix, line);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, std::fs};
+
+    fn process(name: &str) -> ProcessConfig {
+        ProcessConfig {
+            name: name.to_owned(),
+            command: "true".to_owned(),
+            args: vec![],
+            cwd: None,
+            env: Default::default(),
+            restart: RestartPolicy::Never,
+        }
+    }
+
+    fn names(supervisor: &Supervisor) -> Vec<&str> {
+        supervisor
+            .processes
+            .iter()
+            .map(|process| process.config.name.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn orders_processes() {
+        let config = Config {
+            processes: vec![process("web"), process("worker"), process("cron")],
+            restart_order: vec!["worker".to_owned()],
+            ..Default::default()
+        };
+
+        let supervisor = Supervisor::new(Some(process(MAIN_PROCESS)), true, &config).unwrap();
+        assert_eq!(names(&supervisor), vec!["worker", "main", "web", "cron"]);
+        assert_eq!(supervisor.stdio_process.as_deref(), Some(MAIN_PROCESS));
+
+        let supervisor = Supervisor::new(None, true, &config).unwrap();
+        assert_eq!(supervisor.stdio_process.as_deref(), Some("web"));
+    }
+
+    #[test]
+    fn rejects_invalid_processes() {
+        let duplicate = Config {
+            processes: vec![process("web"), process("web")],
+            ..Default::default()
+        };
+        assert!(Supervisor::new(None, false, &duplicate).is_err());
+
+        let unknown = Config {
+            processes: vec![process("web")],
+            restart_order: vec!["wrker".to_owned()],
+            ..Default::default()
+        };
+        assert!(Supervisor::new(None, false, &unknown).is_err());
+
+        assert!(Supervisor::new(None, false, &Config::default()).is_err());
+    }
+
+    #[test]
+    fn layers_child_env() {
+        let root = Path::new("test_repo_child_env");
+        let _ = fs::remove_dir_all(root);
+        fs::create_dir_all(root).unwrap();
+        fs::write(root.join(".env"), "SHARED=file\nSECRET=hunter2\n").unwrap();
+
+        let config = Config {
+            processes: vec![process("web")],
+            env: [("SHARED", "config"), ("PLAIN", "config")]
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect(),
+            env_file: Some(".env".to_owned()),
+            ..Default::default()
+        };
+        let mut supervisor = Supervisor::new(None, false, &config)
+            .unwrap()
+            .for_app("shop", root);
+        supervisor.metadata = child_env::deploy_metadata(root, Some("shop"));
+
+        let env = supervisor.child_env().unwrap();
+        assert_eq!(env["SHARED"], "file");
+        assert_eq!(env["PLAIN"], "config");
+        assert_eq!(env["SECRET"], "hunter2");
+        assert_eq!(env["REPL_DEPLOY_APP"], "shop");
+        assert!(env.contains_key("REPL_DEPLOY_DEPLOYED_AT"));
+    }
+
+    #[test]
+    fn keeps_running_without_the_env_file() {
+        let root = Path::new("test_repo_child_env_missing");
+        let _ = fs::remove_dir_all(root);
+        fs::create_dir_all(root).unwrap();
+        fs::write(root.join(".env"), "SECRET=hunter2\n").unwrap();
+
+        let mut running = process("web");
+        running.command = "sleep".to_owned();
+        running.args = vec!["5".to_owned()];
+        let config = Config {
+            processes: vec![running],
+            env_file: Some(".env".to_owned()),
+            ..Default::default()
+        };
+        let mut supervisor = Supervisor::new(None, false, &config)
+            .unwrap()
+            .for_app("shop", root);
+        supervisor.start().unwrap();
+
+        fs::remove_file(root.join(".env")).unwrap();
+        assert!(supervisor.restart(&config).is_err());
+        assert!(supervisor.shell("true").is_err());
+        let child = supervisor.processes[0].child.as_mut().unwrap();
+        assert!(matches!(child.try_wait(), Ok(None)));
+
+        supervisor.processes[0].stop().unwrap();
+        assert!(supervisor.start().is_err());
+        assert!(supervisor.processes[0].child.is_none());
+    }
+
+    #[test]
+    fn restarts_by_policy() {
+        let mut failing = process("failing");
+        failing.command = "false".to_owned();
+        failing.restart = RestartPolicy::OnFailure;
+        let mut succeeding = process("succeeding");
+        succeeding.restart = RestartPolicy::OnFailure;
+
+        let config = Config {
+            processes: vec![failing, succeeding],
+            ..Default::default()
+        };
+        let mut supervisor = Supervisor::new(None, false, &config).unwrap();
+        supervisor.start().unwrap();
+
+        // Both exit right away
+        thread::sleep(Duration::from_millis(500));
+        supervisor.restart_exited();
+
+        assert!(supervisor.processes[0].child.is_some());
+        assert!(supervisor.processes[1].child.is_none());
+    }
+
+    #[test]
+    fn restarts_the_others_when_one_fails() {
+        let mut missing = process("missing");
+        missing.command = "./does-not-exist".to_owned();
+        let mut running = process("running");
+        running.command = "sleep".to_owned();
+        running.args = vec!["5".to_owned()];
+
+        let config = Config {
+            processes: vec![missing, running],
+            ..Default::default()
+        };
+        let mut supervisor = Supervisor::new(None, false, &config).unwrap();
+        assert!(supervisor.start().is_err());
+        assert!(supervisor.processes[1].child.is_some());
+
+        let e = supervisor.restart(&config).unwrap_err();
+        assert!(e.to_string().contains(FAILED_TO_START_CHILD_PROCESS_ERROR));
+        let child = supervisor.processes[1].child.as_mut().unwrap();
+        assert!(matches!(child.try_wait(), Ok(None)));
+        supervisor.processes[1].stop().unwrap();
+    }
+
+    #[test]
+    fn signals_and_runs_commands() {
+        let mut reloading = process("reloading");
+        reloading.command = "sh".to_owned();
+        reloading.args = vec![
+            "-c".to_owned(),
+            "trap 'exit 3' HUP; while true; do sleep 0.1; done".to_owned(),
+        ];
+
+        let config = Config {
+            processes: vec![reloading],
+            env: [("PLAIN".to_owned(), "config".to_owned())].into(),
+            ..Default::default()
+        };
+        let mut supervisor = Supervisor::new(None, false, &config).unwrap();
+        supervisor.start().unwrap();
+
+        thread::sleep(Duration::from_millis(300));
+        supervisor.signal("SIGHUP").unwrap();
+        let status = supervisor.processes[0]
+            .child
+            .as_mut()
+            .unwrap()
+            .wait()
+            .unwrap();
+        assert_eq!(status.code(), Some(3));
+
+        let install = supervisor
+            .shell("test \"$PLAIN\" = config")
+            .unwrap()
+            .status()
+            .unwrap();
+        assert!(install.success());
+        assert!(!supervisor
+            .shell("exit 1")
+            .unwrap()
+            .status()
+            .unwrap()
+            .success());
+    }
+}
@@ -0,0 +1,165 @@
+use {
+    super::constants::{BAD_WEBHOOK_ERROR, BAD_WEBHOOK_SECRET_ERROR},
+    anyhow::{bail, Context, Result},
+    hmac::{Hmac, Mac, NewMac},
+    serde::Deserialize,
+    sha2::Sha256,
+};
+
+/// A push to a branch, as sent by GitHub, GitLab, Gitea or Forgejo
+#[derive(Debug, PartialEq)]
+pub struct PushEvent {
+    pub git_ref: String,
+    pub commit: String,
+}
+
+// GitHub, Gitea and Forgejo send `after`, GitLab sends `checkout_sha`
+#[derive(Deserialize)]
+struct RawPushEvent {
+    #[serde(rename = "ref")]
+    git_ref: Option<String>,
+    after: Option<String>,
+    checkout_sha: Option<String>,
+}
+
+/// Returns `None` for events that aren't pushes (e.g. pings) and for pushes
+/// that delete a branch
+pub fn parse_push_event(body: &[u8]) -> Result<Option<PushEvent>> {
+    let raw: RawPushEvent = serde_json::from_slice(body).with_context(|| BAD_WEBHOOK_ERROR)?;
+
+    let commit = raw
+        .checkout_sha
+        .or(raw.after)
+        .filter(|commit| commit.chars().any(|c| c != '0'));
+
+    Ok(match (raw.git_ref, commit) {
+        (Some(git_ref), Some(commit)) => Some(PushEvent { git_ref, commit }),
+        _ => None,
+    })
+}
+
+/// Header values a webhook may be authenticated with, if sent
+#[derive(Default)]
+pub struct WebhookAuth {
+    /// GitHub's `X-Hub-Signature-256`, i.e. `sha256=<hex HMAC>`
+    pub hub_signature: Option<String>,
+    /// Gitea and Forgejo's `X-Gitea-Signature`, i.e. `<hex HMAC>`
+    pub gitea_signature: Option<String>,
+    /// GitLab's `X-Gitlab-Token`, the secret itself
+    pub gitlab_token: Option<String>,
+}
+
+pub fn verify_secret(body: &[u8], secret: &str, auth: &WebhookAuth) -> Result<()> {
+    let hmac_hex = auth
+        .hub_signature
+        .as_deref()
+        .and_then(|signature| signature.strip_prefix("sha256="))
+        .or(auth.gitea_signature.as_deref());
+
+    let valid = match (hmac_hex, auth.gitlab_token.as_deref()) {
+        (Some(hmac_hex), _) => match decode_hex(hmac_hex) {
+            Some(expected) => {
+                let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+                mac.update(body);
+                mac.verify(&expected).is_ok()
+            }
+            None => false,
+        },
+        (None, Some(token)) => constant_time_eq(token.as_bytes(), secret.as_bytes()),
+        (None, None) => false,
+    };
+
+    if !valid {
+        bail!(BAD_WEBHOOK_SECRET_ERROR);
+    }
+
+    Ok(())
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "It's a Secret to Everybody";
+    const BODY: &[u8] = b"Hello, World!";
+
+    #[test]
+    fn parses_github_push() {
+        let body = br#"{"ref":"refs/heads/main","after":"6113728f27ae82c7b1a177c8d03f9e96e0adf246","deleted":false}"#;
+        assert_eq!(
+            parse_push_event(body).unwrap(),
+            Some(PushEvent {
+                git_ref: "refs/heads/main".to_owned(),
+                commit: "6113728f27ae82c7b1a177c8d03f9e96e0adf246".to_owned(),
+            })
+        );
+    }
+
+    #[test]
+    fn parses_gitlab_push() {
+        let body = br#"{"object_kind":"push","ref":"refs/heads/main","before":"95790bf891e76fee5e1747ab589903a6a1f80f22","after":"da1560886d4f094c3e6c9ef40349f7d38b5d27d7","checkout_sha":"da1560886d4f094c3e6c9ef40349f7d38b5d27d7"}"#;
+        assert_eq!(
+            parse_push_event(body).unwrap().unwrap().commit,
+            "da1560886d4f094c3e6c9ef40349f7d38b5d27d7"
+        );
+    }
+
+    #[test]
+    fn ignores_pings_and_deletions() {
+        assert_eq!(
+            parse_push_event(br#"{"zen":"Keep it logically awesome."}"#).unwrap(),
+            None
+        );
+        assert_eq!(
+            parse_push_event(
+                br#"{"ref":"refs/heads/old","after":"0000000000000000000000000000000000000000"}"#
+            )
+            .unwrap(),
+            None
+        );
+        assert!(parse_push_event(b"not json").is_err());
+    }
+
+    #[test]
+    fn verifies_github_signature() {
+        // Example from GitHub's webhook documentation
+        let auth = WebhookAuth {
+            hub_signature: Some(
+                "sha256=757107ea0eb2509fc211221cce984b8a37570b6d7586c22c46f4379c8b043e17"
+                    .to_owned(),
+            ),
+            ..Default::default()
+        };
+
+        assert!(verify_secret(BODY, SECRET, &auth).is_ok());
+        assert!(verify_secret(b"Goodbye, World!", SECRET, &auth).is_err());
+        assert!(verify_secret(BODY, "wrong secret", &auth).is_err());
+    }
+
+    #[test]
+    fn verifies_gitlab_token() {
+        let auth = |token: &str| WebhookAuth {
+            gitlab_token: Some(token.to_owned()),
+            ..Default::default()
+        };
+
+        assert!(verify_secret(BODY, SECRET, &auth(SECRET)).is_ok());
+        assert!(verify_secret(BODY, SECRET, &auth("wrong secret")).is_err());
+        assert!(verify_secret(BODY, SECRET, &WebhookAuth::default()).is_err());
+    }
+}
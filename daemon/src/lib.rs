@@ -1,39 +1,135 @@
 #[path = "static/constants.rs"]
 mod constants;
 
+#[path = "functionality/child_env.rs"]
+mod child_env;
+
+#[path = "functionality/commit_verifier.rs"]
+mod commit_verifier;
+
+#[path = "functionality/config_loader.rs"]
+mod config_loader;
+
+#[path = "functionality/control.rs"]
+mod control;
+
+#[path = "functionality/deploy_approval.rs"]
+mod deploy_approval;
+
+#[path = "functionality/deploy_history.rs"]
+mod deploy_history;
+
+#[path = "functionality/deploy_schedule.rs"]
+mod deploy_schedule;
+
+#[path = "functionality/deploy_queue.rs"]
+mod deploy_queue;
+
+#[path = "functionality/git_auth.rs"]
+mod git_auth;
+
 #[path = "functionality/git_updater.rs"]
 mod git_updater;
 
+#[cfg(feature = "native-git")]
+#[path = "functionality/native_git.rs"]
+mod native_git;
+
+#[path = "functionality/restart_rules.rs"]
+mod restart_rules;
+
+#[path = "functionality/shared_config.rs"]
+mod shared_config;
+
+#[path = "functionality/supervisor.rs"]
+mod supervisor;
+
 #[path = "functionality/signature_verifier.rs"]
 mod signature_verifier;
 
+#[path = "functionality/signer.rs"]
+mod signer;
+
+#[path = "functionality/webhook.rs"]
+mod webhook;
+
 #[path = "event_handlers/http.rs"]
 mod http_event_handler;
 
 #[path = "event_handlers/stdio.rs"]
 mod stdio_event_handler;
 
+#[path = "event_handlers/poll.rs"]
+mod poll_event_handler;
+
+#[path = "event_handlers/watch.rs"]
+mod watch_event_handler;
+
+#[path = "commands/init.rs"]
+mod init_command;
+
+#[path = "commands/verify_config.rs"]
+mod verify_config_command;
+
+#[path = "commands/sign.rs"]
+mod sign_command;
+
+#[path = "commands/status.rs"]
+mod status_command;
+
+#[path = "commands/verify.rs"]
+mod verify_command;
+
+#[path = "commands/config_show.rs"]
+mod config_show_command;
+
+#[path = "commands/freeze.rs"]
+mod freeze_command;
+
+#[path = "commands/approve.rs"]
+mod approve_command;
+
+#[path = "commands/keygen.rs"]
+mod keygen_command;
+
+#[path = "commands/relay.rs"]
+mod relay_command;
+
 #[path = "static/types.rs"]
 mod types;
 
 use {
     anyhow::{bail, Context, Result},
+    chrono::Utc,
     constants::{
-        FAILED_TO_KILL_CHILD_PROCESS_ERROR, FAILED_TO_START_CHILD_PROCESS_ERROR,
-        GIT_FETCH_FAILED_STARTUP_WARN, INVALID_CONFIG_JSON_ERROR, MISSING_CONFIG_FILE_ERROR,
-        PUBLIC_KEY_PARSE_ERROR, REPLIT_DEPLOY_JSON_PATH, STAT_PROGRAM_STARTED,
+        APPS_MODE_ERROR, CONTROL_SOCKET_FAILED_WARN, DEFAULT_APPROVAL_EXPIRY,
+        DUPLICATE_APP_NAME_ERROR, GIT_FETCH_FAILED_STARTUP_WARN, INSTALL_FAILED_ERROR,
+        INVALID_APPROVAL_EXPIRY_ERROR, NO_STDIO_PROCESS_ERROR, STAT_ALREADY_DEPLOYED,
+        STAT_AWAITING_APPROVAL, STAT_DEPLOY_HELD, STAT_HELD_DEPLOY_STARTING, STAT_INSTALLING,
+        STAT_NO_RELEVANT_CHANGES, STAT_RESTART_NOT_NEEDED, STAT_SIGNALED,
     },
+    deploy_approval::Approvals,
+    deploy_history::{DeployRecord, Outcome},
+    deploy_queue::DeployQueue,
+    http_event_handler::Route,
     log::{debug, error, info, warn},
     rsa::RSAPublicKey,
-    serde_json,
+    shared_config::SharedConfig,
     std::{
-        cell::RefCell,
-        fs,
-        process::{self, Child, Command, Stdio},
-        rc::Rc,
-        sync::{Arc, Mutex},
+        path::{Path, PathBuf},
+        process,
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc, Mutex,
+        },
+        time::Duration,
     },
-    types::Config,
+    supervisor::{Supervisor, MAIN_PROCESS},
+    tokio::{task, time},
+    types::{
+        Approval, Config, OutsideWindow, ProcessConfig, RestartAction, RestartPolicy, StatusReply,
+    },
+    warp::http::StatusCode,
 };
 
 /*
@@ -68,107 +164,383 @@ yggLIsW8CUnOIhj0AKovh9OvyC//N/GRLQIDAQAB
 */
 const REPL_DEPLOY_PUBLIC_KEY: &[u8; 1038] = include_bytes!("static/public_key.bin");
 
+pub use {
+    approve_command::approve,
+    config_loader::{config_schema, ConfigOptions},
+    config_show_command::config_show,
+    constants::{KEY_BITS, RELAY_PORT},
+    freeze_command::{freeze, unfreeze},
+    init_command::{init, InitOptions},
+    keygen_command::keygen,
+    poll_event_handler::parse_interval,
+    relay_command::{relay, RelayOptions},
+    sign_command::{sign, SIGN_FORMATS},
+    status_command::status,
+    verify_command::{verify, SignedInput},
+    verify_config_command::verify_config,
+};
+
+// How often a held deploy checks whether it may run yet
+const HELD_DEPLOY_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Clone, Copy)]
 pub enum EventHandler {
     Http,
     Stdio,
+    Poll(Duration),
+    Watch,
 }
 
-pub async fn listen(event_handler: EventHandler, cmd: String, cmd_args: Vec<String>) {
-    let repl_deploy_public_key =
-        RSAPublicKey::from_pkcs1(REPL_DEPLOY_PUBLIC_KEY).unwrap_or_else(|_err| {
-            error!("{}", PUBLIC_KEY_PARSE_ERROR);
-            process::exit(1);
-        });
+/// A repository deployed by the daemon: the one it runs in, or one of the
+/// `apps` in its config
+struct App {
+    name: Option<String>,
+    root: PathBuf,
+    config: SharedConfig,
+    public_key: RSAPublicKey,
+}
 
-    let config: Config = serde_json::from_str(
-        &fs::read_to_string(REPLIT_DEPLOY_JSON_PATH).unwrap_or_else(|_err| {
-            error!("{}", MISSING_CONFIG_FILE_ERROR);
-            process::exit(1);
-        }),
-    )
-    .unwrap_or_else(|_err| {
-        error!("{}", INVALID_CONFIG_JSON_ERROR);
-        process::exit(1)
+pub async fn listen(
+    event_handler: EventHandler,
+    config_options: ConfigOptions,
+    command: Vec<String>,
+) {
+    let apps = load_config_and_apps(event_handler, config_options, &command).unwrap_or_else(|e| {
+        error!("{:#}", e);
+        process::exit(1);
     });
 
-    if let Err(e) = git_updater::update_git_from_remote(None) {
-        error!("{}", e);
-        warn!("{}", GIT_FETCH_FAILED_STARTUP_WARN);
+    // Watch mode is for local development, where resetting would throw away
+    // the changes being worked on
+    if !matches!(event_handler, EventHandler::Watch) {
+        for app in &apps {
+            let (root, config) = (app.root.clone(), app.config.clone());
+            task::spawn_blocking(move || {
+                let current = config.get();
+                match git_updater::update_git_from_remote(
+                    Some(&root),
+                    &current.git,
+                    current.env_file.as_deref(),
+                ) {
+                    Ok(update) => {
+                        deploy_history::record(&root, DeployRecord::new(Some(&update), None));
+                        config.reload()
+                    }
+                    Err(e) => {
+                        error!("{}", e);
+                        warn!("{}", GIT_FETCH_FAILED_STARTUP_WARN);
+                        deploy_history::record(&root, DeployRecord::new(None, Some(&e)));
+                    }
+                }
+            })
+            .await
+            .unwrap();
+        }
     }
 
+    let main = main_process(command);
     match event_handler {
-        EventHandler::Http => listen_http(repl_deploy_public_key, config, cmd, cmd_args).await,
-        EventHandler::Stdio => listen_stdio(repl_deploy_public_key, config, cmd, cmd_args),
+        EventHandler::Http => listen_http(apps, main).await,
+        EventHandler::Stdio => {
+            let app = apps.into_iter().next().unwrap();
+            task::spawn_blocking(move || listen_stdio(app, main))
+                .await
+                .unwrap()
+        }
+        EventHandler::Poll(interval) => listen_poll(interval, apps, main).await,
+        EventHandler::Watch => listen_watch(apps.into_iter().next().unwrap(), main).await,
     }
 }
 
-async fn listen_http(pub_key: RSAPublicKey, config: Config, cmd: String, cmd_args: Vec<String>) {
-    let child = match Command::new(&cmd).args(&cmd_args).spawn() {
-        Ok(child_handle) => child_handle,
-        Err(_) => {
-            error!("{}", FAILED_TO_START_CHILD_PROCESS_ERROR);
-            process::exit(1)
+/// The repository the daemon runs in, or the apps its config lists
+fn load_config_and_apps(
+    event_handler: EventHandler,
+    options: ConfigOptions,
+    command: &[String],
+) -> Result<Vec<App>> {
+    let config = config_loader::load_config(&options)?;
+
+    if config.apps.is_empty() {
+        return Ok(vec![load_app(None, PathBuf::from("."), options, config)?]);
+    }
+
+    if !matches!(event_handler, EventHandler::Http | EventHandler::Poll(_)) || !command.is_empty() {
+        bail!(APPS_MODE_ERROR);
+    }
+
+    load_apps(&config)
+}
+
+fn load_apps(config: &Config) -> Result<Vec<App>> {
+    let mut apps: Vec<App> = vec![];
+
+    for app in &config.apps {
+        if apps
+            .iter()
+            .any(|other| other.name.as_ref() == Some(&app.name))
+        {
+            bail!("{}: {}", DUPLICATE_APP_NAME_ERROR, app.name);
         }
-    };
 
-    http_event_handler::listen(
-        Arc::new(config),
-        Arc::new(pub_key),
-        Arc::new(Mutex::new(child)),
-        move |child| -> Result<()> {
-            let mut c = child.lock().unwrap();
-            let cmd_args: Vec<_> = cmd_args.iter().map(|s| s.as_str()).collect();
-            match update_and_restart_process(&mut c, &cmd, &cmd_args, EventHandler::Http) {
-                Ok(new_handle) => {
-                    *c = new_handle;
-                    Ok(())
-                }
-                Err(e) => {
-                    error!("{}", e);
-                    bail!(e);
-                }
+        let root = PathBuf::from(&app.path);
+        let options = ConfigOptions {
+            app_root: Some(root.clone()),
+            ..Default::default()
+        };
+
+        let loaded = config_loader::load_config(&options)
+            .and_then(|config| load_app(Some(app.name.clone()), root, options, config))
+            .with_context(|| format!("App {}", app.name))?;
+        apps.push(loaded);
+    }
+
+    Ok(apps)
+}
+
+/// Loads the app's public key, which is relative to its root like the rest of
+/// its config
+fn load_app(
+    name: Option<String>,
+    root: PathBuf,
+    options: ConfigOptions,
+    config: Config,
+) -> Result<App> {
+    let public_key_path = config.public_key.as_ref().map(|path| root.join(path));
+    let public_key = signature_verifier::load_public_key(public_key_path.as_deref())?;
+
+    Ok(App {
+        name,
+        root,
+        config: SharedConfig::new(options, config),
+        public_key,
+    })
+}
+
+async fn listen_http(apps: Vec<App>, main: Option<ProcessConfig>) {
+    let routes = apps
+        .into_iter()
+        .map(|app| {
+            let deployer = Deployer::new(&app, main.clone(), EventHandler::Http);
+            Route {
+                approvals: deployer.approvals.clone(),
+                state: deployer,
+                name: app.name,
+                root: app.root,
+                config: app.config,
+                public_key: app.public_key,
             }
-        },
+        })
+        .collect();
+
+    http_event_handler::listen(routes, Deployer::request).await
+}
+
+async fn listen_poll(interval: Duration, apps: Vec<App>, main: Option<ProcessConfig>) {
+    let pollers: Vec<_> = apps
+        .into_iter()
+        .map(|app| {
+            let deployer = Deployer::new(&app, main.clone(), EventHandler::Poll(interval));
+
+            tokio::spawn(poll_event_handler::listen(
+                interval,
+                app.root,
+                app.config,
+                deployer,
+                Deployer::request,
+            ))
+        })
+        .collect();
+
+    for poller in pollers {
+        poller.await.unwrap();
+    }
+}
+
+async fn listen_watch(app: App, main: Option<ProcessConfig>) {
+    if let Err(e) = watch_event_handler::listen(
+        &app.root,
+        &app.config.get().watch,
+        start_with_deploy_queue(&app, main, EventHandler::Watch),
+        |deploy_queue: DeployQueue| async move { deploy_queue.request().await },
     )
     .await
+    {
+        error!("{:#}", e);
+        process::exit(1);
+    }
 }
 
-fn listen_stdio(pub_key: RSAPublicKey, config: Config, cmd: String, cmd_args: Vec<String>) {
-    let mut child = Rc::new(RefCell::new(
-        match Command::new(&cmd)
-            .args(&cmd_args)
-            .stdout(Stdio::piped())
-            .stdin(Stdio::piped())
-            .spawn()
-        {
-            Ok(child_handle) => child_handle,
-            Err(_) => {
-                error!("{}", FAILED_TO_START_CHILD_PROCESS_ERROR);
-                process::exit(1)
-            }
-        },
-    ));
+/// The command passed to `run`, if any
+fn main_process(command: Vec<String>) -> Option<ProcessConfig> {
+    let mut command = command.into_iter();
 
-    stdio_event_handler::listen(&pub_key, &config, child.clone(), &mut move || {
-        let child_ref = child.clone();
-        let cmd_args: Vec<_> = cmd_args.iter().map(|s| s.as_str()).collect();
+    Some(ProcessConfig {
+        name: MAIN_PROCESS.to_owned(),
+        command: command.next()?,
+        args: command.collect(),
+        cwd: None,
+        env: Default::default(),
+        restart: RestartPolicy::Never,
+    })
+}
 
-        debug!("Updating and restarting process...");
+/// Deploys of one app, held while frozen or outside the deploy windows, and
+/// until approved if the config says so
+#[derive(Clone)]
+struct Deployer {
+    queue: DeployQueue,
+    root: PathBuf,
+    config: SharedConfig,
+    /// Set while a queued deploy waits, so there's only one
+    held: Arc<AtomicBool>,
+    approvals: Approvals,
+}
+
+impl Deployer {
+    /// Also serves the app's control socket, and runs deploys once approved
+    fn new(app: &App, main: Option<ProcessConfig>, event_handler: EventHandler) -> Self {
+        let (approvals, mut approved) = Approvals::new(app.root.clone());
+        let deployer = Deployer {
+            queue: start_with_deploy_queue(app, main, event_handler),
+            root: app.root.clone(),
+            config: app.config.clone(),
+            held: Arc::new(AtomicBool::new(false)),
+            approvals,
+        };
 
-        let result = update_and_restart_process(
-            &mut *child_ref.borrow_mut(),
-            &cmd,
-            &cmd_args,
-            EventHandler::Stdio,
+        let listened = control::listen(
+            app.root.clone(),
+            app.config.clone(),
+            deployer.approvals.clone(),
         );
+        tokio::spawn(async move {
+            if let Err(e) = listened.await {
+                error!("{:#}", e);
+                warn!("{}", CONTROL_SOCKET_FAILED_WARN);
+            }
+        });
 
-        debug!("Updated and restarted process!");
+        let approved_deployer = deployer.clone();
+        tokio::spawn(async move {
+            while approved.recv().await.is_some() {
+                match approved_deployer.clone().deploy().await {
+                    Ok(()) => {}
+                    Err(e) if e.is::<StatusReply>() => info!("{}", e),
+                    Err(e) => error!("{}", e),
+                }
+            }
+        });
 
-        match result {
-            Ok(new_handle) => {
-                child = Rc::new(RefCell::new(new_handle));
-                Ok(child.clone())
+        deployer
+    }
+
+    /// Deploys as `deploy` does, or adds the request to the pending deploy and
+    /// fails with `StatusReply` when approval is required
+    async fn request(self) -> Result<()> {
+        let config = self.config.get();
+        if config.approval == Approval::None {
+            return self.deploy().await;
+        }
+
+        let expiry = parse_interval(
+            config
+                .approval_expiry
+                .as_deref()
+                .unwrap_or(DEFAULT_APPROVAL_EXPIRY),
+        )
+        .context(INVALID_APPROVAL_EXPIRY_ERROR)?;
+        let (pending, created) = self
+            .approvals
+            .request(expiry.as_secs(), deploy_history::now());
+
+        let message = format!("{} {}", STAT_AWAITING_APPROVAL, pending.id);
+        if created {
+            info!("{}", message);
+        }
+
+        bail!(StatusReply {
+            status: StatusCode::ACCEPTED,
+            message,
+        })
+    }
+
+    /// Deploys now, or queues or rejects the deploy as the schedule says,
+    /// failing with `StatusReply` in that case
+    async fn deploy(self) -> Result<()> {
+        let schedule = self.config.get().schedule.clone();
+        let hold = match deploy_schedule::hold(&self.root, schedule.as_ref(), Utc::now())? {
+            Some(hold) => hold.to_string(),
+            None => {
+                self.held.store(false, Ordering::SeqCst);
+                return self.queue.request().await;
             }
+        };
+
+        info!("{}: {}", STAT_DEPLOY_HELD, hold);
+        let (outcome, status) = match schedule.map_or(OutsideWindow::Queue, |s| s.outside) {
+            OutsideWindow::Queue => (Outcome::Queued, StatusCode::ACCEPTED),
+            OutsideWindow::Reject => (Outcome::Rejected, StatusCode::LOCKED),
+        };
+
+        let record = DeployRecord::held(outcome, &hold);
+        if outcome == Outcome::Rejected {
+            deploy_history::record(&self.root, record);
+        } else if !self.held.swap(true, Ordering::SeqCst) {
+            // Later requests are covered by the deploy already queued
+            deploy_history::record(&self.root, record);
+            tokio::spawn(self.clone().deploy_when_allowed());
+        }
+
+        bail!(StatusReply {
+            status,
+            message: format!("{} ({})", outcome.as_str(), hold),
+        })
+    }
+
+    async fn deploy_when_allowed(self) {
+        loop {
+            time::sleep(HELD_DEPLOY_CHECK_INTERVAL).await;
+
+            // Already run by a request since
+            if !self.held.load(Ordering::SeqCst) {
+                return;
+            }
+
+            let schedule = self.config.get().schedule.clone();
+            match deploy_schedule::hold(&self.root, schedule.as_ref(), Utc::now()) {
+                Ok(None) => break,
+                Ok(Some(_)) => {}
+                Err(e) => error!("{:#}", e),
+            }
+        }
+
+        if self.held.swap(false, Ordering::SeqCst) {
+            info!("{}", STAT_HELD_DEPLOY_STARTING);
+            if let Err(e) = self.queue.request().await {
+                error!("{}", e);
+            }
+        }
+    }
+}
+
+/// Starts the app's programs and returns a queue that updates and restarts
+/// them (or only restarts them, in watch mode)
+fn start_with_deploy_queue(
+    app: &App,
+    main: Option<ProcessConfig>,
+    event_handler: EventHandler,
+) -> DeployQueue {
+    let supervisor = start_supervisor(app, main, false);
+    let (root, config) = (app.root.clone(), app.config.clone());
+
+    DeployQueue::new(move || -> Result<()> {
+        let result = match event_handler {
+            EventHandler::Watch => restart_processes(&supervisor, &config),
+            _ => update_and_restart_processes(&root, &supervisor, &config),
+        };
+
+        match result {
+            Ok(()) => Ok(()),
             Err(e) => {
                 error!("{}", e);
                 bail!(e);
@@ -177,32 +549,137 @@ fn listen_stdio(pub_key: RSAPublicKey, config: Config, cmd: String, cmd_args: Ve
     })
 }
 
-fn update_and_restart_process(
-    child_handle: &mut Child,
-    cmd: &str,
-    cmd_args: &[&str],
-    event_handler: EventHandler,
-) -> Result<Child> {
-    git_updater::update_git_from_remote(None)?;
-
-    child_handle
-        .kill()
-        .with_context(|| FAILED_TO_KILL_CHILD_PROCESS_ERROR)?;
-
-    let child = match event_handler {
-        EventHandler::Http => Command::new(cmd).args(cmd_args).spawn(),
-        EventHandler::Stdio => Command::new(cmd)
-            .args(cmd_args)
-            .stdout(Stdio::piped())
-            .stdin(Stdio::piped())
-            .spawn(),
+fn listen_stdio(app: App, main: Option<ProcessConfig>) {
+    let supervisor = start_supervisor(&app, main, true);
+    let pipes = supervisor::lock(&supervisor)
+        .take_pipes()
+        .unwrap_or_else(|| {
+            error!("{}", NO_STDIO_PROCESS_ERROR);
+            process::exit(1)
+        });
+
+    let (root, shared_config) = (app.root.clone(), app.config.clone());
+    stdio_event_handler::listen(&app.public_key, &app.config, pipes, &mut move || {
+        debug!("Updating and restarting process...");
+
+        if let Err(e) = update_and_restart_processes(&root, &supervisor, &shared_config) {
+            error!("{}", e);
+            bail!(e);
+        }
+
+        debug!("Updated and restarted process!");
+
+        supervisor::lock(&supervisor)
+            .take_pipes()
+            .with_context(|| NO_STDIO_PROCESS_ERROR)
+    })
+}
+
+/// Starts the command passed to `run` and the processes in the app's config,
+/// exiting if that fails
+fn start_supervisor(app: &App, main: Option<ProcessConfig>, stdio: bool) -> Arc<Mutex<Supervisor>> {
+    let supervisor = Supervisor::new(main, stdio, &app.config.get())
+        .map(|supervisor| match &app.name {
+            Some(name) => supervisor.for_app(name, &app.root),
+            None => supervisor,
+        })
+        .and_then(|mut supervisor| supervisor.start().map(|_| supervisor))
+        .unwrap_or_else(|e| {
+            match &app.name {
+                Some(name) => error!("App {}: {:#}", name, e),
+                None => error!("{:#}", e),
+            }
+            process::exit(1)
+        });
+
+    let supervisor = Arc::new(Mutex::new(supervisor));
+    supervisor::monitor(supervisor.clone());
+    supervisor
+}
+
+fn update_and_restart_processes(
+    root: &Path,
+    supervisor: &Mutex<Supervisor>,
+    config: &SharedConfig,
+) -> Result<()> {
+    let current = config.get();
+    let update = match git_updater::update_git_from_remote(
+        Some(root),
+        &current.git,
+        current.env_file.as_deref(),
+    ) {
+        Ok(update) => update,
+        Err(e) => {
+            deploy_history::record(root, DeployRecord::new(None, Some(&e)));
+            return Err(e);
+        }
     };
 
-    match child {
-        Ok(child_handle) => {
-            info!("{}", STAT_PROGRAM_STARTED);
-            Ok(child_handle)
+    // Even without restarts, the config may have changed with the tree
+    if update.reset {
+        config.reload();
+    }
+
+    if !update.relevant {
+        // Pushes that don't move a tag or pinned target, or targets skipped
+        // before, are ignored entirely
+        if update.seen {
+            info!("{} ({})", STAT_ALREADY_DEPLOYED, update.commit);
+        } else {
+            info!("{} ({})", STAT_NO_RELEVANT_CHANGES, update.commit);
+            deploy_history::record(root, DeployRecord::skipped(&update));
         }
-        Err(_) => bail!(FAILED_TO_START_CHILD_PROCESS_ERROR),
+        return Ok(());
     }
+
+    let applied = apply_restart_rules(supervisor, config, &update);
+    let record = match applied {
+        Ok(false) => DeployRecord::skipped(&update),
+        _ => DeployRecord::new(Some(&update), applied.as_ref().err()),
+    };
+    deploy_history::record(root, record);
+    applied.map(|_| ())
+}
+
+/// Restarts, signals or leaves processes alone as `restart_rules` say for the
+/// files the update changed, returning whether any process was touched
+fn apply_restart_rules(
+    supervisor: &Mutex<Supervisor>,
+    config: &SharedConfig,
+    update: &git_updater::Update,
+) -> Result<bool> {
+    let plan = restart_rules::plan(&config.get().restart_rules, update.changed.as_deref())?;
+
+    match plan.action {
+        RestartAction::None => {
+            info!("{}", STAT_RESTART_NOT_NEEDED);
+            return Ok(false);
+        }
+        RestartAction::Signal => {
+            let mut supervisor = supervisor::lock(supervisor);
+            for signal in &plan.signals {
+                supervisor.signal(signal)?;
+                info!("{} {}", STAT_SIGNALED, signal);
+            }
+            return Ok(true);
+        }
+        RestartAction::Restart | RestartAction::ReinstallAndRestart => {}
+    }
+
+    for install in &plan.installs {
+        info!("{} ({})", STAT_INSTALLING, install);
+        // Built under the lock but run outside it, so exited processes are
+        // still restarted while installing
+        let mut command = supervisor::lock(supervisor).shell(install)?;
+        let status = command.status()?;
+        if !status.success() {
+            bail!("{} ({}, {})", INSTALL_FAILED_ERROR, install, status);
+        }
+    }
+
+    restart_processes(supervisor, config).map(|()| true)
+}
+
+fn restart_processes(supervisor: &Mutex<Supervisor>, config: &SharedConfig) -> Result<()> {
+    supervisor::lock(supervisor).restart(&config.get())
 }
@@ -1,62 +1,512 @@
 use {
-    clap::{crate_version, App, AppSettings, Arg},
-    log::debug,
-    pretty_env_logger, repl_deploy as lib,
+    clap::{crate_version, App, AppSettings, Arg, ArgMatches, SubCommand},
+    log::{debug, error},
+    repl_deploy as lib,
+    std::{
+        env,
+        ffi::OsString,
+        path::{Path, PathBuf},
+        process,
+    },
 };
 
 const EXAMPLES: &str = "EXAMPLES:
-    repl.deploy --standalone node index.js
-    repl.deploy --standalone cargo run
-    repl.deploy node server.js
+    repl.deploy run --standalone node index.js
+    repl.deploy run --standalone cargo run
+    repl.deploy run node server.js
+    repl.deploy run --poll 5m node index.js
+    repl.deploy run --watch cargo run
+    repl.deploy init --standalone --endpoint https://app.user.repl.co/refresh node index.js
+    repl.deploy verify-config
+    repl.deploy sign --key priv.pem --endpoint https://app.user.repl.co/refresh --format curl
+    repl.deploy sign -k priv.pem -e https://app.user.repl.co/refresh -f stdio | repl.deploy verify --public-key pub.pem
+    repl.deploy status
+    repl.deploy freeze rolling back the payments outage
+    repl.deploy approve 1a2b3c4d
+    REPL_DEPLOY_GIT_BRANCH=staging repl.deploy config show
+    repl.deploy keygen
+    repl.deploy relay --key repl-deploy.pem --mirror repo.git --secret-file webhook.secret
+
+    `run` may be left out, e.g. `repl.deploy --standalone node index.js`
 ";
 
+const CONFIG_HELP: &str =
+    "Path to the config file, defaults to ./replit-deploy.json (REPL_DEPLOY_CONFIG)";
+
+const SUBCOMMANDS: &[&str] = &[
+    "run",
+    "init",
+    "verify-config",
+    "sign",
+    "verify",
+    "status",
+    "freeze",
+    "unfreeze",
+    "approve",
+    "config",
+    "keygen",
+    "relay",
+    "help",
+];
+
 #[tokio::main]
 async fn main() {
     pretty_env_logger::init();
 
     let matches = App::new("repl.deploy")
-        .setting(AppSettings::TrailingVarArg)
+        .setting(AppSettings::SubcommandRequiredElseHelp)
         .bin_name("repl.deploy")
         .version(crate_version!())
         .author("Khushraj Rathod <khushraj.rathod@gmail.com>")
         .about("Automatically deploy from GitHub to Replit, lightning fast ⚡️")
-        .arg(
-            Arg::with_name("standalone")
-                .long("standalone")
-                .short("s")
-                .takes_value(false)
-                .help("Start an HTTP server to listen for refresh events"),
-        )
-        .arg(
-            Arg::with_name("command")
-                .multiple(true)
-                .required(true)
-                .help("Command to run your program"),
+        .subcommand(
+            SubCommand::with_name("run")
+                .setting(AppSettings::TrailingVarArg)
+                .about("Run your program and restart it on deploys (default)")
+                .arg(
+                    Arg::with_name("standalone")
+                        .long("standalone")
+                        .short("s")
+                        .takes_value(false)
+                        .help("Start an HTTP server to listen for refresh events"),
+                )
+                .arg(
+                    Arg::with_name("poll")
+                        .long("poll")
+                        .short("p")
+                        .takes_value(true)
+                        .value_name("interval")
+                        .conflicts_with("standalone")
+                        .validator(validate_interval)
+                        .help("Poll GitHub for new commits every <interval> (e.g. 30s, 5m)"),
+                )
+                .arg(
+                    Arg::with_name("watch")
+                        .long("watch")
+                        .short("w")
+                        .takes_value(false)
+                        .conflicts_with_all(&["standalone", "poll"])
+                        .help("Restart your program when local files change, without touching git"),
+                )
+                .arg(config_arg())
+                .args(&config_override_args())
+                .arg(
+                    Arg::with_name("command")
+                        .multiple(true)
+                        .help("Command to run your program, optional if the config declares processes"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("init")
+                .setting(AppSettings::TrailingVarArg)
+                .about(
+                    "Set up replit-deploy.json, the .replit run line and check that git is ready",
+                )
+                .arg(
+                    Arg::with_name("endpoint")
+                        .long("endpoint")
+                        .short("e")
+                        .takes_value(true)
+                        .value_name("url")
+                        .help("Address of your repl + /refresh (detected on Replit)"),
+                )
+                .arg(
+                    Arg::with_name("remote")
+                        .long("remote")
+                        .takes_value(true)
+                        .help("Git remote to deploy from (detected if left out)"),
+                )
+                .arg(
+                    Arg::with_name("branch")
+                        .long("branch")
+                        .takes_value(true)
+                        .help("Branch to deploy (detected if left out)"),
+                )
+                .arg(
+                    Arg::with_name("standalone")
+                        .long("standalone")
+                        .short("s")
+                        .takes_value(false)
+                        .help("Your program doesn't run its own HTTP server"),
+                )
+                .arg(
+                    Arg::with_name("force")
+                        .long("force")
+                        .short("f")
+                        .takes_value(false)
+                        .help("Overwrite an existing replit-deploy.json"),
+                )
+                .arg(
+                    Arg::with_name("command")
+                        .multiple(true)
+                        .help("Command to run your program"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("verify-config")
+                .about("Check that replit-deploy.json is valid")
+                .arg(config_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("sign")
+                .about("Sign a refresh payload with a local private key, for testing")
+                .arg(
+                    Arg::with_name("key")
+                        .long("key")
+                        .short("k")
+                        .takes_value(true)
+                        .value_name("priv.pem")
+                        .required(true)
+                        .help("PEM encoded RSA private key"),
+                )
+                .arg(
+                    Arg::with_name("endpoint")
+                        .long("endpoint")
+                        .short("e")
+                        .takes_value(true)
+                        .value_name("url")
+                        .required(true)
+                        .help("Endpoint the payload is intended for"),
+                )
+                .arg(
+                    Arg::with_name("commit")
+                        .long("commit")
+                        .takes_value(true)
+                        .value_name("sha")
+                        .help("Commit to include in the payload"),
+                )
+                .arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .short("f")
+                        .takes_value(true)
+                        .possible_values(lib::SIGN_FORMATS)
+                        .default_value("headers")
+                        .help("Print HTTP headers and body, a curl command, or the repl.deploy{...} stdio line"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("verify")
+                .about("Check a signed payload like the daemon would, explaining any failed check")
+                .after_help("Reads a repl.deploy{...}<signature> line from stdin unless --line or --payload and --signature are passed")
+                .arg(config_arg())
+                .arg(
+                    Arg::with_name("public-key")
+                        .long("public-key")
+                        .takes_value(true)
+                        .value_name("pub.pem")
+                        .help("PEM encoded RSA public key (defaults to repl.deploy's own key)"),
+                )
+                .arg(
+                    Arg::with_name("line")
+                        .long("line")
+                        .takes_value(true)
+                        .conflicts_with("payload")
+                        .help("A repl.deploy{...}<signature> line"),
+                )
+                .arg(
+                    Arg::with_name("payload")
+                        .long("payload")
+                        .takes_value(true)
+                        .requires("signature")
+                        .help("Request body"),
+                )
+                .arg(
+                    Arg::with_name("signature")
+                        .long("signature")
+                        .takes_value(true)
+                        .requires("payload")
+                        .help("Signature header"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("status")
+                .about("Show the config, deployed commit and whether the daemon is running")
+                .arg(config_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("freeze")
+                .about("Hold deploys of this repository until unfrozen")
+                .arg(
+                    Arg::with_name("reason")
+                        .multiple(true)
+                        .help("Shown in the log, history and status"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("unfreeze")
+                .about("Allow deploys again, running any that were queued"),
+        )
+        .subcommand(
+            SubCommand::with_name("approve")
+                .about("Run a deploy awaiting approval (with approval: required)")
+                .arg(
+                    Arg::with_name("id")
+                        .required(true)
+                        .help("Id of the pending deploy, as shown by status"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("config")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .about("Inspect the configuration")
+                .subcommand(
+                    SubCommand::with_name("show")
+                        .about("Show the effective config after applying the environment and flags, with secrets redacted")
+                        .arg(config_arg())
+                        .args(&config_override_args()),
+                )
+                .subcommand(
+                    SubCommand::with_name("schema")
+                        .about("Print the JSON Schema for replit-deploy.json, for editors to validate it with"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("keygen")
+                .about("Generate a key pair for signing your own refresh requests")
+                .arg(
+                    Arg::with_name("out")
+                        .long("out")
+                        .short("o")
+                        .takes_value(true)
+                        .value_name("prefix")
+                        .default_value("repl-deploy")
+                        .help("Writes <prefix>.pem and <prefix>.pub.pem"),
+                )
+                .arg(
+                    Arg::with_name("force")
+                        .long("force")
+                        .short("f")
+                        .takes_value(false)
+                        .help("Overwrite existing key files"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("relay")
+                .about("Receive push webhooks and send signed refresh requests, instead of the GitHub app")
+                .after_help("Point your forge's push webhook at http://<host>:<port>/webhook")
+                .arg(
+                    Arg::with_name("key")
+                        .long("key")
+                        .short("k")
+                        .takes_value(true)
+                        .value_name("priv.pem")
+                        .required(true)
+                        .help("PEM encoded RSA private key, see `repl.deploy keygen`"),
+                )
+                .arg(
+                    Arg::with_name("mirror")
+                        .long("mirror")
+                        .short("m")
+                        .takes_value(true)
+                        .value_name("path")
+                        .required(true)
+                        .help("Local mirror of the repository (git clone --mirror <url>)"),
+                )
+                .arg(
+                    Arg::with_name("port")
+                        .long("port")
+                        .short("p")
+                        .takes_value(true)
+                        .validator(|port| port.parse::<u16>().map(|_| ()).map_err(|e| e.to_string()))
+                        .help("Port to listen on (defaults to 8091)"),
+                )
+                .arg(
+                    Arg::with_name("secret-file")
+                        .long("secret-file")
+                        .takes_value(true)
+                        .value_name("path")
+                        .help("File holding the webhook secret, requests that aren't signed with it are rejected (defaults to REPL_DEPLOY_WEBHOOK_SECRET)"),
+                )
+                .arg(
+                    Arg::with_name("insecure-no-secret")
+                        .long("insecure-no-secret")
+                        .takes_value(false)
+                        .help("Relay webhooks without a secret, letting anyone who can reach the relay trigger deploys"),
+                ),
         )
         .after_help(EXAMPLES)
-        .get_matches();
+        .get_matches_from(args_with_default_subcommand());
 
-    let event_handler = if matches.is_present("standalone") {
+    let result = match matches.subcommand() {
+        ("run", Some(matches)) => {
+            run(matches).await;
+            Ok(())
+        }
+        ("init", Some(matches)) => lib::init(lib::InitOptions {
+            endpoint: matches.value_of("endpoint").map(String::from),
+            remote: matches.value_of("remote").map(String::from),
+            branch: matches.value_of("branch").map(String::from),
+            command: matches
+                .values_of("command")
+                .map(|command| command.collect::<Vec<_>>().join(" ")),
+            standalone: matches.is_present("standalone"),
+            force: matches.is_present("force"),
+        }),
+        ("verify-config", Some(matches)) => lib::verify_config(&config_options(matches)),
+        ("sign", Some(matches)) => lib::sign(
+            Path::new(matches.value_of("key").unwrap()),
+            matches.value_of("endpoint").unwrap(),
+            matches.value_of("commit"),
+            matches.value_of("format").unwrap(),
+        ),
+        ("verify", Some(matches)) => lib::verify(
+            match (
+                matches.value_of("line"),
+                matches.value_of("payload"),
+                matches.value_of("signature"),
+            ) {
+                (Some(line), _, _) => lib::SignedInput::Line(line.to_owned()),
+                (None, Some(payload), Some(signature)) => {
+                    lib::SignedInput::PayloadAndSignature(payload.to_owned(), signature.to_owned())
+                }
+                _ => lib::SignedInput::Stdin,
+            },
+            &config_options(matches),
+            matches.value_of("public-key").map(Path::new),
+        ),
+        ("status", Some(matches)) => lib::status(&config_options(matches)),
+        ("freeze", Some(matches)) => lib::freeze(
+            &matches
+                .values_of("reason")
+                .map_or_else(Vec::new, |reason| reason.collect())
+                .join(" "),
+        ),
+        ("unfreeze", Some(_)) => lib::unfreeze(),
+        ("approve", Some(matches)) => lib::approve(matches.value_of("id").unwrap()),
+        ("config", Some(matches)) => match matches.subcommand() {
+            ("show", Some(matches)) => lib::config_show(&config_options(matches)),
+            ("schema", Some(_)) => {
+                println!("{}", lib::config_schema());
+                Ok(())
+            }
+            _ => unreachable!(),
+        },
+        ("keygen", Some(matches)) => lib::keygen(
+            matches.value_of("out").unwrap(),
+            lib::KEY_BITS,
+            matches.is_present("force"),
+        ),
+        ("relay", Some(matches)) => {
+            lib::relay(lib::RelayOptions {
+                key: PathBuf::from(matches.value_of("key").unwrap()),
+                mirror: PathBuf::from(matches.value_of("mirror").unwrap()),
+                port: matches
+                    .value_of("port")
+                    .map_or(lib::RELAY_PORT, |port| port.parse().unwrap()),
+                secret_file: matches.value_of("secret-file").map(PathBuf::from),
+                insecure_no_secret: matches.is_present("insecure-no-secret"),
+            })
+            .await
+        }
+        _ => unreachable!(),
+    };
+
+    if let Err(e) = result {
+        error!("{:#}", e);
+        process::exit(1);
+    }
+}
+
+async fn run(matches: &ArgMatches<'_>) {
+    let event_handler = if matches.is_present("watch") {
+        lib::EventHandler::Watch
+    } else if let Some(interval) = matches.value_of("poll") {
+        lib::EventHandler::Poll(lib::parse_interval(interval).unwrap())
+    } else if matches.is_present("standalone") {
         lib::EventHandler::Http
     } else {
         lib::EventHandler::Stdio
     };
 
-    let mut cmd_and_args = matches.values_of("command").unwrap();
-    let cmd = cmd_and_args.next().unwrap().to_owned();
-    let args: Vec<String> = cmd_and_args.map(String::from).collect();
-
-    drop(matches);
+    let command: Vec<String> = matches
+        .values_of("command")
+        .map(|command| command.map(String::from).collect())
+        .unwrap_or_default();
 
-    debug!("Cmd: {:?}", cmd);
-    debug!("Args: {:?}", args);
+    debug!("Command: {:?}", command);
     debug!(
         "Event handler: {:?}",
         match event_handler {
             lib::EventHandler::Http => "HTTP",
             lib::EventHandler::Stdio => "STDIO",
+            lib::EventHandler::Poll(_) => "POLL",
+            lib::EventHandler::Watch => "WATCH",
         }
     );
 
-    lib::listen(event_handler, cmd, args).await;
+    lib::listen(event_handler, config_options(matches), command).await;
+}
+
+fn config_arg() -> Arg<'static, 'static> {
+    Arg::with_name("config")
+        .long("config")
+        .short("c")
+        .takes_value(true)
+        .value_name("path")
+        .env("REPL_DEPLOY_CONFIG")
+        .help(CONFIG_HELP)
+}
+
+/// Flags that take precedence over the config file and environment
+fn config_override_args() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("endpoint")
+            .long("endpoint")
+            .takes_value(true)
+            .value_name("url")
+            .help("Overrides the endpoint (REPL_DEPLOY_ENDPOINT)"),
+        Arg::with_name("public-key")
+            .long("public-key")
+            .takes_value(true)
+            .value_name("pub.pem")
+            .help("Overrides the public key (REPL_DEPLOY_PUBLIC_KEY)"),
+        Arg::with_name("remote")
+            .long("remote")
+            .takes_value(true)
+            .help("Overrides the git remote (REPL_DEPLOY_GIT_REMOTE)"),
+        Arg::with_name("branch")
+            .long("branch")
+            .takes_value(true)
+            .help("Overrides the git branch (REPL_DEPLOY_GIT_BRANCH)"),
+    ]
+}
+
+/// Reads the config flags, any of which a subcommand may leave out
+fn config_options(matches: &ArgMatches<'_>) -> lib::ConfigOptions {
+    let value = |name| matches.value_of(name).map(String::from);
+
+    lib::ConfigOptions {
+        path: matches.value_of("config").map(PathBuf::from),
+        endpoint: value("endpoint"),
+        public_key: value("public-key"),
+        remote: value("remote"),
+        branch: value("branch"),
+        app_root: None,
+    }
+}
+
+/// Keeps the original `repl.deploy [--standalone] <command>` invocation
+/// working by treating anything that isn't a subcommand as `run`
+fn args_with_default_subcommand() -> Vec<OsString> {
+    let mut args: Vec<OsString> = env::args_os().collect();
+
+    let is_explicit = match args.get(1).and_then(|arg| arg.to_str()) {
+        Some(arg) => {
+            SUBCOMMANDS.contains(&arg) || ["-h", "--help", "-V", "--version"].contains(&arg)
+        }
+        None => true,
+    };
+
+    if !is_explicit {
+        args.insert(1, OsString::from("run"));
+    }
+
+    args
+}
+
+fn validate_interval(interval: String) -> Result<(), String> {
+    lib::parse_interval(&interval)
+        .map(|_| ())
+        .map_err(|e| e.to_string())
 }
@@ -1,32 +1,173 @@
 // Strings
 pub const REPLIT_DEPLOY_JSON_PATH: &str = "./replit-deploy.json";
+pub const REPLIT_DEPLOY_CONFIG_FILE_NAMES: &[&str] = &[
+    "replit-deploy.json",
+    "replit-deploy.toml",
+    "replit-deploy.yaml",
+    "replit-deploy.yml",
+];
+pub const FREEZE_FILE_NAME: &str = "repl-deploy-freeze";
+pub const SKIPPED_TARGET_FILE_NAME: &str = "repl-deploy-skipped";
+pub const CONTROL_SOCKET_FILE_NAME: &str = "repl-deploy.sock";
+pub const DEPLOY_HISTORY_FILE_NAME: &str = "repl-deploy-history.jsonl";
+pub const REPLIT_FILE_PATH: &str = "./.replit";
 pub const SIGNATURE_HEADER_NAME: &str = "Signature";
 pub const STDIN_REGEX: &str = r"repl\.deploy(\{.*})(.*)";
 pub const STDIN_SUCCESS: &str = "repl.deploy-success";
 pub const STDIN_RESPONDED_SUCCESSFULLY: &str = "Responded successfully";
 pub const OK: &str = "OK";
 pub const REFRESH_PATH: &str = "refresh";
+pub const CONTROL_PATH: &str = "control";
+pub const STATUS_PATH: &str = "status";
+pub const DEFAULT_APPROVAL_EXPIRY: &str = "24h";
+pub const WEBHOOK_PATH: &str = "webhook";
+pub const WEBHOOK_SECRET_VAR: &str = "REPL_DEPLOY_WEBHOOK_SECRET";
+
+// Numbers
+pub const HTTP_PORT: u16 = 8090;
+pub const RELAY_PORT: u16 = 8091;
+pub const KEY_BITS: usize = 4096;
 
 // Errors
 pub const PUBLIC_KEY_PARSE_ERROR: &str =
     "Failed to parse public key. This shouldn't have happened, please open a new issue at https://github.com/khrj/repl.deploy/issues/new";
-pub const MISSING_CONFIG_FILE_ERROR: &str = "Config file doesn't exist";
-pub const INVALID_CONFIG_JSON_ERROR: &str = "Invalid config JSON";
-pub const GIT_FETCH_FAILED_ERROR: &str = "'git fetch --all' failed";
-pub const GIT_RESET_FAILED_ERROR: &str = "'git reset --hard origin/main' failed";
+pub const PUBLIC_KEY_FILE_ERROR: &str =
+    "Failed to parse public key, expected an RSA key in PEM format";
+pub const PRIVATE_KEY_PARSE_ERROR: &str =
+    "Failed to parse private key, expected an RSA key in PEM format";
+pub const KEY_GENERATION_FAILED_ERROR: &str = "Failed to generate key pair";
+pub const KEY_FILE_EXISTS_ERROR: &str = "Key file already exists, pass --force to overwrite it";
+pub const SIGNING_FAILED_ERROR: &str = "Failed to sign payload";
+pub const UNKNOWN_SIGN_FORMAT_ERROR: &str = "Unknown output format";
+pub const MISSING_SIGNED_PAYLOAD_ERROR: &str =
+    "Expected a line like repl.deploy{...}<signature>, or pass --payload and --signature";
+pub const MISSING_CONFIG_FILE_ERROR: &str =
+    "Config file doesn't exist, run 'repl.deploy init' to create one";
+pub const INVALID_CONFIG_ERROR: &str = "Invalid config";
+pub const MISSING_ENDPOINT_ERROR: &str =
+    "No endpoint configured, set it in the config file, REPL_DEPLOY_ENDPOINT or --endpoint";
+pub const CONFIG_FILE_EXISTS_ERROR: &str =
+    "Config file already exists, pass --force to overwrite it";
+pub const MISSING_INIT_OPTION_ERROR: &str = "Not running interactively, please pass";
+pub const PREFLIGHT_FETCH_FAILED_ERROR: &str =
+    "Couldn't fetch the deploy branch, make sure the remote and branch exist and git can access them";
+pub const INVALID_ENDPOINT_ERROR: &str = "Endpoint must be an http:// or https:// URL";
+pub const GIT_FETCH_FAILED_ERROR: &str = "'git fetch' failed";
+pub const GIT_RESET_FAILED_ERROR: &str = "'git reset --hard' failed";
+#[cfg_attr(not(feature = "native-git"), allow(dead_code))]
+pub const NOT_A_REPOSITORY_ERROR: &str = "Not a git repository";
+pub const GIT_CLEAN_FAILED_ERROR: &str = "'git clean' failed";
+pub const GIT_SUBMODULE_FAILED_ERROR: &str = "'git submodule update' failed";
+pub const GIT_LFS_FAILED_ERROR: &str = "'git lfs' failed, is git-lfs installed?";
+#[cfg_attr(not(feature = "native-git"), allow(dead_code))]
+pub const GIT_AUTH_FAILED_ERROR: &str = "Authentication failed, check the credentials in git.auth";
+pub const MISSING_GIT_TOKEN_ERROR: &str = "Couldn't read the git token from git.auth";
+pub const MISSING_SSH_KEY_ERROR: &str = "SSH key in git.auth doesn't exist";
+pub const UNSIGNED_COMMIT_ERROR: &str =
+    "Refusing to deploy, git.verify is set and the commit isn't signed";
+pub const UNTRUSTED_SIGNATURE_ERROR: &str =
+    "Refusing to deploy, the signature is invalid or not from an allowed key";
+pub const MISSING_ALLOWLIST_ERROR: &str = "Missing allowlist for the commit's signature type";
+pub const ALLOWLIST_IN_REPOSITORY_ERROR: &str =
+    "Signing key allowlists have to be kept outside the repository";
+pub const NO_MATCHING_TAG_ERROR: &str = "No version tag matches the pattern";
+pub const INVALID_TAG_PATTERN_ERROR: &str = "Invalid tag pattern";
+pub const PINNED_COMMIT_MISSING_ERROR: &str =
+    "The pinned commit doesn't exist in the repository, even after fetching";
+pub const PINNED_COMMIT_NOT_ON_BRANCH_ERROR: &str =
+    "The pinned commit isn't reachable from the deploy branch";
+pub const PRESERVE_FAILED_ERROR: &str = "Failed to preserve";
+pub const RESTORE_FAILED_ERROR: &str = "Failed to restore preserved";
+pub const GIT_REV_PARSE_FAILED_ERROR: &str = "'git rev-parse' failed";
+pub const GIT_STATUS_FAILED_ERROR: &str = "'git status' failed, is this a git repository?";
+pub const NO_GIT_REMOTE_ERROR: &str =
+    "No git remote found, add one with 'git remote add origin <url>'";
 pub const INVALID_SIGNATURE_ERROR: &str = "Invalid Signature";
 pub const BAD_PAYLOAD_ERROR: &str = "Bad payload";
 pub const PAYLOAD_TOO_OLD_ERROR: &str = "Payload too old";
 pub const BAD_ENDPOINT_ERROR: &str = "Signed request not intended for current endpoint";
+pub const BAD_WEBHOOK_ERROR: &str = "Unrecognised webhook payload";
+pub const BAD_WEBHOOK_SECRET_ERROR: &str = "Webhook secret doesn't match";
+pub const MISSING_WEBHOOK_SECRET_ERROR: &str =
+    "The relay needs a webhook secret, set REPL_DEPLOY_WEBHOOK_SECRET or pass --secret-file";
+pub const INVALID_WEBHOOK_SECRET_FILE_ERROR: &str = "Couldn't read a webhook secret from";
+pub const REPL_REQUEST_FAILED_ERROR: &str = "Failed to send signed request to repl";
+pub const NO_PROCESSES_ERROR: &str =
+    "Nothing to run, pass a command or declare processes in the config";
+pub const DUPLICATE_PROCESS_NAME_ERROR: &str = "Process names must be unique, found twice";
+pub const UNKNOWN_PROCESS_ERROR: &str = "restart_order names a process that isn't declared";
+pub const INVALID_ENV_FILE_ERROR: &str = "Couldn't read env_file";
+pub const NESTED_APPS_ERROR: &str = "An app's config can't declare apps of its own";
+pub const PATH_OUTSIDE_REPOSITORY_ERROR: &str =
+    "Paths have to be relative to the repository root, without `..`";
+pub const DUPLICATE_APP_NAME_ERROR: &str = "App names must be unique, found twice";
+pub const APPS_MODE_ERROR: &str =
+    "Apps are deployed with --standalone or --poll, and run the processes in their own configs rather than a command";
+pub const UNKNOWN_APP_ERROR: &str = "Unknown app";
+pub const NO_STDIO_PROCESS_ERROR: &str =
+    "The process whose output is scanned for signed requests is gone";
 pub const FAILED_TO_START_CHILD_PROCESS_ERROR: &str = "Failed to start child process";
 pub const FAILED_TO_KILL_CHILD_PROCESS_ERROR: &str = "Failed to kill child process";
 pub const PROBLEMS_SERIALIZING_JSON_ERROR: &str = "Problems serializing JSON";
 pub const PROBLEMS_WRITING_TO_STDIN_OF_SUBPROCESS_ERROR: &str =
     "Problems writing to stdin of subprocess";
+pub const DEPLOY_QUEUE_STOPPED_ERROR: &str = "Deploy queue has stopped";
+pub const FILE_WATCHER_ERROR: &str = "Failed to watch files for changes";
+pub const INVALID_WATCH_GLOB_ERROR: &str = "Invalid glob in watch config";
+pub const INVALID_PATHS_GLOB_ERROR: &str = "Invalid glob in git.paths";
+pub const INVALID_RESTART_RULE_GLOB_ERROR: &str = "Invalid glob in restart_rules";
+pub const MISSING_INSTALL_COMMAND_ERROR: &str =
+    "reinstall-and-restart rules need an install command";
+pub const INSTALL_FAILED_ERROR: &str = "Install command failed, not restarting";
+pub const SIGNAL_FAILED_ERROR: &str = "Failed to signal process";
+pub const INVALID_TIMEZONE_ERROR: &str = "Unknown time zone in schedule";
+pub const INVALID_WINDOW_TIME_ERROR: &str = "Deploy window times have to be HH:MM";
+pub const SKIPPED_TARGET_ERROR: &str = "Failed to remember the skipped deploy target";
+pub const FREEZE_FAILED_ERROR: &str = "Failed to change the deploy freeze";
+pub const DEPLOYS_FROZEN_ERROR: &str = "Deploys are frozen";
+pub const OUTSIDE_DEPLOY_WINDOW_ERROR: &str = "Outside the deploy windows";
+pub const CONTROL_DISABLED_ERROR: &str = "Control API isn't enabled";
+pub const CONTROL_UNAUTHORIZED_ERROR: &str = "Missing or wrong control token";
+pub const UNKNOWN_CONTROL_ACTION_ERROR: &str = "Unknown control action";
+pub const CONTROL_SOCKET_ERROR: &str = "Failed to listen on the control socket";
+pub const DAEMON_UNREACHABLE_ERROR: &str =
+    "Couldn't reach repl.deploy through its control socket, is it running here with --poll or the HTTP server?";
+pub const UNKNOWN_APPROVAL_ERROR: &str = "No deploy with that id is pending";
+pub const APPROVAL_EXPIRED_ERROR: &str =
+    "The deploy wasn't approved in time, push again or send a new refresh request";
+pub const APPROVALS_STOPPED_ERROR: &str = "Approved deploys aren't being run anymore";
+pub const INVALID_APPROVAL_EXPIRY_ERROR: &str = "Invalid approval_expiry";
+pub const INVALID_POLL_INTERVAL_ERROR: &str =
+    "Invalid poll interval, expected a number of seconds or e.g. 30s, 5m, 1h";
 
 // Warnings
 pub const GIT_FETCH_FAILED_STARTUP_WARN: &str =
-    "Failed to fetch from GitHub on startup, make sure git is set up";
+    "Failed to fetch on startup, make sure git is set up, and for private repositories that git.auth is configured";
+pub const UNCOMMITTED_CHANGES_WARN: &str =
+    "These files have uncommitted changes that will be lost when repl.deploy resets to the remote branch. Commit and push them first:";
+pub const CONFIG_RELOAD_FAILED_WARN: &str =
+    "Failed to reload the config after deploying, keeping the previous one";
+pub const PUBLIC_KEY_CHANGED_WARN: &str =
+    "The public key changed, restart repl.deploy for requests to be verified with it";
+pub const INVALID_PROCESSES_WARN: &str =
+    "The new config's processes are invalid, restarting the previous ones";
+pub const ENV_FILE_TRACKED_WARN: &str =
+    "env_file is tracked by git, so deploys reset it and secrets end up in the repository, add it to .gitignore";
+#[cfg_attr(not(feature = "native-git"), allow(dead_code))]
+pub const NATIVE_FILTER_UNSUPPORTED_WARN: &str =
+    "libgit2 doesn't support git.filter, fetching with the git binary instead";
+pub const DIFF_FAILED_WARN: &str =
+    "Failed to list the files changed by the deploy, treating them all as relevant";
+pub const DEPLOY_HISTORY_WRITE_FAILED_WARN: &str =
+    "Couldn't record the deploy in the history, it went ahead regardless";
+pub const TOKEN_FILE_TRACKED_WARN: &str =
+    "git.auth.token_file is tracked by git, so the token is in the repository, add it to .gitignore";
+pub const CONTROL_SOCKET_FAILED_WARN: &str =
+    "The control socket isn't available, so `repl.deploy approve` won't reach this daemon";
+pub const POLL_FETCH_FAILED_WARN: &str =
+    "Failed to check GitHub for new commits, backing off before the next poll";
+pub const RELAY_WITHOUT_SECRET_WARN: &str =
+    "Relaying webhooks without a secret, anyone who can reach the relay can trigger deploys";
 
 // Status
 pub const STAT_PROGRAM_STARTED: &str = "Program has been started.";
@@ -35,3 +176,28 @@ pub const STAT_SIGNATURE_VALIDATION_FAILED: &str =
     "Signature validation failed, restart will not be triggered";
 pub const STAT_SIGNATURE_VALIDATION_SUCCESS: &str =
     "Signature validation successful, restarting program";
+pub const STAT_PROCESS_EXITED: &str = "Process exited";
+pub const STAT_ENDPOINT_MATCHED: &str = "Request is intended for";
+pub const STAT_POLL_NEW_COMMIT: &str = "New commit found while polling, restarting program";
+pub const STAT_POLL_FAILED_TARGET: &str =
+    "Deploying this commit failed or was refused before, waiting for a new one";
+pub const STAT_WATCHING_FILES: &str = "Watching files for changes...";
+pub const STAT_FILES_CHANGED: &str = "Files changed, restarting program";
+pub const STAT_RELAY_LISTENING: &str = "Relay listening for webhooks on port";
+pub const STAT_RELAY_SENT: &str = "Sent signed refresh request to";
+pub const STAT_RELAY_IGNORED: &str = "Ignored webhook";
+pub const STAT_FETCHED: &str = "Fetched";
+pub const STAT_ALREADY_DEPLOYED: &str = "Target was already deployed or skipped, not restarting";
+pub const STAT_NO_RELEVANT_CHANGES: &str = "No paths in git.paths changed, not restarting";
+pub const STAT_RESTART_NOT_NEEDED: &str = "restart_rules say no action is needed for this deploy";
+pub const STAT_SIGNALED: &str = "Signaled processes with";
+pub const STAT_INSTALLING: &str = "Running install command";
+pub const STAT_DEPLOY_HELD: &str = "Holding deploy";
+pub const STAT_HELD_DEPLOY_STARTING: &str = "Deploys are allowed again, running the held deploy";
+pub const STAT_FROZEN: &str = "Deploys frozen";
+pub const STAT_UNFROZEN: &str = "Deploys unfrozen";
+pub const STAT_AWAITING_APPROVAL: &str = "Deploy awaits approval, run repl.deploy approve";
+pub const STAT_APPROVED: &str = "Deploy approved, running it";
+pub const STAT_CONFIG_RELOADED: &str = "Reloaded config, changes:";
+pub const STAT_DEPLOY_COALESCED: &str =
+    "Multiple deploys were requested while busy, deploying latest once";
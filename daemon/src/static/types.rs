@@ -1,6 +1,7 @@
 use {
+    schemars::JsonSchema,
     serde::{Deserialize, Serialize},
-    std::fmt,
+    std::{collections::BTreeMap, fmt, iter},
     warp::{http::StatusCode, reject::Reject},
 };
 
@@ -8,11 +9,414 @@ use {
 pub struct Payload {
     pub timestamp: u128,
     pub endpoint: String,
+    /// Commit that triggered the deploy, informational only
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub commit: Option<String>,
 }
 
-#[derive(Deserialize)]
+// Doc comments on the config types end up in replit-deploy.schema.json, which
+// is regenerated with `repl.deploy config schema`
+
+/// Contents of replit-deploy.json, .toml or .yaml
+#[derive(Serialize, Deserialize, JsonSchema, Default, Clone, Debug)]
+#[serde(deny_unknown_fields)]
 pub struct Config {
+    /// JSON Schema for editors, ignored by repl.deploy
+    #[serde(rename = "$schema", default, skip_serializing)]
+    #[allow(dead_code)]
+    pub schema: Option<String>,
+    /// Address of your repl + /refresh, which signed requests must be intended
+    /// for. May be left out when `endpoints` is set.
+    #[serde(default)]
     pub endpoint: String,
+    /// Other addresses requests may be intended for, such as a custom domain
+    #[serde(default)]
+    pub endpoints: Vec<String>,
+    /// PEM public key to verify requests with instead of repl.deploy's own,
+    /// for requests signed by a self-hosted relay
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub public_key: Option<String>,
+    #[serde(default)]
+    pub git: GitConfig,
+    #[serde(default)]
+    pub watch: WatchConfig,
+    /// Environment variables for every process, on top of the daemon's own
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    /// File of `KEY=value` lines, such as `.env`, inside the repository,
+    /// read before each process start and applied over `env`. Keep it
+    /// untracked (in .gitignore) for secrets, untracked files are left alone
+    /// by deploys. Deploys fail when it's missing or invalid.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub env_file: Option<String>,
+    /// Programs supervised alongside (or instead of) the command passed to `run`
+    #[serde(default)]
+    pub processes: Vec<ProcessConfig>,
+    /// Process names in the order they're started on deploys, stopping happens
+    /// in reverse. Processes left out follow in the order they're declared.
+    #[serde(default)]
+    pub restart_order: Vec<String>,
+    /// What deploys do depending on the files they change. The first rule
+    /// matching a file decides for it, files no rule matches restart, and the
+    /// strongest action wins.
+    #[serde(default)]
+    pub restart_rules: Vec<RestartRule>,
+    /// When deploys may happen
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub schedule: Option<ScheduleConfig>,
+    /// Enables the authenticated /control and /status HTTP endpoints, such as
+    /// freezing or approving deploys
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub control: Option<ControlConfig>,
+    /// Whether deploy requests wait for `repl.deploy approve <id>` before
+    /// running
+    #[serde(default)]
+    pub approval: Approval,
+    /// How long a deploy waits for approval before it's dropped, such as `30m`,
+    /// 24h when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub approval_expiry: Option<String>,
+    /// Repositories deployed by this daemon, each with its own config. When
+    /// set, the other keys of this file are ignored.
+    #[serde(default)]
+    pub apps: Vec<AppConfig>,
+}
+
+impl Config {
+    /// `endpoint` followed by any extra `endpoints`, leaving out empty ones
+    pub fn accepted_endpoints(&self) -> impl Iterator<Item = &String> {
+        iter::once(&self.endpoint)
+            .chain(&self.endpoints)
+            .filter(|endpoint| !endpoint.is_empty())
+    }
+}
+
+/// Which remote branch, tag or commit deploys are reset to
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct GitConfig {
+    pub remote: String,
+    pub branch: String,
+    /// What deploys are reset to, the tip of `branch` by default
+    #[serde(skip_serializing_if = "DeployTarget::is_branch")]
+    pub target: DeployTarget,
+    /// Files or directories, relative to the repository root and without
+    /// `..`, kept as they are across deploys even when git tracks them (e.g.
+    /// SQLite databases)
+    pub preserve: Vec<String>,
+    /// Runs `git clean -fdx` after resetting, removing untracked and ignored
+    /// files such as stale build output. Preserved paths, `env_file` and
+    /// `auth.token_file` are kept.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub clean: Option<CleanConfig>,
+    /// Syncs and checks out submodules, recursively, after resetting
+    pub submodules: bool,
+    /// Fetches and checks out Git LFS files after resetting, which needs
+    /// git-lfs to be installed
+    pub lfs: bool,
+    /// Fetches only this many commits of history
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub depth: Option<u32>,
+    /// Partial clone filter such as `blob:none`, which fetches file contents
+    /// only when they're checked out. Needs a remote that supports it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub filter: Option<String>,
+    /// Credentials for fetching from a private repository
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub auth: Option<GitAuth>,
+    /// Deploys only commits (or annotated tags) signed by an allowed key
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub verify: Option<VerifyConfig>,
+    /// Deploys that change none of these paths don't restart processes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub paths: Option<PathsConfig>,
+}
+
+impl GitConfig {
+    pub fn remote_ref(&self) -> String {
+        format!("{}/{}", self.remote, self.branch)
+    }
+
+    /// What deploys follow, for messages
+    pub fn describe_target(&self) -> String {
+        match &self.target {
+            DeployTarget::Branch => self.remote_ref(),
+            DeployTarget::TagPattern { pattern } => {
+                format!("the latest tag matching {} on {}", pattern, self.remote)
+            }
+            DeployTarget::Pinned { commit } => format!("commit {}", commit),
+        }
+    }
+}
+
+impl Default for GitConfig {
+    fn default() -> Self {
+        GitConfig {
+            remote: "origin".to_owned(),
+            branch: "main".to_owned(),
+            target: DeployTarget::Branch,
+            preserve: vec![],
+            clean: None,
+            submodules: false,
+            lfs: false,
+            depth: None,
+            filter: None,
+            auth: None,
+            verify: None,
+            paths: None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(tag = "mode", rename_all = "kebab-case", deny_unknown_fields)]
+pub enum DeployTarget {
+    /// The tip of `git.branch`
+    #[default]
+    Branch,
+    /// The tag with the highest semver version among those matching the glob
+    /// `pattern`, such as `v*`. Versions are read after any leading non-digits
+    /// and tags without one are skipped, as are prereleases such as
+    /// `v2.0.0-rc1` unless the pattern has a `-` after its first wildcard or
+    /// digit, like `v*-*`.
+    TagPattern { pattern: String },
+    /// A fixed commit, which has to be reachable from `git.branch`
+    Pinned { commit: String },
+}
+
+impl DeployTarget {
+    pub fn is_branch(&self) -> bool {
+        *self == DeployTarget::Branch
+    }
+}
+
+/// Allowlists of signing keys, which have to be kept outside the repository.
+/// Relative paths are resolved from the repository.
+#[derive(Serialize, Deserialize, JsonSchema, Default, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct VerifyConfig {
+    /// SSH allowed signers file, in the format described in ssh-keygen(1)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub allowed_signers: Option<String>,
+    /// GnuPG home directory whose keyring holds the allowed GPG keys
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gpg_home: Option<String>,
+}
+
+/// Used for fetches only and never written to `.git/config`
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct GitAuth {
+    /// Environment variable holding an HTTPS token, such as a deploy token
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token_env: Option<String>,
+    /// File holding the token, used when `token_env` isn't set. Keep it
+    /// untracked.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token_file: Option<String>,
+    /// User name sent with the token
+    pub username: String,
+    /// SSH private key, such as a deploy key, for SSH remotes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ssh_key: Option<String>,
+}
+
+impl Default for GitAuth {
+    fn default() -> Self {
+        GitAuth {
+            token_env: None,
+            token_file: None,
+            // Accepted by GitHub, GitLab takes any non-empty name
+            username: "x-access-token".to_owned(),
+            ssh_key: None,
+        }
+    }
+}
+
+/// Globs relative to the repository root, matched against the files that
+/// differ between the deployed and target commits. When `include` is empty,
+/// every path that isn't excluded is relevant.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct PathsConfig {
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+    /// Still resets the tree to the target when nothing relevant changed
+    pub update_tree: bool,
+}
+
+impl Default for PathsConfig {
+    fn default() -> Self {
+        PathsConfig {
+            include: vec![],
+            exclude: vec![],
+            update_tree: true,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Default, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct CleanConfig {
+    /// Patterns left alone, as with `git clean -e`
+    #[serde(default)]
+    pub exclude: Vec<String>,
+}
+
+/// Paths are globs relative to the repository root. When `include` is empty,
+/// every path that isn't excluded or ignored by a `.gitignore`, at the root or
+/// in a subdirectory, is watched.
+#[derive(Serialize, Deserialize, JsonSchema, Default, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct WatchConfig {
+    #[serde(default)]
+    pub include: Vec<String>,
+    #[serde(default)]
+    pub exclude: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct AppConfig {
+    /// Requests to /refresh/<name> deploy this app
+    pub name: String,
+    /// Repository root holding the app's own config, relative to this file's
+    /// working directory
+    pub path: String,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ProcessConfig {
+    /// Shown before each line the process logs
+    pub name: String,
+    pub command: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// Working directory, relative to the repository root
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cwd: Option<String>,
+    /// Extra environment variables, applied over the top-level `env` and
+    /// `env_file`
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    #[serde(default)]
+    pub restart: RestartPolicy,
+}
+
+/// What happens when a process exits by itself, between deploys
+#[derive(Serialize, Deserialize, JsonSchema, Default, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum RestartPolicy {
+    #[default]
+    Never,
+    OnFailure,
+    Always,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct RestartRule {
+    /// Globs relative to the repository root
+    pub paths: Vec<String>,
+    pub action: RestartAction,
+    /// Sent to every process for `signal`, `SIGHUP` by default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signal: Option<String>,
+    /// Shell command run from the repository root before restarting for
+    /// `reinstall-and-restart`, such as `npm ci`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub install: Option<String>,
+}
+
+/// In increasing strength, a deploy does what the strongest one it needs says
+#[derive(
+    Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum RestartAction {
+    /// Leaves processes running, for files they read as they go
+    None,
+    /// Signals processes to reload
+    Signal,
+    Restart,
+    ReinstallAndRestart,
+}
+
+/// Windows are in `timezone`, and deploys are also held while frozen with
+/// `repl.deploy freeze` or the control API
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct ScheduleConfig {
+    /// IANA time zone such as `Europe/Berlin`
+    pub timezone: String,
+    /// Deploys only happen inside one of these, or at any time when empty
+    pub windows: Vec<DeployWindow>,
+    /// What happens to deploys requested outside the windows or while frozen
+    pub outside: OutsideWindow,
+}
+
+impl Default for ScheduleConfig {
+    fn default() -> Self {
+        ScheduleConfig {
+            timezone: "UTC".to_owned(),
+            windows: vec![],
+            outside: OutsideWindow::Queue,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct DeployWindow {
+    /// Days the window starts on, every day when empty
+    #[serde(default)]
+    pub days: Vec<Weekday>,
+    /// `HH:MM`
+    pub start: String,
+    /// `HH:MM`, excluded. Windows ending before they start run past midnight.
+    pub end: String,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Weekday {
+    Mon,
+    Tue,
+    Wed,
+    Thu,
+    Fri,
+    Sat,
+    Sun,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Default, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum OutsideWindow {
+    /// Deploys once the window opens or deploys are unfrozen, answering with
+    /// 202 Accepted meanwhile
+    #[default]
+    Queue,
+    /// Answers with 423 Locked
+    Reject,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ControlConfig {
+    /// Environment variable holding the token sent as `Authorization: Bearer
+    /// <token>` to /control and /status
+    pub token_env: String,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Default, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Approval {
+    #[default]
+    None,
+    /// Deploy requests answer with 202 Accepted and the id of a pending
+    /// deploy, which runs once approved
+    Required,
 }
 
 #[derive(Debug)]
@@ -28,3 +432,19 @@ impl fmt::Display for ValidationResult {
         write!(f, "{} {}", self.body, self.status)
     }
 }
+
+/// An error answered with `status` rather than as a failure, such as a
+/// deploy request that was queued by the schedule or awaits approval
+#[derive(Debug)]
+pub struct StatusReply {
+    pub status: StatusCode,
+    pub message: String,
+}
+
+impl fmt::Display for StatusReply {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for StatusReply {}